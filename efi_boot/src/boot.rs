@@ -1,6 +1,6 @@
 #![no_std]
 #![no_main]
-#![feature(abi_efiapi, const_option, negative_impls, core_intrinsics)]
+#![feature(abi_efiapi, const_option, negative_impls, core_intrinsics, global_asm)]
 
 #[macro_use]
 extern crate log;
@@ -14,6 +14,8 @@ use core::{
 };
 use libkernel::{
     elf::{ELFHeader64, ProgramHeader, ProgramHeaderType},
+    instructions::{cpu_extended_features, CPUExtendedFeatures},
+    registers::{CR4Flags, CR3, CR4},
     FramebufferInfo,
 };
 use uefi::{
@@ -41,6 +43,13 @@ const KERNEL_CODE: MemoryType = MemoryType::custom(0xFFFFFF00);
 const KERNEL_DATA: MemoryType = MemoryType::custom(0xFFFFFF01);
 const PAGE_SIZE: usize = 0x1000;
 
+global_asm!(include_str!("la57_switch.s"));
+
+extern "C" {
+    static la57_switch_start: u8;
+    static la57_switch_end: u8;
+}
+
 #[cfg(debug_assertions)]
 fn configure_log_level() {
     log::set_max_level(log::LevelFilter::Debug);
@@ -176,6 +185,7 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
 
     // test to see how much memory we're working with
     ensure_enough_memory(boot_services);
+    let la57_switch = prepare_five_level_paging(boot_services);
 
     // prepare required environment data
     let image = get_protocol::<LoadedImage>(boot_services, image_handle)
@@ -220,7 +230,74 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     info!("Acquired kernel image file.");
     let kernel_entry_point = load_kernel(boot_services, kernel_file);
 
-    kernel_transfer(image_handle, system_table, kernel_entry_point, framebuffer)
+    kernel_transfer(
+        image_handle,
+        system_table,
+        kernel_entry_point,
+        framebuffer,
+        la57_switch,
+    )
+}
+
+/// The switch to 5-level paging (see `la57_switch.s`), copied below 4GiB, and the PML5 it loads.
+struct LA57Switch {
+    code: *const u8,
+    pml5: *mut u64,
+}
+
+impl LA57Switch {
+    /// Switches to 5-level paging.
+    ///
+    /// Safety: boot services must have been exited, as the firmware expects the paging mode it
+    ///  set up.
+    unsafe fn enable(self) {
+        let switch: extern "sysv64" fn(u64) = transmute(self.code);
+        switch(self.pml5 as u64);
+    }
+}
+
+/// Prepares to enable 5-level paging, if the CPU supports it and the firmware hasn't already.
+///
+/// CR4.LA57 can only be changed with paging disabled, so the switch drops to 32-bit protected
+/// mode to enable it. It's made once boot services are exited, so its code and the PML5 (which
+/// wraps the firmware's PML4, keeping its identity mapping) are placed below 4GiB beforehand.
+fn prepare_five_level_paging(boot_services: &BootServices) -> Option<LA57Switch> {
+    let supported = cpu_extended_features().contains(CPUExtendedFeatures::LA57);
+    let cr4 = CR4::read();
+
+    if cr4.contains(CR4Flags::LA57) {
+        info!("5-level paging (LA57) is already enabled by firmware.");
+        None
+    } else if !supported {
+        info!("5-level paging (LA57) is unsupported; using 4-level paging.");
+        None
+    } else if cr4.contains(CR4Flags::PCIDE) {
+        // Paging can't be disabled while process-context identifiers are enabled.
+        warn!("5-level paging (LA57) is supported, but PCIDs are enabled by firmware; using 4-level paging.");
+        None
+    } else {
+        let pages = allocate_pages(
+            boot_services,
+            AllocateType::MaxAddress(0xFFFF_F000),
+            KERNEL_CODE,
+            2,
+        );
+        pages.buffer.fill(0);
+
+        let pml5 = pages.pointer as *mut u64;
+        let code = unsafe { pages.pointer.add(PAGE_SIZE) };
+        unsafe {
+            // present & writable
+            *pml5 = (CR3::read_frame().addr().as_usize() as u64) | 0b11;
+
+            let start = &la57_switch_start as *const u8;
+            let len = (&la57_switch_end as *const u8).offset_from(start) as usize;
+            core::ptr::copy_nonoverlapping(start, code, len);
+        }
+
+        info!("5-level paging (LA57) is supported; enabling it before dropping into the kernel.");
+        Some(LA57Switch { code, pml5 })
+    }
 }
 
 fn ensure_enough_memory(boot_services: &BootServices) {
@@ -388,6 +465,7 @@ fn kernel_transfer(
     system_table: SystemTable<Boot>,
     kernel_entry_point: usize,
     framebuffer: Option<FramebufferInfo>,
+    la57_switch: Option<LA57Switch>,
 ) -> ! {
     info!("Preparing to exit boot services environment.");
     // Retrieve a raw allocation pointer & size for the system memory map.
@@ -436,6 +514,10 @@ fn kernel_transfer(
         memory_map[index] = *descriptor;
     }
 
+    if let Some(la57_switch) = la57_switch {
+        unsafe { la57_switch.enable() };
+    }
+
     // Finally, drop into the kernel.
     let kernel_main: libkernel::KernelMain<MemoryDescriptor, uefi::table::cfg::ConfigTableEntry> =
         unsafe { transmute(kernel_entry_point) };
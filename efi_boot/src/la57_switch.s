@@ -0,0 +1,114 @@
+// Switch from 4-level to 5-level paging (LA57).
+//
+// CR4.LA57 can only be changed while paging is disabled, so this drops from long mode to 32-bit
+// protected mode, sets CR4.LA57, loads the PML5 whose address is passed in `rdi` (its first entry
+// must point to the current PML4, so the identity mapping is kept), then re-enables paging,
+// which returns to long mode. It's called with the System V ABI, after exiting boot services.
+//
+// It's copied to an identity-mapped page below 4GiB (as it runs in 32-bit mode), and only
+// addresses itself relative to `la57_switch_start`, so it can run from wherever it's copied to.
+// The PML5 must be below 4GiB as well, and CR4.PCIDE must be clear (paging can't be disabled
+// while it's set). The firmware's GDT is replaced by the switch's own.
+
+.intel_syntax noprefix
+
+.section .rodata.la57_switch, "a"
+.global la57_switch_start
+.global la57_switch_end
+
+.code64
+la57_switch_start:
+    pushfq
+    cli
+    push rbx
+
+    lea rbx, [rip + la57_switch_start]
+
+    // Patches in the linear addresses of the GDT and the far jump target, now the switch's
+    //  base (in `rbx`) is known.
+    lea rax, [rbx + GDT_OFFSET]
+    mov qword ptr [rbx + GDT_POINTER_OFFSET + 2], rax
+    lea rax, [rbx + LONG_OFFSET]
+    mov dword ptr [rbx + LONG_JUMP_OFFSET], eax
+
+    lgdt [rbx + GDT_POINTER_OFFSET]
+
+    // The upper half of each register is undefined after returning to 64-bit mode, so the stack
+    //  pointer (which may be above 4GiB) is kept in memory.
+    mov qword ptr [rbx + SAVED_RSP_OFFSET], rsp
+
+    lea rax, [rbx + COMPATIBILITY_OFFSET]
+    push 0x08
+    push rax
+    retfq
+
+.code32
+la57_switch_compatibility:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    // Disabling paging leaves long mode (EFER.LMA is cleared, but EFER.LME stays set).
+    mov eax, cr0
+    and eax, 0x7FFFFFFF
+    mov cr0, eax
+
+    mov eax, cr4
+    or eax, 1 << 12
+    mov cr4, eax
+    mov cr3, edi
+
+    // Re-enabling paging re-enters long mode, now with 5-level paging.
+    mov eax, cr0
+    or eax, 0x80000000
+    mov cr0, eax
+
+    jmp fword ptr [ebx + LONG_JUMP_OFFSET]
+
+.code64
+la57_switch_long:
+    mov ebx, ebx
+
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    mov rsp, qword ptr [rbx + SAVED_RSP_OFFSET]
+    pop rbx
+    popfq
+    ret
+
+.align 8
+la57_switch_gdt:
+    .quad 0
+    // 32-bit code
+    .quad 0x00CF9A000000FFFF
+    // data
+    .quad 0x00CF92000000FFFF
+    // 64-bit code
+    .quad 0x00AF9A000000FFFF
+la57_switch_gdt_end:
+
+la57_switch_gdt_pointer:
+    .word la57_switch_gdt_end - la57_switch_gdt - 1
+    .quad 0
+
+la57_switch_long_jump:
+    .long 0
+    .word 0x18
+
+.align 8
+la57_switch_saved_rsp:
+    .quad 0
+la57_switch_end:
+
+.set GDT_OFFSET, la57_switch_gdt - la57_switch_start
+.set GDT_POINTER_OFFSET, la57_switch_gdt_pointer - la57_switch_start
+.set COMPATIBILITY_OFFSET, la57_switch_compatibility - la57_switch_start
+.set LONG_OFFSET, la57_switch_long - la57_switch_start
+.set LONG_JUMP_OFFSET, la57_switch_long_jump - la57_switch_start
+.set SAVED_RSP_OFFSET, la57_switch_saved_rsp - la57_switch_start
+
+.att_syntax prefix
@@ -0,0 +1,142 @@
+//! Boot-time benchmarks comparing the slab allocator against the underlying block allocator,
+//! and mapping pages one at a time against mapping them as a range.
+
+use core::alloc::Layout;
+use libkernel::{
+    instructions::{rdtsc, tlb::ReleasedFrames},
+    memory::{
+        vma::{self, AreaAttributes, AreaOwner},
+        Frame, Page,
+    },
+};
+
+/// Number of live allocations held by each benchmark round.
+const ALLOCATION_COUNT: usize = 512;
+/// Number of times each round is repeated.
+const ROUND_COUNT: usize = 16;
+/// Allocation sizes (in bytes) each allocator is benchmarked with.
+const SIZES: [usize; 6] = [16, 64, 256, 1024, 2048, 0x2000];
+/// Number of pages mapped by each mapping benchmark round.
+const MAP_PAGE_COUNT: usize = 0x1000;
+
+/// Runs an alloc/dealloc workload with the given layout, and returns the elapsed ticks.
+fn run<A, D>(layout: Layout, alloc: A, dealloc: D) -> usize
+where
+    A: Fn(Layout) -> *mut u8,
+    D: Fn(*mut u8, Layout),
+{
+    let mut ptrs = [core::ptr::null_mut::<u8>(); ALLOCATION_COUNT];
+    let mut stopwatch = crate::timer::Stopwatch::start_new();
+
+    for _ in 0..ROUND_COUNT {
+        ptrs.iter_mut().for_each(|ptr| *ptr = alloc(layout));
+        // Free every other allocation first, to fragment the heap between rounds.
+        ptrs.iter().step_by(2).for_each(|ptr| dealloc(*ptr, layout));
+        ptrs.iter()
+            .skip(1)
+            .step_by(2)
+            .for_each(|ptr| dealloc(*ptr, layout));
+    }
+
+    stopwatch.stop();
+    stopwatch.elapsed_ticks()
+}
+
+/// Benchmarks the kernel's slab allocator against its underlying block allocator,
+///  logging the elapsed milliseconds for each allocation size.
+///
+/// Requires the timer to be running.
+pub fn compare(allocator: &crate::slab_malloc::SlabAllocator) {
+    info!(
+        "Benchmarking allocators ({} rounds of {} allocations).",
+        ROUND_COUNT, ALLOCATION_COUNT
+    );
+
+    for size in SIZES.iter().map(|size| *size) {
+        let layout = Layout::from_size_align(size, 16).unwrap();
+
+        let block_ticks = run(
+            layout,
+            |layout| allocator.pages().alloc(layout),
+            |ptr, layout| allocator.pages().dealloc(ptr, layout.size()),
+        );
+        let slab_ticks = run(
+            layout,
+            |layout| allocator.alloc(layout),
+            |ptr, layout| allocator.dealloc(ptr, layout),
+        );
+
+        info!(
+            "{:>6} bytes: block {:>6}ms, slab {:>6}ms",
+            size, block_ticks, slab_ticks
+        );
+    }
+}
+
+/// Maps `MAP_PAGE_COUNT` pages from `start` with `map_pages`, then unmaps them, and returns the
+///  cycles taken to map them.
+fn run_mapping<M>(allocator: &crate::slab_malloc::SlabAllocator, start: Page, map_pages: M) -> u64
+where
+    M: Fn(&mut libkernel::memory::paging::VirtualAddressor, &Page),
+{
+    let mut released = ReleasedFrames::new();
+    let mut cycles = 0;
+
+    for _ in 0..ROUND_COUNT {
+        let mut addressor_mut = unsafe { allocator.pages().get_addressor_mut() };
+        let map_start = rdtsc();
+        map_pages(&mut *addressor_mut, &start);
+        cycles += rdtsc() - map_start;
+
+        // The pages aren't mapped copy-on-write, so no frames are released.
+        addressor_mut
+            .unmap_range(&start, MAP_PAGE_COUNT, &mut released)
+            .unwrap();
+        drop(addressor_mut);
+        released.flush_and_free();
+    }
+
+    cycles
+}
+
+/// Benchmarks mapping a range of pages one at a time (with `VirtualAddressor::map`) against
+///  mapping the same range with `VirtualAddressor::map_range`, logging the cycles taken by each.
+pub fn compare_mapping(allocator: &crate::slab_malloc::SlabAllocator) {
+    info!(
+        "Benchmarking page mapping ({} rounds of {} pages).",
+        ROUND_COUNT, MAP_PAGE_COUNT
+    );
+
+    let area = vma::reserve(
+        MAP_PAGE_COUNT,
+        1,
+        "mapping benchmark",
+        AreaOwner::Kernel,
+        AreaAttributes::WRITABLE,
+    )
+    .expect("failed to reserve mapping benchmark area");
+
+    // The pages are mapped to the lowest frames, and never accessed.
+    let per_page_cycles = run_mapping(allocator, area.start(), |addressor_mut, start| {
+        for offset in 0..MAP_PAGE_COUNT {
+            addressor_mut
+                .map(&start.offset(offset), unsafe { &Frame::from_index(offset) })
+                .unwrap();
+        }
+    });
+    let range_cycles = run_mapping(allocator, area.start(), |addressor_mut, start| {
+        addressor_mut
+            .map_range(start, &Frame::null(), MAP_PAGE_COUNT)
+            .unwrap();
+    });
+
+    vma::release(area.start()).unwrap();
+
+    info!(
+        "Mapping {} pages: per page {} cycles, range {} cycles ({}x speedup)",
+        MAP_PAGE_COUNT,
+        per_page_cycles / (ROUND_COUNT as u64),
+        range_cycles / (ROUND_COUNT as u64),
+        per_page_cycles / core::cmp::max(range_cycles, 1)
+    );
+}
@@ -0,0 +1,129 @@
+//! Boot-time checks of the kernel heap's alignment and reallocation guarantees.
+//!
+//! Each check panics on failure, so a kernel built with `alloc_check` only reaches its safe
+//! shutdown state if every check passed.
+
+use core::alloc::Layout;
+
+/// Alignments (in bytes) every allocator is checked with, from below the block size to
+///  beyond a page.
+const ALIGNMENTS: [usize; 10] = [1, 2, 8, 16, 32, 64, 0x200, 0x1000, 0x4000, 0x20_0000];
+/// Allocation sizes (in bytes) checked with each alignment, covering both slab and page
+///  allocations.
+const SIZES: [usize; 7] = [1, 15, 16, 24, 2048, 0x1001, 0x3000];
+
+/// Fills the allocation with a pattern derived from each byte's offset.
+unsafe fn fill(ptr: *mut u8, size: usize) {
+    (0..size).for_each(|offset| ptr.add(offset).write(offset as u8));
+}
+
+/// Whether the first `size` bytes of the allocation still hold the pattern written by `fill`.
+unsafe fn verify(ptr: *const u8, size: usize) -> bool {
+    (0..size).all(|offset| ptr.add(offset).read() == (offset as u8))
+}
+
+fn check_alignment<A, D>(name: &str, alloc: A, dealloc: D)
+where
+    A: Fn(Layout) -> *mut u8,
+    D: Fn(*mut u8, Layout),
+{
+    for align in ALIGNMENTS.iter().copied() {
+        for size in SIZES.iter().copied() {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            // Allocations are held in pairs, so the second can't reuse the first's memory.
+            let ptrs = [alloc(layout), alloc(layout)];
+
+            for ptr in ptrs.iter().copied() {
+                assert!(!ptr.is_null(), "{}: failed to allocate {:?}", name, layout);
+                assert_eq!(
+                    (ptr as usize) % align,
+                    0,
+                    "{}: misaligned allocation for {:?}: {:?}",
+                    name,
+                    layout,
+                    ptr
+                );
+
+                unsafe { fill(ptr, size) };
+            }
+
+            for ptr in ptrs.iter().copied() {
+                assert!(
+                    unsafe { verify(ptr, size) },
+                    "{}: overlapping allocations for {:?}",
+                    name,
+                    layout
+                );
+
+                dealloc(ptr, layout);
+            }
+        }
+    }
+}
+
+fn check_realloc(allocator: &crate::slab_malloc::SlabAllocator) {
+    // (old size, new size) pairs: within a size class, across size classes, from a slab
+    //  object to pages, and between page counts.
+    const RESIZES: [(usize, usize); 8] = [
+        (20, 30),
+        (30, 20),
+        (64, 1024),
+        (1024, 64),
+        (512, 0x2000),
+        (0x2000, 0x5000),
+        (0x5000, 0x2000),
+        (0x2000, 512),
+    ];
+
+    for align in [16, 0x1000, 0x4000].iter().copied() {
+        for (size, new_size) in RESIZES.iter().copied() {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = allocator.alloc::<u8>(layout);
+            assert!(!ptr.is_null(), "failed to allocate {:?}", layout);
+            unsafe { fill(ptr, size) };
+
+            let new_ptr = allocator.realloc(ptr, layout, new_size);
+            assert!(
+                !new_ptr.is_null(),
+                "failed to reallocate {:?} to {} bytes",
+                layout,
+                new_size
+            );
+            assert_eq!(
+                (new_ptr as usize) % align,
+                0,
+                "misaligned reallocation of {:?} to {} bytes",
+                layout,
+                new_size
+            );
+            assert!(
+                unsafe { verify(new_ptr, core::cmp::min(size, new_size)) },
+                "reallocation of {:?} to {} bytes lost its contents",
+                layout,
+                new_size
+            );
+
+            allocator.dealloc(new_ptr, Layout::from_size_align(new_size, align).unwrap());
+        }
+    }
+}
+
+/// Checks that the kernel's slab allocator, and its underlying block allocator, honour
+///  every alignment and preserve contents across reallocation.
+pub fn run(allocator: &crate::slab_malloc::SlabAllocator) {
+    info!("Checking kernel heap alignment and reallocation.");
+
+    check_alignment(
+        "slab",
+        |layout| allocator.alloc(layout),
+        |ptr, layout| allocator.dealloc(ptr, layout),
+    );
+    check_alignment(
+        "block",
+        |layout| allocator.pages().alloc(layout),
+        |ptr, layout| allocator.pages().dealloc(ptr, layout.size()),
+    );
+    check_realloc(allocator);
+
+    info!("Kernel heap checks passed.");
+}
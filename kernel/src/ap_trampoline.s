@@ -0,0 +1,130 @@
+// Application processor trampoline.
+//
+// This is copied to a page-aligned frame below 1MiB, whose page number is the vector of the
+// startup IPI, so the AP begins executing at its first byte in real mode (with `cs` set to the
+// frame's segment, and `ip` to 0). It enters long mode on the kernel's page tables (which must
+// identity-map the trampoline's frame), then calls the entry point in `ap_trampoline_data`.
+//
+// The code only ever addresses itself relative to `ap_trampoline_start`, so it can run from
+// wherever it's copied to.
+
+.intel_syntax noprefix
+
+.section .rodata.ap_trampoline, "a"
+.global ap_trampoline_start
+.global ap_trampoline_data
+.global ap_trampoline_end
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+
+    mov ax, cs
+    mov ds, ax
+    movzx ebx, ax
+    shl ebx, 4
+
+    // Patches in the linear addresses of the GDT and far jump targets, now the trampoline's
+    //  base (in `ebx`) is known.
+    lea eax, [ebx + GDT_OFFSET]
+    mov dword ptr [GDT_POINTER_OFFSET + 2], eax
+    lea eax, [ebx + PROTECTED_OFFSET]
+    mov dword ptr [PROTECTED_JUMP_OFFSET], eax
+    lea eax, [ebx + LONG_OFFSET]
+    mov dword ptr [LONG_JUMP_OFFSET], eax
+
+    lgdt [GDT_POINTER_OFFSET]
+
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    jmp fword ptr ds:[PROTECTED_JUMP_OFFSET]
+
+.code32
+ap_trampoline_protected:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    // Paging is enabled with the BSP's control registers, which sets up long mode (as EFER.LME
+    //  is set).
+    mov eax, dword ptr [ebx + DATA_OFFSET + 8]
+    mov cr4, eax
+    mov eax, dword ptr [ebx + DATA_OFFSET]
+    mov cr3, eax
+    mov ecx, 0xC0000080
+    mov eax, dword ptr [ebx + DATA_OFFSET + 24]
+    xor edx, edx
+    wrmsr
+    mov eax, dword ptr [ebx + DATA_OFFSET + 16]
+    mov cr0, eax
+
+    jmp fword ptr [ebx + LONG_JUMP_OFFSET]
+
+.code64
+ap_trampoline_long:
+    // The upper half of each register is undefined after entering long mode.
+    mov ebx, ebx
+
+    mov rsp, qword ptr [rbx + DATA_OFFSET + 32]
+    mov rax, qword ptr [rbx + DATA_OFFSET + 40]
+    mov rdi, qword ptr [rbx + DATA_OFFSET + 48]
+
+    // The entry point never returns, but is called with the stack aligned as if it were.
+    push 0
+    jmp rax
+
+.align 8
+ap_trampoline_gdt:
+    .quad 0
+    // 32-bit code
+    .quad 0x00CF9A000000FFFF
+    // data
+    .quad 0x00CF92000000FFFF
+    // 64-bit code
+    .quad 0x00AF9A000000FFFF
+ap_trampoline_gdt_end:
+
+ap_trampoline_gdt_pointer:
+    .word ap_trampoline_gdt_end - ap_trampoline_gdt - 1
+    .long 0
+
+ap_trampoline_protected_jump:
+    .long 0
+    .word 0x08
+
+ap_trampoline_long_jump:
+    .long 0
+    .word 0x18
+
+// Written by the BSP before each AP is started (see `TrampolineData` in `smp.rs`).
+.align 8
+ap_trampoline_data:
+    // CR3
+    .quad 0
+    // CR4
+    .quad 0
+    // CR0
+    .quad 0
+    // EFER
+    .quad 0
+    // stack top
+    .quad 0
+    // entry point
+    .quad 0
+    // entry argument
+    .quad 0
+ap_trampoline_end:
+
+.set GDT_OFFSET, ap_trampoline_gdt - ap_trampoline_start
+.set GDT_POINTER_OFFSET, ap_trampoline_gdt_pointer - ap_trampoline_start
+.set PROTECTED_OFFSET, ap_trampoline_protected - ap_trampoline_start
+.set PROTECTED_JUMP_OFFSET, ap_trampoline_protected_jump - ap_trampoline_start
+.set LONG_OFFSET, ap_trampoline_long - ap_trampoline_start
+.set LONG_JUMP_OFFSET, ap_trampoline_long_jump - ap_trampoline_start
+.set DATA_OFFSET, ap_trampoline_data - ap_trampoline_start
+
+.att_syntax prefix
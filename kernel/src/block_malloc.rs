@@ -2,8 +2,16 @@ use core::mem::size_of;
 use libkernel::{
     addr_ty::{Physical, Virtual},
     align_up_div,
-    memory::{falloc, paging::VirtualAddressor, Frame, FrameIterator, Page},
-    Address, SYSTEM_SLICE_SIZE,
+    cell::SyncOnceCell,
+    instructions::tlb::ReleasedFrames,
+    memory::{
+        falloc,
+        paging::{PageAttributes, PagingError, VirtualAddressor},
+        pressure,
+        vma::{self, Area, AreaAttributes, AreaOwner},
+        Frame, FrameIterator, Page,
+    },
+    Address,
 };
 use spin::RwLock;
 
@@ -145,21 +153,42 @@ impl core::fmt::Debug for SectionState {
     }
 }
 
+/// Reasons `BlockAllocator::alloc_blocks` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AllocBlocksError {
+    /// Some of the blocks are allocated (i.e. concurrently, since they were found free), or
+    ///  outside of the map.
+    NotFree,
+    /// A heap page couldn't be backed.
+    Paging(PagingError),
+}
+
 /// Allocator utilizing blocks of memory, in size of 16 bytes per block, to
 ///  easily and efficiently allocate.
 pub struct BlockAllocator<'map> {
     // todo remove addressor from this struct
     addressor: RwLock<VirtualAddressor>,
     map: RwLock<&'map mut [BlockPage]>,
+    heap_area: SyncOnceCell<Area>,
+    map_area: SyncOnceCell<Area>,
 }
 
 impl BlockAllocator<'_> {
     /// The size of an allocator block.
     pub const BLOCK_SIZE: usize = 16;
 
-    /// Base page the allocator uses to store the internal block page map.
-    const ALLOCATOR_BASE: Page =
-        Page::from_addr(unsafe { Address::new_unsafe(SYSTEM_SLICE_SIZE * 0xA) });
+    /// Alignment (in pages) of the heap area, so large page alignments can be honored.
+    const HEAP_AREA_ALIGNMENT: usize = 512;
+    /// Number of pages reserved for the kernel stack (not including its guard page).
+    ///
+    /// The stack is demand paged (page faults are handled on their own stack), so only the
+    ///  pages it actually uses are backed by frames.
+    const STACK_PAGE_COUNT: usize = 0x1000;
+    /// Number of pages at the top of the kernel stack that are mapped up front.
+    ///
+    /// Demand paging can't resolve a fault while the faulting CPU holds the addressor lock,
+    ///  so the stack's usual depth is always backed.
+    const STACK_MAPPED_PAGE_COUNT: usize = 256;
 
     #[allow(const_item_mutation)]
     pub const fn new() -> Self {
@@ -169,6 +198,8 @@ impl BlockAllocator<'_> {
             // TODO make addressor use a RwLock
             addressor: RwLock::new(VirtualAddressor::null()),
             map: RwLock::new(&mut EMPTY),
+            heap_area: SyncOnceCell::new(),
+            map_area: SyncOnceCell::new(),
         }
     }
 
@@ -180,6 +211,25 @@ impl BlockAllocator<'_> {
         self.addressor.write()
     }
 
+    /// Virtual memory area the heap's blocks are allocated within.
+    fn heap_area(&self) -> &Area {
+        self.heap_area
+            .get()
+            .expect("block allocator has not been initialized")
+    }
+
+    /// Virtual memory area the internal block page map is stored within.
+    fn map_area(&self) -> &Area {
+        self.map_area
+            .get()
+            .expect("block allocator has not been initialized")
+    }
+
+    /// Heap page corresponding to the given block page map index.
+    fn heap_page(&self, map_index: usize) -> Page {
+        self.heap_area().start().offset(map_index)
+    }
+
     /* INITIALIZATION */
 
     pub unsafe fn init(&self, stack_frames: &mut libkernel::memory::FrameIterator) {
@@ -195,33 +245,107 @@ impl BlockAllocator<'_> {
                 .enumerate()
                 .filter(|(_, frame_state)| *frame_state == falloc::FrameState::Reserved)
                 .for_each(|(frame_index, _)| {
-                    addressor_mut.identity_map(&Frame::from_index(frame_index))
+                    addressor_mut
+                        .identity_map(&Frame::from_index(frame_index))
+                        .expect("failed to identity map reserved frame")
                 });
 
+            debug!("Creating kernel-half page tables.");
+            addressor_mut
+                .populate_kernel_half()
+                .expect("failed to create kernel-half page tables");
+
+            let total_pages = falloc::get().total_memory(None) / 0x1000;
+            vma::reserve_at(
+                Page::null(),
+                total_pages,
+                "identity map",
+                AreaOwner::Kernel,
+                AreaAttributes::WRITABLE | AreaAttributes::EXECUTABLE,
+            )
+            .expect("failed to reserve identity-mapped area");
+
             // Since we're using physical offset mapping for our page table modification
             //  strategy, the memory needs to be identity mapped at the correct offset.
-            let phys_mapping_addr = falloc::virtual_map_offset();
-            debug!("Mapping physical memory at offset: {:?}", phys_mapping_addr);
-            addressor_mut.modify_mapped_page(Page::from_addr(phys_mapping_addr));
+            let phys_mapping_area = vma::reserve(
+                total_pages,
+                1,
+                "physical memory map",
+                AreaOwner::PhysicalMap,
+                AreaAttributes::WRITABLE,
+            )
+            .expect("failed to reserve physical memory map area");
+            debug!(
+                "Mapping physical memory at offset: {:?}",
+                phys_mapping_area.start().addr()
+            );
+            let map_start = libkernel::instructions::rdtsc();
+            addressor_mut
+                .modify_mapped_page(phys_mapping_area.start())
+                .expect("failed to map physical memory");
+            debug!(
+                "Mapped {} pages of physical memory in {} cycles.",
+                total_pages,
+                libkernel::instructions::rdtsc() - map_start
+            );
+
+            // The heap is reserved at its maximum size (all of physical memory), and
+            //  is only backed by frames as it grows.
+            let heap_area = vma::reserve(
+                total_pages,
+                Self::HEAP_AREA_ALIGNMENT,
+                "kernel heap",
+                AreaOwner::Allocator,
+                AreaAttributes::WRITABLE,
+            )
+            .expect("failed to reserve kernel heap area");
+            let map_area = vma::reserve(
+                align_up_div(total_pages * size_of::<BlockPage>(), 0x1000).next_power_of_two(),
+                1,
+                "kernel heap map",
+                AreaOwner::Allocator,
+                AreaAttributes::WRITABLE,
+            )
+            .expect("failed to reserve kernel heap map area");
+            self.heap_area.set(heap_area).ok();
+            self.map_area.set(map_area).ok();
 
             // Swap the PML4 into CR3
             debug!("Writing kernel addressor's PML4 to the CR3 register.");
             addressor_mut.swap_into();
         }
 
-        debug!("Allocating reserved global memory frames.");
-        falloc::get()
-            .iter()
-            .enumerate()
-            .filter(|(_, frame_state)| *frame_state == falloc::FrameState::Reserved)
-            .for_each(|(frame_index, _)| self.identity_map(&Frame::from_index(frame_index), false));
-
-        const STACK_SIZE: usize = 256 * 0x1000; /* 1MB in pages */
-
-        debug!("Allocating new stack: {} bytes", STACK_SIZE);
-        let new_stack_base = self.alloc::<u8>(
-            core::alloc::Layout::from_size_align(STACK_SIZE, Self::BLOCK_SIZE).unwrap(),
+        debug!(
+            "Reserving new stack: {} bytes",
+            Self::STACK_PAGE_COUNT * 0x1000
+        );
+        let stack_area = vma::reserve(
+            Self::STACK_PAGE_COUNT + 1,
+            1,
+            "kernel stack",
+            AreaOwner::Stack,
+            AreaAttributes::WRITABLE | AreaAttributes::GUARDED | AreaAttributes::DEMAND_PAGED,
+        )
+        .expect("failed to reserve kernel stack area");
+        let stack_page_count = stack_frames.len();
+        assert!(
+            stack_page_count <= Self::STACK_MAPPED_PAGE_COUNT,
+            "bootloader-allocated stack is larger than the kernel stack's mapped pages"
         );
+
+        {
+            let mut addressor_mut = self.get_addressor_mut();
+            for page in Page::from_index(stack_area.end().index() - Self::STACK_MAPPED_PAGE_COUNT)
+                .iter_count(Self::STACK_MAPPED_PAGE_COUNT)
+            {
+                addressor_mut
+                    .map(&page, &falloc::get().lock_next().unwrap())
+                    .expect("failed to map kernel stack page");
+            }
+        }
+        // The stack grows downwards, so the bootloader's stack is copied to the top of the area.
+        let stack_start = Page::from_index(stack_area.end().index() - stack_page_count);
+        let new_stack_base = stack_start.as_mut_ptr::<u8>();
         let stack_base_cell = core::lazy::OnceCell::<*mut u8>::new();
 
         debug!("Copying data from bootloader-allocated stack.");
@@ -264,7 +388,12 @@ impl BlockAllocator<'_> {
 
             transmute::<&mut FrameIteratorBytes, &mut FrameIterator>(temp)
         }
-        .for_each(|frame| addressor_mut.unmap(&Page::from_index(frame.index())));
+        .for_each(|frame| {
+            // The stack isn't mapped copy-on-write, so no frames are released.
+            addressor_mut
+                .unmap(&Page::from_index(frame.index()), &mut ReleasedFrames::new())
+                .unwrap()
+        });
 
         debug!("Finished block allocator initialization.");
     }
@@ -273,21 +402,19 @@ impl BlockAllocator<'_> {
 
     // TODO consider returning a slice from this function rather than a raw pointer
     //      reasoning: possibly a more idiomatic way to return a sized chunk of memory
+    /// Allocates memory fitting the layout, returning a null pointer if it can't be backed
+    ///  (even after shrinkers have been invoked to relieve memory pressure).
     pub fn alloc<T>(&self, layout: core::alloc::Layout) -> *mut T {
-        const MINIMUM_ALIGNMENT: usize = 16;
-
-        let size_in_blocks = (layout.size() + (Self::BLOCK_SIZE - 1)) / Self::BLOCK_SIZE;
-        let alignment = if (layout.align() & (MINIMUM_ALIGNMENT - 1)) == 0 {
-            layout.align()
-        } else {
-            trace!(
-                "Unsupported allocator alignment: {}, defaulting to {}",
-                layout.align(),
-                Self::BLOCK_SIZE
-            );
+        pressure::alloc_with_reclaim(align_up_div(layout.size(), 0x1000), || {
+            self.try_alloc(layout)
+        })
+    }
 
-            MINIMUM_ALIGNMENT
-        };
+    fn try_alloc<T>(&self, layout: core::alloc::Layout) -> *mut T {
+        let size_in_blocks = core::cmp::max(align_up_div(layout.size(), Self::BLOCK_SIZE), 1);
+        // Every block is aligned to the block size, so smaller alignments are always satisfied.
+        let alignment = core::cmp::max(layout.align(), Self::BLOCK_SIZE);
+        let heap_start = self.heap_area().start().addr().as_usize();
 
         trace!(
             "Allocation requested: {}{{by {}}} bytes ({} blocks)",
@@ -296,66 +423,112 @@ impl BlockAllocator<'_> {
             size_in_blocks
         );
 
-        let (mut block_index, mut current_run);
-        while {
-            block_index = 0;
-            current_run = 0;
-
-            'outer: for block_page in self.map.read().iter() {
-                if block_page.is_full() {
-                    current_run = 0;
-                    block_index += BlockPage::BLOCK_COUNT;
-                } else {
-                    for section in block_page.iter().map(|section| *section) {
-                        if section == u64::MAX {
-                            current_run = 0;
-                            block_index += BlockPage::SECTION_LEN;
-                        } else {
-                            for bit in (0..64).map(|shift| (section & (1 << shift)) > 0) {
-                                if bit {
-                                    current_run = 0;
-                                } else if current_run > 0 || (block_index % alignment) == 0 {
-                                    current_run += 1;
-                                }
-
-                                block_index += 1;
-
-                                if current_run == size_in_blocks {
-                                    break 'outer;
+        loop {
+            let (mut block_index, mut current_run);
+            while {
+                block_index = 0;
+                current_run = 0;
+
+                'outer: for block_page in self.map.read().iter() {
+                    if block_page.is_full() {
+                        current_run = 0;
+                        block_index += BlockPage::BLOCK_COUNT;
+                    } else {
+                        for section in block_page.iter().map(|section| *section) {
+                            if section == u64::MAX {
+                                current_run = 0;
+                                block_index += BlockPage::SECTION_LEN;
+                            } else {
+                                for bit in (0..64).map(|shift| (section & (1 << shift)) > 0) {
+                                    if bit {
+                                        current_run = 0;
+                                    } else if current_run > 0
+                                        || ((heap_start + (block_index * Self::BLOCK_SIZE))
+                                            % alignment)
+                                            == 0
+                                    {
+                                        current_run += 1;
+                                    }
+
+                                    block_index += 1;
+
+                                    if current_run == size_in_blocks {
+                                        break 'outer;
+                                    }
                                 }
                             }
                         }
                     }
                 }
+
+                current_run < size_in_blocks
+            } {
+                // Growing by the alignment too guarantees an aligned run fits.
+                if let Err(paging_error) =
+                    self.grow(size_in_blocks + (alignment / Self::BLOCK_SIZE) - 1)
+                {
+                    warn!("Failed to grow allocator map: {:?}", paging_error);
+                    return core::ptr::null_mut();
+                }
             }
 
-            current_run < size_in_blocks
-        } {
-            self.grow(size_in_blocks);
+            let start_block_index = block_index - current_run;
+            let end_block_index = block_index;
+            trace!(
+                "Allocation fulfilling: {}..{}",
+                start_block_index,
+                end_block_index
+            );
+
+            match self.alloc_blocks(start_block_index, end_block_index) {
+                Ok(()) => {
+                    return (self.heap_area().start().addr()
+                        + (start_block_index * Self::BLOCK_SIZE))
+                        .as_mut_ptr()
+                }
+                // Another allocation claimed some of the blocks since the search, so search again.
+                Err(AllocBlocksError::NotFree) => {}
+                Err(AllocBlocksError::Paging(paging_error)) => {
+                    warn!("Failed to back allocation: {:?}", paging_error);
+                    return core::ptr::null_mut();
+                }
+            }
         }
+    }
 
-        let start_block_index = block_index - current_run;
-        let end_block_index = block_index;
-        block_index = start_block_index;
-        trace!(
-            "Allocation fulfilling: {}..{}",
-            start_block_index,
-            end_block_index
-        );
+    /// Marks the blocks `start_block_index..end_block_index` as allocated, backing any heap
+    ///  pages which previously had no allocated blocks.
+    ///
+    /// The blocks are checked to be free under the same lock they're claimed with, so
+    ///  `AllocBlocksError::NotFree` is returned if any were allocated since the caller found
+    ///  them. If a heap page can't be backed, every block is left unallocated.
+    fn alloc_blocks(
+        &self,
+        start_block_index: usize,
+        end_block_index: usize,
+    ) -> Result<(), AllocBlocksError> {
+        let mut map = self.map.write();
+        if !Self::blocks_free(&map, start_block_index, end_block_index) {
+            return Err(AllocBlocksError::NotFree);
+        }
+
+        let mut block_index = start_block_index;
 
         let start_map_index = start_block_index / BlockPage::BLOCK_COUNT;
         let mut initial_section_skip =
             libkernel::align_down_div(block_index, BlockPage::SECTION_LEN)
                 - (start_map_index * BlockPage::SECTION_COUNT);
+        let mut alloc_error = None;
 
-        for (map_index, block_page) in self
-            .map
-            .write()
+        for (map_index, block_page) in map
             .iter_mut()
             .enumerate()
             .skip(start_map_index)
             .take(align_up_div(end_block_index, BlockPage::BLOCK_COUNT) - start_map_index)
         {
+            // Retained so this block page can be restored if its memory can't be backed.
+            let prev_block_page = block_page.clone();
+            let prev_block_index = block_index;
             let mut page_state: [SectionState; BlockPage::SECTION_COUNT] =
                 [SectionState::empty(); BlockPage::SECTION_COUNT];
 
@@ -387,22 +560,100 @@ impl BlockAllocator<'_> {
 
             if SectionState::should_alloc(&page_state) {
                 // 'has bits', but not 'had bits'
+                if let Err(paging_error) = self.back_heap_page(map_index) {
+                    *block_page = prev_block_page;
+                    block_index = prev_block_index;
+                    alloc_error = Some(paging_error);
+                    break;
+                }
+            }
+        }
 
-                let page = &mut Page::from_index(map_index);
-
-                unsafe {
-                    self.get_addressor_mut()
-                        .map(page, &falloc::get().lock_next().unwrap());
-                    page.clear();
+        drop(map);
+        match alloc_error {
+            Some(paging_error) => {
+                // Release the blocks which were allocated before the failure.
+                if block_index > start_block_index {
+                    self.dealloc(
+                        (self.heap_area().start().addr() + (start_block_index * Self::BLOCK_SIZE))
+                            .as_mut_ptr::<u8>(),
+                        (block_index - start_block_index) * Self::BLOCK_SIZE,
+                    );
                 }
+
+                Err(AllocBlocksError::Paging(paging_error))
             }
+            None => Ok(()),
         }
+    }
 
-        (start_block_index * Self::BLOCK_SIZE) as *mut T
+    /// Whether every block `start_block_index..end_block_index` is unallocated (and
+    ///  within the map).
+    fn blocks_free(map: &[BlockPage], start_block_index: usize, end_block_index: usize) -> bool {
+        end_block_index <= (map.len() * BlockPage::BLOCK_COUNT)
+            && (start_block_index..end_block_index).all(|block_index| {
+                let section = map[block_index / BlockPage::BLOCK_COUNT]
+                    [(block_index % BlockPage::BLOCK_COUNT) / BlockPage::SECTION_LEN];
+
+                (section & (1 << (block_index % BlockPage::SECTION_LEN))) == 0
+            })
+    }
+
+    /// Resizes the allocation at `ptr` to `new_size` bytes, in place if the blocks following
+    ///  it are free (or it's shrinking), otherwise by moving it to a new allocation.
+    ///
+    /// Returns a null pointer, leaving the allocation untouched, if it can't be resized.
+    pub fn realloc<T>(&self, ptr: *mut T, layout: core::alloc::Layout, new_size: usize) -> *mut T {
+        let start_block_index =
+            ((ptr as usize) - self.heap_area().start().addr().as_usize()) / Self::BLOCK_SIZE;
+        let old_blocks = core::cmp::max(align_up_div(layout.size(), Self::BLOCK_SIZE), 1);
+        let new_blocks = core::cmp::max(align_up_div(new_size, Self::BLOCK_SIZE), 1);
+
+        if new_blocks < old_blocks {
+            self.dealloc(
+                unsafe { (ptr as *mut u8).add(new_blocks * Self::BLOCK_SIZE) },
+                (old_blocks - new_blocks) * Self::BLOCK_SIZE,
+            );
+
+            return ptr;
+        } else if new_blocks == old_blocks
+            || self
+                .alloc_blocks(
+                    start_block_index + old_blocks,
+                    start_block_index + new_blocks,
+                )
+                .is_ok()
+        {
+            trace!(
+                "Reallocated in place: {} -> {} blocks",
+                old_blocks,
+                new_blocks
+            );
+            return ptr;
+        }
+
+        let new_ptr = match core::alloc::Layout::from_size_align(new_size, layout.align()) {
+            Ok(new_layout) => self.alloc::<T>(new_layout),
+            Err(_) => return core::ptr::null_mut(),
+        };
+
+        if !new_ptr.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    ptr as *const u8,
+                    new_ptr as *mut u8,
+                    core::cmp::min(layout.size(), new_size),
+                )
+            };
+            self.dealloc(ptr, layout.size());
+        }
+
+        new_ptr
     }
 
     pub fn dealloc<T>(&self, ptr: *mut T, size: usize) {
-        let start_block_index = (ptr as usize) / Self::BLOCK_SIZE;
+        let start_block_index =
+            ((ptr as usize) - self.heap_area().start().addr().as_usize()) / Self::BLOCK_SIZE;
         let end_block_index = start_block_index + align_up_div(size, Self::BLOCK_SIZE);
         let mut block_index = start_block_index;
         trace!(
@@ -412,59 +663,62 @@ impl BlockAllocator<'_> {
         );
 
         let start_map_index = start_block_index / BlockPage::BLOCK_COUNT;
-        let end_map_index = align_up_div(end_block_index, BlockPage::BLOCK_COUNT) - start_map_index;
+        let end_map_index = align_up_div(end_block_index, BlockPage::BLOCK_COUNT);
         let mut initial_section_skip =
             libkernel::align_down_div(block_index, BlockPage::SECTION_LEN)
                 - (start_map_index * BlockPage::SECTION_COUNT);
-        for (map_index, block_page) in self
-            .map
-            .write()
-            .iter_mut()
-            .enumerate()
-            .skip(start_map_index)
-            .take(end_map_index)
-        {
-            let mut page_state: [SectionState; BlockPage::SECTION_COUNT] =
-                [SectionState::empty(); BlockPage::SECTION_COUNT];
-
-            for (section_index, section) in block_page.iter_mut().enumerate() {
-                page_state[section_index].had_bits = *section > 0;
-
-                if initial_section_skip > 0 {
-                    initial_section_skip -= 1;
-                } else if block_index < end_block_index {
-                    let (bit_count, bit_mask) = Self::calculate_bit_fields(
-                        map_index,
-                        section_index,
-                        end_block_index,
-                        block_index,
-                    );
+        let mut released = ReleasedFrames::new();
+        let mut map_index = start_map_index;
+
+        // Frames of emptied pages can only be freed once the pages are shot down, which can't
+        //  be done with the map locked, so the map is unlocked whenever `released` fills.
+        while map_index < end_map_index {
+            let mut map = self.map.write();
+
+            while map_index < end_map_index && !released.is_full() {
+                let block_page = &mut map[map_index];
+                let mut page_state: [SectionState; BlockPage::SECTION_COUNT] =
+                    [SectionState::empty(); BlockPage::SECTION_COUNT];
+
+                for (section_index, section) in block_page.iter_mut().enumerate() {
+                    page_state[section_index].had_bits = *section > 0;
+
+                    if initial_section_skip > 0 {
+                        initial_section_skip -= 1;
+                    } else if block_index < end_block_index {
+                        let (bit_count, bit_mask) = Self::calculate_bit_fields(
+                            map_index,
+                            section_index,
+                            end_block_index,
+                            block_index,
+                        );
+
+                        assert_eq!(
+                            *section & bit_mask,
+                            bit_mask,
+                            "attempting to deallocate blocks that are already deallocated"
+                        );
+
+                        *section ^= bit_mask;
+                        block_index += bit_count;
+                    }
 
-                    assert_eq!(
-                        *section & bit_mask,
-                        bit_mask,
-                        "attempting to deallocate blocks that are already deallocated"
-                    );
+                    page_state[section_index].has_bits = *section > 0;
+                }
 
-                    *section ^= bit_mask;
-                    block_index += bit_count;
+                if SectionState::should_dealloc(&page_state) {
+                    // 'had bits', but not 'has bits'
+                    let mut addressor_mut = unsafe { self.get_addressor_mut() };
+                    let page = &self.heap_page(map_index);
+                    released.push(addressor_mut.translate_page(page).unwrap());
+                    addressor_mut.unmap(page, &mut released).unwrap();
                 }
 
-                page_state[section_index].has_bits = *section > 0;
+                map_index += 1;
             }
 
-            if SectionState::should_dealloc(&page_state) {
-                // 'has bits', but not 'had bits'
-                let mut addressor_mut = unsafe { self.get_addressor_mut() };
-                let page = &Page::from_index(map_index);
-                // todo FIX THIS (uncomment & build for error)
-                unsafe {
-                    falloc::get()
-                        .free_frame(addressor_mut.translate_page(page).unwrap())
-                        .unwrap()
-                };
-                addressor_mut.unmap(page);
-            }
+            drop(map);
+            released.flush_and_free();
         }
     }
 
@@ -497,6 +751,73 @@ impl BlockAllocator<'_> {
     pub fn alloc_to<T>(&self, frames: &FrameIterator) -> *mut T {
         let size_in_frames = frames.len();
         trace!("Allocation requested to: {} frames", size_in_frames);
+
+        let area = match vma::reserve(
+            size_in_frames,
+            1,
+            "mmio",
+            AreaOwner::MMIO,
+            AreaAttributes::WRITABLE | AreaAttributes::UNCACHEABLE,
+        ) {
+            Ok(area) => area,
+            Err(vma_error) => {
+                warn!(
+                    "Failed to reserve virtual memory area for frames: {:?}",
+                    vma_error
+                );
+                return core::ptr::null_mut();
+            }
+        };
+        trace!("Allocation fulfilling: {:?}", area);
+
+        match unsafe { self.get_addressor_mut() }.map_range(
+            &area.start(),
+            frames.start(),
+            size_in_frames,
+        ) {
+            Ok(()) => area.start().as_mut_ptr(),
+            Err(paging_error) => {
+                warn!("Failed to map allocation to frames: {:?}", paging_error);
+                vma::release(area.start()).unwrap();
+                core::ptr::null_mut()
+            }
+        }
+    }
+
+    /// Releases an allocation made by `alloc_to`, unmapping its pages and releasing its virtual
+    ///  memory area. The frames it was mapped to are left to the caller.
+    pub fn dealloc_to<T>(&self, ptr: *mut T, frames: &FrameIterator) {
+        let page = Page::from_ptr(ptr);
+        trace!("Deallocation requested from: {} frames", frames.len());
+
+        unsafe { self.unmap_pages(&page, frames.len()) }
+            .expect("failed to unmap allocation from frames");
+        vma::release(page).expect("allocation was not made by `alloc_to`");
+    }
+
+    /// Allocates a run of whole pages, backed by newly locked (and zeroed) frames.
+    ///
+    /// This only inspects the map at block page granularity, so it's considerably
+    ///  cheaper than `alloc` for page-sized (or larger) allocations. As with `alloc`, a null
+    ///  pointer is returned if the pages can't be backed.
+    pub fn alloc_pages<T>(&self, page_count: usize, page_alignment: usize) -> *mut T {
+        pressure::alloc_with_reclaim(page_count, || {
+            self.try_alloc_pages(page_count, page_alignment)
+        })
+    }
+
+    fn try_alloc_pages<T>(&self, page_count: usize, page_alignment: usize) -> *mut T {
+        assert!(page_count > 0, "page allocations must be nonzero");
+        assert!(
+            page_alignment.is_power_of_two(),
+            "page alignment must be a power of two"
+        );
+
+        trace!(
+            "Page allocation requested: {}{{by {}}} pages",
+            page_count,
+            page_alignment
+        );
         let (mut map_index, mut current_run);
 
         while {
@@ -504,7 +825,10 @@ impl BlockAllocator<'_> {
             current_run = 0;
 
             for block_page in self.map.read().iter() {
-                if block_page.is_empty() {
+                if block_page.is_empty()
+                    && (current_run > 0
+                        || (self.heap_page(map_index).index() % page_alignment) == 0)
+                {
                     current_run += 1;
                 } else {
                     current_run = 0;
@@ -512,66 +836,238 @@ impl BlockAllocator<'_> {
 
                 map_index += 1;
 
-                if current_run == size_in_frames {
+                if current_run == page_count {
                     break;
                 }
             }
 
-            current_run < size_in_frames
+            current_run < page_count
         } {
-            self.grow(size_in_frames * BlockPage::BLOCK_COUNT);
+            if let Err(paging_error) =
+                self.grow((page_count + page_alignment) * BlockPage::BLOCK_COUNT)
+            {
+                warn!("Failed to grow allocator map: {:?}", paging_error);
+                return core::ptr::null_mut();
+            }
         }
 
         let start_index = map_index - current_run;
         trace!(
-            "Allocation fulfilling: pages {}..{}",
+            "Page allocation fulfilling: pages {}..{}",
             start_index,
-            start_index + size_in_frames
+            start_index + page_count
         );
 
+        let mut backed_count = 0;
+        let mut alloc_error = None;
+        for (map_index, block_page) in self
+            .map
+            .write()
+            .iter_mut()
+            .enumerate()
+            .skip(start_index)
+            .take(page_count)
         {
-            let mut addressor_mut = unsafe { self.get_addressor_mut() };
-            for (map_index, block_page) in self
-                .map
-                .write()
-                .iter_mut()
-                .enumerate()
-                .skip(start_index)
-                .take(size_in_frames)
-            {
-                block_page.set_full();
-                addressor_mut.map(&Page::from_index(map_index), &frames.start());
+            if let Err(paging_error) = self.back_heap_page(map_index) {
+                alloc_error = Some(paging_error);
+                break;
+            }
+
+            block_page.set_full();
+            backed_count += 1;
+        }
+
+        if let Some(paging_error) = alloc_error {
+            warn!("Failed to back page allocation: {:?}", paging_error);
+
+            // Release the pages which were allocated before the failure.
+            if backed_count > 0 {
+                self.dealloc_pages(self.heap_page(start_index).as_mut_ptr::<u8>(), backed_count);
             }
+
+            return core::ptr::null_mut();
         }
 
-        (start_index * 0x1000) as *mut T
+        self.heap_page(start_index).as_mut_ptr()
     }
 
-    pub fn identity_map(&self, frame: &Frame, map: bool) {
-        trace!("Identity mapping requested: {:?}", frame);
+    /// Resizes a run of whole pages previously allocated with `alloc_pages` in place,
+    ///  returning whether it could be (growing requires the pages following the run to be
+    ///  unallocated).
+    pub fn realloc_pages<T>(&self, ptr: *mut T, page_count: usize, new_page_count: usize) -> bool {
+        let start_index = Page::from_ptr(ptr).index() - self.heap_area().start().index();
+
+        if new_page_count < page_count {
+            self.dealloc_pages(
+                self.heap_page(start_index + new_page_count)
+                    .as_mut_ptr::<u8>(),
+                page_count - new_page_count,
+            );
 
-        let map_len = self.map.read().len();
-        if map_len <= frame.index() {
-            self.grow(((frame.index() - map_len) + 1) * BlockPage::BLOCK_COUNT);
+            return true;
+        } else if new_page_count == page_count {
+            return true;
         }
 
-        let block_page = &mut self.map.write()[frame.index()];
-        block_page.set_empty();
+        let mut map = self.map.write();
+        let grown_indexes = (start_index + page_count)..(start_index + new_page_count);
+        if grown_indexes.end > map.len()
+            || !map[grown_indexes.clone()].iter().all(BlockPage::is_empty)
+        {
+            return false;
+        }
+
+        for map_index in grown_indexes.clone() {
+            if let Err(paging_error) = self.back_heap_page(map_index) {
+                warn!("Failed to back page reallocation: {:?}", paging_error);
+
+                // Release the pages which were backed before the failure.
+                drop(map);
+                if map_index > grown_indexes.start {
+                    self.dealloc_pages(
+                        self.heap_page(grown_indexes.start).as_mut_ptr::<u8>(),
+                        map_index - grown_indexes.start,
+                    );
+                }
+
+                return false;
+            }
+
+            map[map_index].set_full();
+        }
+
+        trace!(
+            "Reallocated pages in place: {} -> {} pages",
+            page_count,
+            new_page_count
+        );
+        true
+    }
+
+    /// Deallocates a run of whole pages previously allocated with `alloc_pages`,
+    ///  freeing their backing frames.
+    pub fn dealloc_pages<T>(&self, ptr: *mut T, page_count: usize) {
+        let start_index = Page::from_ptr(ptr).index() - self.heap_area().start().index();
+        trace!(
+            "Page deallocation requested: pages {}..{}",
+            start_index,
+            start_index + page_count
+        );
+
+        let end_index = start_index + page_count;
+        let mut released = ReleasedFrames::new();
+        let mut map_index = start_index;
+
+        // As in `dealloc`, the locks are released whenever `released` fills.
+        while map_index < end_index {
+            let mut map = self.map.write();
+            let mut addressor_mut = unsafe { self.get_addressor_mut() };
+
+            while map_index < end_index && !released.is_full() {
+                self.release_page(
+                    map_index,
+                    &mut map[map_index],
+                    &mut addressor_mut,
+                    &mut released,
+                );
+                map_index += 1;
+            }
+
+            drop(addressor_mut);
+            drop(map);
+            released.flush_and_free();
+        }
+    }
+
+    /// As `dealloc_pages`, but only if neither the map nor the addressor are locked, returning
+    ///  whether the pages were deallocated.
+    ///
+    /// This is for shrinkers, which may be invoked while either lock is held. At most
+    ///  `ReleasedFrames::CAPACITY` pages may be deallocated at once.
+    pub fn try_dealloc_pages<T>(&self, ptr: *mut T, page_count: usize) -> bool {
         assert!(
-            block_page.is_empty(),
-            "attempting to identity map page with previously allocated blocks: {:?} (map? {})\n {:?}",
-            frame,
-            map,
+            page_count <= ReleasedFrames::CAPACITY,
+            "too many pages to deallocate at once"
+        );
+
+        let start_index = Page::from_ptr(ptr).index() - self.heap_area().start().index();
+        let mut released = ReleasedFrames::new();
+
+        match (self.map.try_write(), self.addressor.try_write()) {
+            (Some(mut map), Some(mut addressor_mut)) => {
+                trace!(
+                    "Page deallocation requested: pages {}..{}",
+                    start_index,
+                    start_index + page_count
+                );
+
+                for (map_index, block_page) in map
+                    .iter_mut()
+                    .enumerate()
+                    .skip(start_index)
+                    .take(page_count)
+                {
+                    self.release_page(map_index, block_page, &mut addressor_mut, &mut released);
+                }
+            }
+            _ => return false,
+        }
+
+        released.flush_and_free();
+        true
+    }
+
+    /// Marks the (fully allocated) block page at `map_index` empty, and unmaps its heap page,
+    ///  adding its frame to `released`.
+    fn release_page(
+        &self,
+        map_index: usize,
+        block_page: &mut BlockPage,
+        addressor_mut: &mut VirtualAddressor,
+        released: &mut ReleasedFrames,
+    ) {
+        assert!(
+            block_page.is_full(),
+            "attempting to deallocate page that isn't fully allocated: {:?}",
             block_page
         );
-        block_page.set_full();
+        block_page.set_empty();
+
+        let page = &self.heap_page(map_index);
+        released.push(addressor_mut.translate_page(page).unwrap());
+        addressor_mut.unmap(page, released).unwrap();
+    }
+
+    /// Backs the heap page at the given map index with a newly locked (and zeroed) frame.
+    fn back_heap_page(&self, map_index: usize) -> Result<(), PagingError> {
+        let page = &mut self.heap_page(map_index);
+        let (frame, zeroed) = match falloc::get().lock_zeroed() {
+            Some(frame) => (frame, true),
+            None => (
+                falloc::get().lock_next().ok_or(PagingError::OutOfFrames)?,
+                false,
+            ),
+        };
 
-        if map {
-            unsafe { self.get_addressor_mut() }.identity_map(frame);
+        match unsafe { self.get_addressor_mut() }.map(page, &frame) {
+            Ok(()) => {
+                if !zeroed {
+                    unsafe { page.clear() };
+                }
+
+                Ok(())
+            }
+            Err(paging_error) => {
+                unsafe { falloc::get().free_frame(frame).unwrap() };
+                Err(paging_error)
+            }
         }
     }
 
-    pub fn grow(&self, required_blocks: usize) {
+    /// Grows the map to fit at least `required_blocks` more blocks.
+    ///
+    /// If memory can't be mapped for the map, it's left as it was.
+    pub fn grow(&self, required_blocks: usize) -> Result<(), PagingError> {
         assert!(required_blocks > 0, "calls to grow must be nonzero");
 
         trace!("Growing map to faciliate {} blocks.", required_blocks);
@@ -589,21 +1085,52 @@ impl BlockAllocator<'_> {
             new_page_offset
         );
 
-        {
+        let map_area = self.map_area();
+        assert!(
+            new_page_offset <= map_area.page_count(),
+            "kernel heap map has outgrown its virtual memory area: {:?}",
+            map_area
+        );
+
+        let result = {
             let mut addressor_mut = unsafe { self.get_addressor_mut() };
-            for offset in cur_page_offset..new_page_offset {
-                let map_page = &mut Self::ALLOCATOR_BASE.offset(offset);
-                addressor_mut.map(map_page, &falloc::get().lock_next().unwrap());
-            }
+            (cur_page_offset..new_page_offset).try_for_each(|offset| {
+                let map_page = &map_area.start().offset(offset);
+                let result = match falloc::get().lock_next() {
+                    Some(frame) => addressor_mut.map(map_page, &frame).map_err(|paging_error| {
+                        unsafe { falloc::get().free_frame(frame).unwrap() };
+                        paging_error
+                    }),
+                    None => Err(PagingError::OutOfFrames),
+                };
+
+                if result.is_err() {
+                    // Release the map pages which were mapped before the failure. No other CPU
+                    //  has used them, so their frames are freed right away.
+                    for offset in cur_page_offset..offset {
+                        let map_page = &map_area.start().offset(offset);
+                        let frame = addressor_mut.translate_page(map_page).unwrap();
+                        addressor_mut
+                            .unmap(map_page, &mut ReleasedFrames::new())
+                            .unwrap();
+                        unsafe { falloc::get().free_frame(frame).unwrap() };
+                    }
+                }
+
+                result
+            })
+        };
+
+        if let Err(paging_error) = result {
+            drop(map_read);
+            libkernel::instructions::tlb::flush_shootdowns();
+            return Err(paging_error);
         }
 
         let new_map_len = new_page_offset * (0x1000 / size_of::<BlockPage>());
         let mut map_write = map_read.upgrade();
         *map_write = unsafe {
-            &mut *core::ptr::slice_from_raw_parts_mut(
-                Self::ALLOCATOR_BASE.as_mut_ptr(),
-                new_map_len,
-            )
+            &mut *core::ptr::slice_from_raw_parts_mut(map_area.start().as_mut_ptr(), new_map_len)
         };
         map_write[cur_map_len..].fill(BlockPage::empty());
 
@@ -613,11 +1140,72 @@ impl BlockAllocator<'_> {
             new_map_len,
             new_map_len * BLOCKS_PER_MAP_PAGE
         );
+
+        Ok(())
     }
 
     pub unsafe fn physical_memory(&self, addr: Address<Physical>) -> Address<Virtual> {
         self.get_addressor().mapped_page().addr() + addr.as_usize()
     }
+
+    pub unsafe fn map_page(&self, page: &Page, frame: &Frame) -> Result<(), PagingError> {
+        self.get_addressor_mut().map(page, frame)
+    }
+
+    pub unsafe fn try_map_page(
+        &self,
+        page: &Page,
+        frame: &Frame,
+    ) -> Option<Result<(), PagingError>> {
+        self.addressor
+            .try_write()
+            .map(|mut addressor_mut| addressor_mut.map(page, frame))
+    }
+
+    pub unsafe fn map_pages(
+        &self,
+        page: &Page,
+        frame: &Frame,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError> {
+        self.get_addressor_mut()
+            .map_range_with_attribs(page, frame, count, attribs)
+    }
+
+    /// Unmaps `count` contiguous pages, in runs of up to `ReleasedFrames::CAPACITY` pages, so
+    ///  the references of those mapped copy-on-write are dropped once each run is shot down.
+    ///
+    /// If a run fails to unmap, the runs before it remain unmapped.
+    pub unsafe fn unmap_pages(&self, page: &Page, count: usize) -> Result<(), PagingError> {
+        let mut released = ReleasedFrames::new();
+
+        for offset in (0..count).step_by(ReleasedFrames::CAPACITY) {
+            let result = self.get_addressor_mut().unmap_range(
+                &page.offset(offset),
+                core::cmp::min(ReleasedFrames::CAPACITY, count - offset),
+                &mut released,
+            );
+            released.flush_and_free();
+            result?;
+        }
+
+        Ok(())
+    }
+
+    pub unsafe fn set_pages_attribs(
+        &self,
+        page: &Page,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError> {
+        let result = self
+            .get_addressor_mut()
+            .set_range_attribs(page, count, attribs);
+        libkernel::instructions::tlb::flush_shootdowns();
+
+        result
+    }
 }
 
 impl libkernel::memory::malloc::MemoryAllocator for BlockAllocator<'_> {
@@ -629,6 +1217,14 @@ impl libkernel::memory::malloc::MemoryAllocator for BlockAllocator<'_> {
         self.alloc_to(frames)
     }
 
+    fn dealloc_to(&self, ptr: *mut u8, frames: &FrameIterator) {
+        self.dealloc_to(ptr, frames);
+    }
+
+    fn realloc(&self, ptr: *mut u8, layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
+        self.realloc(ptr, layout, new_size)
+    }
+
     fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
         self.dealloc(ptr, layout.size());
     }
@@ -640,4 +1236,35 @@ impl libkernel::memory::malloc::MemoryAllocator for BlockAllocator<'_> {
     unsafe fn physical_memory(&self, addr: Address<Physical>) -> Address<Virtual> {
         self.physical_memory(addr)
     }
+
+    unsafe fn map_page(&self, page: &Page, frame: &Frame) -> Result<(), PagingError> {
+        self.map_page(page, frame)
+    }
+
+    unsafe fn try_map_page(&self, page: &Page, frame: &Frame) -> Option<Result<(), PagingError>> {
+        self.try_map_page(page, frame)
+    }
+
+    unsafe fn map_pages(
+        &self,
+        page: &Page,
+        frame: &Frame,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError> {
+        self.map_pages(page, frame, count, attribs)
+    }
+
+    unsafe fn unmap_pages(&self, page: &Page, count: usize) -> Result<(), PagingError> {
+        self.unmap_pages(page, count)
+    }
+
+    unsafe fn set_pages_attribs(
+        &self,
+        page: &Page,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError> {
+        self.set_pages_attribs(page, count, attribs)
+    }
 }
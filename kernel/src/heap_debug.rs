@@ -0,0 +1,283 @@
+//! Heap debugging, via red zones around each allocation and poisoning of freed memory.
+//!
+//! Each allocation is laid out as:
+//!
+//! ```text
+//! [ header | front red zone | allocation | back red zone ]
+//! ```
+//!
+//! The header records the allocation site, and links the allocation into a list of live
+//!  allocations so red zones can be verified periodically (not only on `dealloc`).
+
+use core::{alloc::Layout, mem::size_of, panic::Location};
+use spin::Mutex;
+
+/// Byte pattern red zones are filled with.
+pub const REDZONE_PATTERN: u8 = 0xFD;
+/// Byte pattern freed memory is filled with.
+pub const POISON_PATTERN: u8 = 0x6B;
+/// Size of the back red zone, and minimum size of the front red zone.
+pub const REDZONE_SIZE: usize = 16;
+/// Interval (in timer ticks) at which the heap is periodically verified.
+pub const VERIFY_INTERVAL_TICKS: usize = 1000;
+
+const HEADER_MAGIC: usize = 0xA110_CA7E_D0D0_CAFE;
+
+#[repr(C)]
+struct AllocationHeader {
+    /// Reserved for the slab free list link, so `location` survives deallocation.
+    free_link: usize,
+    location: &'static Location<'static>,
+    magic: usize,
+    size: usize,
+    front_offset: usize,
+    prev: *mut AllocationHeader,
+    next: *mut AllocationHeader,
+}
+
+impl AllocationHeader {
+    /// Offset of the location field, which is retained after the allocation is freed.
+    const LOCATION_OFFSET: usize = size_of::<usize>();
+    /// Offset at which poisoning begins (after the retained location field).
+    const POISON_OFFSET: usize = Self::LOCATION_OFFSET + size_of::<usize>();
+
+    fn is_valid(&self) -> bool {
+        self.magic == HEADER_MAGIC
+    }
+}
+
+struct LiveList {
+    head: *mut AllocationHeader,
+}
+
+// Headers are only ever accessed with the list's lock held.
+unsafe impl Send for LiveList {}
+
+static LIVE_ALLOCATIONS: Mutex<LiveList> = Mutex::new(LiveList {
+    head: core::ptr::null_mut(),
+});
+
+/// Offset, from the start of the padded allocation, at which the caller's allocation begins.
+fn front_offset(layout: &Layout) -> usize {
+    libkernel::align_up(size_of::<AllocationHeader>() + REDZONE_SIZE, layout.align())
+}
+
+/// The layout required to fit the given layout, its header, and its red zones.
+pub fn padded_layout(layout: Layout) -> Layout {
+    Layout::from_size_align(
+        front_offset(&layout) + layout.size() + REDZONE_SIZE,
+        core::cmp::max(layout.align(), 16),
+    )
+    .unwrap()
+}
+
+/// Writes the header and red zones for a new allocation, returning the caller's pointer.
+pub unsafe fn on_alloc(
+    base_ptr: *mut u8,
+    layout: Layout,
+    location: &'static Location<'static>,
+) -> *mut u8 {
+    let front_offset = front_offset(&layout);
+    let user_ptr = base_ptr.add(front_offset);
+
+    core::ptr::write_bytes(
+        base_ptr.add(size_of::<AllocationHeader>()),
+        REDZONE_PATTERN,
+        front_offset - size_of::<AllocationHeader>(),
+    );
+    core::ptr::write_bytes(user_ptr.add(layout.size()), REDZONE_PATTERN, REDZONE_SIZE);
+
+    let mut live_allocations = LIVE_ALLOCATIONS.lock();
+    let header = base_ptr as *mut AllocationHeader;
+    header.write(AllocationHeader {
+        free_link: 0,
+        location,
+        magic: HEADER_MAGIC,
+        size: layout.size(),
+        front_offset,
+        prev: core::ptr::null_mut(),
+        next: live_allocations.head,
+    });
+
+    if let Some(head) = live_allocations.head.as_mut() {
+        head.prev = header;
+    }
+    live_allocations.head = header;
+
+    user_ptr
+}
+
+/// Verifies and unlinks an allocation's header and red zones, then poisons it,
+///  returning the base pointer of the padded allocation.
+pub unsafe fn on_dealloc(user_ptr: *mut u8, layout: Layout) -> *mut u8 {
+    let front_offset = front_offset(&layout);
+    let base_ptr = user_ptr.sub(front_offset);
+
+    {
+        let mut live_allocations = LIVE_ALLOCATIONS.lock();
+        let header = &mut *(base_ptr as *mut AllocationHeader);
+
+        if !verify_allocation(header) {
+            panic!("heap corruption detected on deallocation of {:?}", user_ptr);
+        } else if header.size != layout.size() || header.front_offset != front_offset {
+            report_header("DEALLOCATION LAYOUT MISMATCH", header);
+            error!("  Deallocated with layout: {:?}", layout);
+            panic!("heap corruption detected on deallocation of {:?}", user_ptr);
+        }
+
+        match header.prev.as_mut() {
+            Some(prev) => prev.next = header.next,
+            None => live_allocations.head = header.next,
+        }
+
+        if let Some(next) = header.next.as_mut() {
+            next.prev = header.prev;
+        }
+    }
+
+    // Poison everything except the location, so writes after free can name the allocation site.
+    let padded_size = padded_layout(layout).size();
+    core::ptr::write_bytes(base_ptr, POISON_PATTERN, AllocationHeader::LOCATION_OFFSET);
+    core::ptr::write_bytes(
+        base_ptr.add(AllocationHeader::POISON_OFFSET),
+        POISON_PATTERN,
+        padded_size - AllocationHeader::POISON_OFFSET,
+    );
+
+    base_ptr
+}
+
+/// Poisons a new slab object that has never been allocated.
+pub unsafe fn poison_fresh(object_ptr: *mut u8, object_size: usize) {
+    core::ptr::write_bytes(object_ptr, POISON_PATTERN, object_size);
+}
+
+/// Verifies that an unallocated slab object hasn't been written to since it was freed.
+///
+/// The first two words of the object (the free list link, and the location of the
+///  previous allocation) are excluded.
+pub unsafe fn verify_free_object(object_ptr: *mut u8, object_size: usize) -> bool {
+    let poisoned = core::slice::from_raw_parts(
+        object_ptr.add(AllocationHeader::POISON_OFFSET),
+        object_size.saturating_sub(AllocationHeader::POISON_OFFSET),
+    );
+
+    match poisoned.iter().position(|byte| *byte != POISON_PATTERN) {
+        Some(offset) => {
+            error!("HEAP CORRUPTION: WRITE AFTER FREE");
+            error!("  Object: {:?} ({} bytes)", object_ptr, object_size);
+
+            let location_word =
+                *(object_ptr.add(AllocationHeader::LOCATION_OFFSET) as *const usize);
+            if location_word == usize::from_ne_bytes([POISON_PATTERN; size_of::<usize>()]) {
+                error!("  Allocated at: unknown (never allocated)");
+            } else {
+                let location = &*(location_word as *const Location<'static>);
+                error!("  Allocated at: {}", location);
+            }
+
+            error!(
+                "  Offset {}: expected 0x{:X}, found 0x{:X}",
+                AllocationHeader::POISON_OFFSET + offset,
+                POISON_PATTERN,
+                poisoned[offset]
+            );
+
+            false
+        }
+        None => true,
+    }
+}
+
+/// Verifies the header and red zones of a live allocation, reporting any corruption.
+unsafe fn verify_allocation(header: &AllocationHeader) -> bool {
+    let base_ptr = header as *const _ as *mut u8;
+
+    if !header.is_valid() {
+        error!("HEAP CORRUPTION: ALLOCATION HEADER OVERWRITTEN");
+        error!("  Header: {:?}", base_ptr);
+        error!(
+            "  Magic: expected 0x{:X}, found 0x{:X}",
+            HEADER_MAGIC, header.magic
+        );
+        return false;
+    }
+
+    let front_offset = header.front_offset;
+    let user_ptr = base_ptr.add(front_offset);
+
+    let front_redzone = core::slice::from_raw_parts(
+        base_ptr.add(size_of::<AllocationHeader>()),
+        front_offset - size_of::<AllocationHeader>(),
+    );
+    let back_redzone = core::slice::from_raw_parts(user_ptr.add(header.size), REDZONE_SIZE);
+
+    if let Some(offset) = front_redzone
+        .iter()
+        .position(|byte| *byte != REDZONE_PATTERN)
+    {
+        report_header("BUFFER UNDERFLOW", header);
+        error!(
+            "  Offset -{}: expected 0x{:X}, found 0x{:X}",
+            front_redzone.len() - offset,
+            REDZONE_PATTERN,
+            front_redzone[offset]
+        );
+
+        false
+    } else if let Some(offset) = back_redzone
+        .iter()
+        .position(|byte| *byte != REDZONE_PATTERN)
+    {
+        report_header("BUFFER OVERFLOW", header);
+        error!(
+            "  Offset +{}: expected 0x{:X}, found 0x{:X}",
+            header.size + offset,
+            REDZONE_PATTERN,
+            back_redzone[offset]
+        );
+
+        false
+    } else {
+        true
+    }
+}
+
+fn report_header(kind: &str, header: &AllocationHeader) {
+    let user_ptr = unsafe { (header as *const _ as *const u8).add(header.front_offset) };
+
+    error!("HEAP CORRUPTION: {}", kind);
+    error!(
+        "  Allocation: {:?} ({} bytes, {} byte front red zone)",
+        user_ptr,
+        header.size,
+        header.front_offset - size_of::<AllocationHeader>()
+    );
+    error!("  Allocated at: {}", header.location);
+}
+
+/// Verifies the red zones of every live allocation, returning the number of corrupted allocations.
+///
+/// Verification is skipped (returning zero) if the live allocation list is currently locked.
+pub fn verify_live() -> usize {
+    let live_allocations = match LIVE_ALLOCATIONS.try_lock() {
+        Some(live_allocations) => live_allocations,
+        None => return 0,
+    };
+
+    let mut corrupted = 0;
+    let mut header_ptr = live_allocations.head;
+    while let Some(header) = unsafe { header_ptr.as_ref() } {
+        if !unsafe { verify_allocation(header) } {
+            corrupted += 1;
+            // Links can't be trusted past a corrupted header.
+            if !header.is_valid() {
+                break;
+            }
+        }
+
+        header_ptr = header.next;
+    }
+
+    corrupted
+}
@@ -0,0 +1,331 @@
+//! Optional accounting of kernel heap usage, by allocation site and size class.
+//!
+//! Records are kept in fixed-size tables, so accounting never allocates from the heap
+//!  it's accounting for. Allocations made through Rust's global allocator are attributed
+//!  to the global allocator proxy, since `alloc` internals don't propagate caller locations.
+
+use crate::slab_malloc::SlabAllocator;
+use core::panic::Location;
+use spin::Mutex;
+
+/// Maximum number of distinct allocation sites that can be tracked.
+const SITE_CAPACITY: usize = 256;
+/// Maximum number of live allocations that can be tracked.
+const LIVE_CAPACITY: usize = 4096;
+/// Number of size classes tracked (slab size classes, plus one for page allocations).
+const CLASS_COUNT: usize = SlabAllocator::SIZE_CLASSES.len() + 1;
+
+#[derive(Clone, Copy)]
+struct SiteRecord {
+    location: Option<&'static Location<'static>>,
+    live_count: usize,
+    live_bytes: usize,
+    total_count: usize,
+}
+
+impl SiteRecord {
+    const fn empty() -> Self {
+        Self {
+            location: None,
+            live_count: 0,
+            live_bytes: 0,
+            total_count: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct LiveRecord {
+    ptr: usize,
+    size: usize,
+    site_index: usize,
+    generation: usize,
+}
+
+impl LiveRecord {
+    const fn empty() -> Self {
+        Self {
+            ptr: 0,
+            size: 0,
+            site_index: 0,
+            generation: 0,
+        }
+    }
+}
+
+/// Opaque marker, after which live allocations can be listed with `log_live_since`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint(usize);
+
+struct HeapStats {
+    sites: [SiteRecord; SITE_CAPACITY],
+    live: [LiveRecord; LIVE_CAPACITY],
+    class_live_counts: [usize; CLASS_COUNT],
+    live_bytes: usize,
+    peak_bytes: usize,
+    failures: usize,
+    untracked: usize,
+    generation: usize,
+}
+
+impl HeapStats {
+    const fn new() -> Self {
+        Self {
+            sites: [SiteRecord::empty(); SITE_CAPACITY],
+            live: [LiveRecord::empty(); LIVE_CAPACITY],
+            class_live_counts: [0; CLASS_COUNT],
+            live_bytes: 0,
+            peak_bytes: 0,
+            failures: 0,
+            untracked: 0,
+            generation: 0,
+        }
+    }
+
+    /// Finds (or creates) the site record index for the given location.
+    fn site_index(&mut self, location: &'static Location<'static>) -> Option<usize> {
+        let hash = (location.file().as_ptr() as usize)
+            ^ ((location.line() as usize) << 8)
+            ^ (location.column() as usize);
+
+        for probe in 0..SITE_CAPACITY {
+            let index = (hash + probe) % SITE_CAPACITY;
+            let site = &mut self.sites[index];
+
+            match site.location {
+                Some(site_location) if site_location == location => return Some(index),
+                Some(_) => {}
+                None => {
+                    site.location = Some(location);
+                    return Some(index);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn live_index(ptr: usize, probe: usize) -> usize {
+        ((ptr >> 4) + probe) % LIVE_CAPACITY
+    }
+
+    /// Empties the live record at `index`, shifting later records of its probe run back into
+    ///  the gap, so the table needs no tombstones (which would otherwise accumulate, and
+    ///  lengthen every probe).
+    fn remove_live(&mut self, mut index: usize) {
+        self.live[index] = LiveRecord::empty();
+
+        let mut next = index;
+        loop {
+            next = (next + 1) % LIVE_CAPACITY;
+            let live = self.live[next];
+            if live.ptr == 0 {
+                break;
+            }
+
+            // A record can fill the gap if the gap is no further from the record than its
+            //  first probe is.
+            let home = HeapStats::live_index(live.ptr, 0);
+            if ((next + LIVE_CAPACITY - home) % LIVE_CAPACITY)
+                >= ((next + LIVE_CAPACITY - index) % LIVE_CAPACITY)
+            {
+                self.live[index] = live;
+                self.live[next] = LiveRecord::empty();
+                index = next;
+            }
+        }
+    }
+}
+
+static HEAP_STATS: Mutex<HeapStats> = Mutex::new(HeapStats::new());
+
+/// Records a successful allocation of `size` bytes at `ptr`, in the given slab size
+///  class (or `None` for page allocations).
+pub fn record_alloc(
+    ptr: *mut u8,
+    size: usize,
+    class_index: Option<usize>,
+    location: &'static Location<'static>,
+) {
+    let mut stats = HEAP_STATS.lock();
+
+    stats.live_bytes += size;
+    stats.peak_bytes = core::cmp::max(stats.peak_bytes, stats.live_bytes);
+    stats.class_live_counts[class_index.unwrap_or(CLASS_COUNT - 1)] += 1;
+
+    let site_index = match stats.site_index(location) {
+        Some(site_index) => site_index,
+        None => {
+            stats.untracked += 1;
+            return;
+        }
+    };
+
+    {
+        let site = &mut stats.sites[site_index];
+        site.live_count += 1;
+        site.live_bytes += size;
+        site.total_count += 1;
+    }
+
+    let generation = stats.generation;
+    for probe in 0..LIVE_CAPACITY {
+        let live = &mut stats.live[HeapStats::live_index(ptr as usize, probe)];
+
+        if live.ptr == 0 {
+            *live = LiveRecord {
+                ptr: ptr as usize,
+                size,
+                site_index,
+                generation,
+            };
+
+            return;
+        }
+    }
+
+    stats.untracked += 1;
+}
+
+/// Records the deallocation of `size` bytes at `ptr`, in the given slab size class
+///  (or `None` for page allocations).
+pub fn record_dealloc(ptr: *mut u8, size: usize, class_index: Option<usize>) {
+    let mut stats = HEAP_STATS.lock();
+
+    stats.live_bytes = stats.live_bytes.saturating_sub(size);
+    let class_live_count = &mut stats.class_live_counts[class_index.unwrap_or(CLASS_COUNT - 1)];
+    *class_live_count = class_live_count.saturating_sub(1);
+
+    for probe in 0..LIVE_CAPACITY {
+        let live_index = HeapStats::live_index(ptr as usize, probe);
+        let live = stats.live[live_index];
+
+        if live.ptr == 0 {
+            // Allocation was never tracked (i.e. the tables were full).
+            break;
+        } else if live.ptr == (ptr as usize) {
+            let site = &mut stats.sites[live.site_index];
+            site.live_count -= 1;
+            site.live_bytes -= live.size;
+
+            stats.remove_live(live_index);
+            break;
+        }
+    }
+}
+
+/// Records an allocation request which the allocator failed to fulfill.
+pub fn record_failure(size: usize) {
+    HEAP_STATS.lock().failures += 1;
+    warn!("Heap allocation failed: {} bytes", size);
+}
+
+/// Creates a checkpoint; allocations made after it can be listed with `log_live_since`.
+pub fn checkpoint() -> Checkpoint {
+    let mut stats = HEAP_STATS.lock();
+    stats.generation += 1;
+
+    Checkpoint(stats.generation)
+}
+
+/// Logs overall heap usage, live allocations per size class, and the `top` allocation
+///  sites by live bytes.
+///
+/// The statistics are copied out before each line is logged, as logging may allocate (which
+///  would take the statistics' lock again).
+pub fn log_summary(top: usize) {
+    let (live_bytes, peak_bytes, failures, untracked, class_live_counts) = {
+        let stats = HEAP_STATS.lock();
+
+        (
+            stats.live_bytes,
+            stats.peak_bytes,
+            stats.failures,
+            stats.untracked,
+            stats.class_live_counts,
+        )
+    };
+
+    info!("HEAP STATISTICS");
+    info!("---------------");
+    info!(
+        "Live: {} bytes, Peak: {} bytes, Failures: {}, Untracked: {}",
+        live_bytes, peak_bytes, failures, untracked
+    );
+
+    for (class_index, live_count) in class_live_counts.iter().enumerate() {
+        match SlabAllocator::SIZE_CLASSES.get(class_index) {
+            Some(class_size) => info!("  Class {:>5} bytes: {} live", class_size, live_count),
+            None => info!("  Class       pages: {} live", live_count),
+        }
+    }
+
+    info!("Top {} allocation sites by live bytes:", top);
+    let mut last_bytes = usize::MAX;
+    let mut last_index = SITE_CAPACITY;
+    for _ in 0..top {
+        // Selects the next-largest site, ordering equal sites by index to avoid repeats.
+        let next = HEAP_STATS
+            .lock()
+            .sites
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, site)| site.location.is_some() && site.live_bytes > 0)
+            .filter(|(index, site)| {
+                site.live_bytes < last_bytes
+                    || (site.live_bytes == last_bytes && *index > last_index)
+            })
+            .max_by(|(a_index, a_site), (b_index, b_site)| {
+                a_site
+                    .live_bytes
+                    .cmp(&b_site.live_bytes)
+                    .then(b_index.cmp(a_index))
+            });
+
+        match next {
+            Some((index, site)) => {
+                info!(
+                    "  {}: {} bytes in {} live ({} total)",
+                    site.location.unwrap(),
+                    site.live_bytes,
+                    site.live_count,
+                    site.total_count
+                );
+
+                last_bytes = site.live_bytes;
+                last_index = index;
+            }
+            None => break,
+        }
+    }
+}
+
+/// Logs every tracked allocation made after `checkpoint` which is still live.
+///
+/// As with `log_summary`, each record is copied out before it's logged.
+pub fn log_live_since(checkpoint: Checkpoint) {
+    info!("Allocations still live since checkpoint {:?}:", checkpoint);
+    let mut live_count = 0;
+    for index in 0..LIVE_CAPACITY {
+        let (live, location) = {
+            let stats = HEAP_STATS.lock();
+            let live = stats.live[index];
+
+            (live, stats.sites[live.site_index].location)
+        };
+
+        if live.ptr != 0 && live.generation >= checkpoint.0 {
+            info!(
+                "  {:?}: {} bytes from {}",
+                live.ptr as *const u8,
+                live.size,
+                location.unwrap()
+            );
+
+            live_count += 1;
+        }
+    }
+
+    info!("{} allocations still live.", live_count);
+}
@@ -1,6 +1,13 @@
 #![no_std]
 #![no_main]
-#![feature(asm, abi_efiapi, abi_x86_interrupt, once_cell, const_mut_refs)]
+#![feature(
+    asm,
+    global_asm,
+    abi_efiapi,
+    abi_x86_interrupt,
+    once_cell,
+    const_mut_refs
+)]
 
 #[macro_use]
 extern crate log;
@@ -11,8 +18,19 @@ mod block_malloc;
 mod drivers;
 mod logging;
 mod pic8259;
+mod slab_malloc;
+mod smp;
 mod timer;
 
+#[cfg(feature = "alloc_bench")]
+mod alloc_bench;
+#[cfg(feature = "alloc_check")]
+mod alloc_check;
+#[cfg(feature = "heap_debug")]
+mod heap_debug;
+#[cfg(feature = "heap_stats")]
+mod heap_stats;
+
 use core::ffi::c_void;
 use libkernel::{
     memory::{falloc, UEFIMemoryDescriptor},
@@ -45,7 +63,7 @@ fn get_log_level() -> log::LevelFilter {
 }
 
 static mut SERIAL_OUT: drivers::io::Serial = drivers::io::Serial::new(drivers::io::COM1);
-static KERNEL_MALLOC: block_malloc::BlockAllocator = block_malloc::BlockAllocator::new();
+static KERNEL_MALLOC: slab_malloc::SlabAllocator = slab_malloc::SlabAllocator::new();
 
 #[no_mangle]
 #[export_name = "_start"]
@@ -72,6 +90,18 @@ extern "efiapi" fn kernel_main(
         "Detected CPU features: {:?}",
         libkernel::instructions::cpu_features()
     );
+    debug!(
+        "Detected extended CPU features: {:?}",
+        libkernel::instructions::cpu_extended_features()
+    );
+    info!(
+        "Using {}-level paging.",
+        if libkernel::memory::paging::five_level_paging() {
+            5
+        } else {
+            4
+        }
+    );
 
     unsafe { libkernel::instructions::init_segment_registers(0x0) };
     debug!("Zeroed segment registers.");
@@ -92,6 +122,25 @@ extern "efiapi" fn kernel_main(
         info!("Initializing kernel default allocator.");
         KERNEL_MALLOC.init(&mut stack_frames);
         libkernel::memory::malloc::set(&KERNEL_MALLOC);
+        register_shrinkers();
+
+        libkernel::percpu::init();
+        info!("Initialized per-CPU data of the bootstrap processor.");
+
+        if libkernel::memory::paging::enable_pcid() {
+            info!("Process-context identifiers enabled.");
+        }
+
+        if libkernel::memory::paging::enable_write_combining() {
+            info!("Write-combining page attributes enabled.");
+        }
+
+        if libkernel::memory::numa::init() {
+            info!(
+                "Loaded NUMA topology with {} nodes.",
+                libkernel::memory::numa::get().unwrap().node_count()
+            );
+        }
 
         debug!(
             "System reserved memory: {:?} MB",
@@ -102,16 +151,56 @@ extern "efiapi" fn kernel_main(
     }
 
     init_apic();
+    libkernel::percpu::get().set_online();
+
+    match libkernel::instructions::tlb::init_shootdown() {
+        Ok(vector) => debug!("TLB shootdowns will be sent on vector {}.", vector),
+        Err(error) => panic!("failed to initialize TLB shootdowns: {:?}", error),
+    }
+
+    info!("{} CPUs online.", smp::init());
+    libkernel::memory::vma::log_layout();
+
+    #[cfg(feature = "alloc_bench")]
+    alloc_bench::compare(&KERNEL_MALLOC);
+    #[cfg(feature = "alloc_bench")]
+    alloc_bench::compare_mapping(&KERNEL_MALLOC);
+
+    #[cfg(feature = "alloc_check")]
+    alloc_check::run(&KERNEL_MALLOC);
+
+    #[cfg(feature = "heap_stats")]
+    let heap_checkpoint = heap_stats::checkpoint();
 
     use libkernel::structures::acpi::MCFG;
     libkernel::structures::acpi::xsdt::get_entry::<MCFG>()
         .unwrap()
         .init_pcie();
 
+    #[cfg(feature = "heap_stats")]
+    {
+        heap_stats::log_summary(10);
+        heap_stats::log_live_since(heap_checkpoint);
+    }
+
+    libkernel::structures::irq::log_counters();
+    libkernel::percpu::log_counters();
+
     info!("Kernel has reached safe shutdown state.");
     unsafe { libkernel::instructions::pwm::qemu_shutdown() }
 }
 
+/// Registers the shrinkers of the kernel's caches, which are invoked under memory pressure.
+fn register_shrinkers() {
+    use libkernel::memory::pressure::register_shrinker;
+
+    register_shrinker("slab caches", |target_frames| {
+        KERNEL_MALLOC.shrink(target_frames)
+    })
+    .unwrap();
+    register_shrinker("zeroed frames", falloc::zeroed_pool_shrinker).unwrap();
+}
+
 pub unsafe fn init_falloc(memory_map: &[UEFIMemoryDescriptor]) {
     info!("Initializing kernel frame allocator.");
 
@@ -215,15 +304,30 @@ fn init_system_config_table(config_table: &[SystemConfigTableEntry]) {
 fn init_apic() {
     use libkernel::structures::{
         apic::{APICRegister, APICTimerDivisor, APICTimerMode},
-        idt,
+        irq,
     };
 
     crate::pic8259::enable();
     info!("Successfully initialized PIC.");
+    // The PIC's vectors stay reserved after it's disabled, as it may still raise spurious IRQs.
+    //  The local APIC doesn't track the PIC's interrupts, so their handlers acknowledge them
+    //  with the PIC instead (and spurious IRQs aren't acknowledged at all).
+    for vector in
+        crate::pic8259::InterruptOffset::BASE..(crate::pic8259::InterruptOffset::BASE + 16)
+    {
+        irq::reserve_vector(vector).unwrap();
+        irq::set_end_of_interrupt(vector, irq::EndOfInterrupt::Handler).unwrap();
+    }
+
     info!("Configuring PIT frequency to 1000Hz.");
     crate::pic8259::set_timer_freq(crate::timer::TIMER_FREQUENCY as u32);
     debug!("Setting timer interrupt handler and enabling interrupts.");
-    idt::set_interrupt_handler(32, crate::timer::tick_handler);
+    irq::register_handler(
+        crate::pic8259::InterruptOffset::Timer as u8,
+        crate::timer::tick_handler,
+        core::ptr::null_mut(),
+    )
+    .unwrap();
     libkernel::instructions::interrupts::enable();
 
     libkernel::structures::apic::load();
@@ -233,34 +337,54 @@ fn init_apic() {
         debug!("Resetting and enabling local APIC (it may have already been enabled).");
         apic.reset();
         apic.enable();
-        apic.write_spurious(u8::MAX, true);
+        apic.write_spurious(irq::SPURIOUS_VECTOR, true);
     }
 
+    info!(
+        "Local APIC {} enabled in {} mode.",
+        apic.id(),
+        if apic.is_x2apic() { "x2APIC" } else { "xAPIC" }
+    );
+
     let timer = timer::Timer::new(crate::timer::TIMER_FREQUENCY / 1000);
     debug!("Configuring APIC timer state.");
     apic.timer().set_mode(APICTimerMode::OneShot);
     apic.timer().set_masked(false);
 
     debug!("Determining APIC timer frequency using PIT windowing.");
-    apic[APICRegister::TimerDivisor] = APICTimerDivisor::Div1 as u32;
-    apic[APICRegister::TimerInitialCount] = u32::MAX;
+    apic.write_register(APICRegister::TIMER_DIVISOR, APICTimerDivisor::Div1 as u32);
+    apic.write_register(APICRegister::TIMER_INITIAL_COUNT, u32::MAX);
 
     timer.wait();
 
     apic.timer().set_masked(true);
-    apic[APICRegister::TimerInitialCount] = u32::MAX - apic[APICRegister::TimerCurrentCount];
-    apic[APICRegister::TimerDivisor] = APICTimerDivisor::Div1 as u32;
+    let elapsed = u32::MAX - apic.read_register(APICRegister::TIMER_CURRENT_COUNT);
+    apic.write_register(APICRegister::TIMER_INITIAL_COUNT, elapsed);
+    apic.write_register(APICRegister::TIMER_DIVISOR, APICTimerDivisor::Div1 as u32);
 
     debug!("Disabling 8259 emulated PIC.");
     libkernel::instructions::interrupts::without_interrupts(|| unsafe {
         crate::pic8259::disable()
     });
 
-    debug!("Updating APIC register vectors and respective IDT entires.");
-    apic.timer().set_vector(48);
-    idt::set_interrupt_handler(48, timer::apic_timer_handler);
-    apic.error().set_vector(58);
-    idt::set_interrupt_handler(58, apic_error_handler);
+    match libkernel::structures::ioapic::load() {
+        Ok(ioapic_count) => info!("Loaded {} I/O APIC(s), with all IRQs masked.", ioapic_count),
+        Err(error) => warn!("Failed to load I/O APICs: {:?}", error),
+    }
+
+    debug!("Allocating APIC register vectors and registering their handlers.");
+    let timer_vector = irq::allocate_vector().unwrap();
+    irq::register_handler(
+        timer_vector,
+        timer::apic_timer_handler,
+        core::ptr::null_mut(),
+    )
+    .unwrap();
+    apic.timer().set_vector(timer_vector);
+
+    let error_vector = irq::allocate_vector().unwrap();
+    irq::register_handler(error_vector, apic_error_handler, core::ptr::null_mut()).unwrap();
+    apic.error().set_vector(error_vector);
 
     debug!("Unmasking APIC timer interrupt (it will fire now!).");
     apic.timer().set_mode(APICTimerMode::Periodic);
@@ -269,9 +393,7 @@ fn init_apic() {
     info!("Core-local APIC configured and enabled.");
 }
 
-extern "x86-interrupt" fn apic_error_handler(
-    _: &mut libkernel::structures::idt::InterruptStackFrame,
-) {
+fn apic_error_handler(_: *mut ()) -> bool {
     let apic = libkernel::structures::apic::local_apic_mut().unwrap();
 
     error!("APIC ERROR INTERRUPT");
@@ -279,5 +401,5 @@ extern "x86-interrupt" fn apic_error_handler(
     error!("DUMPING APIC ERROR REGISTER:");
     error!("  {:?}", apic.error_status());
 
-    apic.end_of_interrupt();
+    true
 }
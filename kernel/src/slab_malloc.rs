@@ -0,0 +1,518 @@
+use crate::block_malloc::BlockAllocator;
+use core::{alloc::Layout, ptr::NonNull};
+use libkernel::{
+    addr_ty::{Physical, Virtual},
+    align_up_div,
+    memory::{
+        paging::{PageAttributes, PagingError},
+        Frame, FrameIterator, Page,
+    },
+    Address,
+};
+use spin::Mutex;
+
+const SIZE_CLASS_COUNT: usize = 8;
+
+/// An unallocated object within a slab, which stores a link to the next unallocated object.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// A cache of equally-sized objects, carved from whole pages.
+struct SlabCache {
+    object_size: usize,
+    free_list: Option<NonNull<FreeObject>>,
+}
+
+// Free objects are only ever accessed with the cache's lock held.
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            free_list: None,
+        }
+    }
+
+    /// Pops the first unallocated object from the free list.
+    fn pop(&mut self) -> Option<*mut u8> {
+        self.free_list.map(|object| {
+            self.free_list = unsafe { object.as_ref().next };
+            object.as_ptr() as *mut u8
+        })
+    }
+
+    /// Pushes an object onto the front of the free list.
+    unsafe fn push(&mut self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject {
+            next: self.free_list,
+        });
+
+        self.free_list = Some(NonNull::new_unchecked(object));
+    }
+
+    /// Carves the page at `page_ptr` into objects, and pushes them onto the free list.
+    unsafe fn refill(&mut self, page_ptr: *mut u8) {
+        trace!(
+            "Refilling slab cache ({} bytes) from page: {:?}",
+            self.object_size,
+            page_ptr
+        );
+
+        #[cfg(feature = "heap_debug")]
+        crate::heap_debug::poison_fresh(page_ptr, 0x1000);
+
+        // Push in reverse, so objects are handed out in ascending address order.
+        for offset in (0..0x1000).step_by(self.object_size).rev() {
+            self.push(page_ptr.add(offset));
+        }
+    }
+
+    /// Unlinks pages whose objects are all unallocated from the free list, passing each to
+    ///  `release` (up to `max_count` pages). A page is relinked if `release` returns `false`.
+    ///
+    /// Returns the number of pages released.
+    unsafe fn release_free_pages<F: FnMut(*mut u8) -> bool>(
+        &mut self,
+        max_count: usize,
+        mut release: F,
+    ) -> usize {
+        // Once sorted, a page's objects are adjacent on the free list.
+        self.free_list = Self::sort(self.free_list.take());
+
+        let objects_per_page = 0x1000 / self.object_size;
+        let mut released_count = 0;
+        let mut link: *mut Option<NonNull<FreeObject>> = &mut self.free_list;
+
+        while let (Some(object), true) = (*link, released_count < max_count) {
+            let page_ptr = object.as_ptr() as *mut u8;
+
+            // Find the object following this page's objects, if they're all unallocated.
+            let mut next = Some(object);
+            let is_free_page = (page_ptr as usize % 0x1000) == 0
+                && (0..objects_per_page).all(|index| match next {
+                    Some(next_object)
+                        if next_object.as_ptr() as *mut u8
+                            == page_ptr.add(index * self.object_size) =>
+                    {
+                        next = next_object.as_ref().next;
+                        true
+                    }
+                    _ => false,
+                });
+
+            if is_free_page && release(page_ptr) {
+                *link = next;
+                released_count += 1;
+            } else {
+                link = &mut (*object.as_ptr()).next;
+            }
+        }
+
+        released_count
+    }
+
+    /// Sorts a free list by address. This is a merge sort, so nothing is allocated.
+    unsafe fn sort(list: Option<NonNull<FreeObject>>) -> Option<NonNull<FreeObject>> {
+        let head = match list {
+            Some(head) if head.as_ref().next.is_some() => head,
+            _ => return list,
+        };
+
+        // Split the list at its middle.
+        let mut middle = head;
+        let mut end = head.as_ref().next;
+        while let Some(end_object) = end {
+            end = end_object.as_ref().next;
+
+            if let Some(end_object) = end {
+                end = end_object.as_ref().next;
+                middle = middle.as_ref().next.unwrap();
+            }
+        }
+        let back = (*middle.as_ptr()).next.take();
+
+        let (mut front, mut back) = (Self::sort(Some(head)), Self::sort(back));
+        let mut sorted = None;
+        let mut tail: *mut Option<NonNull<FreeObject>> = &mut sorted;
+        loop {
+            let object = match (front, back) {
+                (Some(front_object), Some(back_object)) if front_object < back_object => {
+                    front = front_object.as_ref().next;
+                    front_object
+                }
+                (_, Some(back_object)) => {
+                    back = back_object.as_ref().next;
+                    back_object
+                }
+                (rest, None) => {
+                    *tail = rest;
+                    return sorted;
+                }
+            };
+
+            *tail = Some(object);
+            tail = &mut (*object.as_ptr()).next;
+        }
+    }
+
+    /// Verifies the poison of every unallocated object, returning the number of corrupted objects.
+    #[cfg(feature = "heap_debug")]
+    fn verify(&self) -> usize {
+        let mut corrupted = 0;
+        let mut object = self.free_list;
+
+        while let Some(object_ptr) = object {
+            if !unsafe {
+                crate::heap_debug::verify_free_object(
+                    object_ptr.as_ptr() as *mut u8,
+                    self.object_size,
+                )
+            } {
+                corrupted += 1;
+            }
+
+            object = unsafe { object_ptr.as_ref().next };
+        }
+
+        corrupted
+    }
+}
+
+impl core::fmt::Debug for SlabCache {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        formatter
+            .debug_struct("SlabCache")
+            .field("Object Size", &self.object_size)
+            .field("Has Free", &self.free_list.is_some())
+            .finish()
+    }
+}
+
+/// Allocator utilizing size-classed slabs for small allocations, and whole
+///  pages (from an underlying `BlockAllocator`) for large allocations.
+///
+/// Small allocations are popped from and pushed onto a per-size-class free
+///  list, so neither `alloc` nor `dealloc` scan the heap on their hot path.
+pub struct SlabAllocator<'map> {
+    pages: BlockAllocator<'map>,
+    caches: [Mutex<SlabCache>; SIZE_CLASS_COUNT],
+}
+
+impl<'map> SlabAllocator<'map> {
+    /// Object sizes of each slab cache, in bytes.
+    pub const SIZE_CLASSES: [usize; SIZE_CLASS_COUNT] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+    /// Size of the smallest size class.
+    pub const MINIMUM_SIZE: usize = Self::SIZE_CLASSES[0];
+    /// Size of the largest size class; anything larger is allocated by the page.
+    pub const MAXIMUM_SIZE: usize = Self::SIZE_CLASSES[SIZE_CLASS_COUNT - 1];
+
+    pub const fn new() -> Self {
+        Self {
+            pages: BlockAllocator::new(),
+            caches: [
+                Mutex::new(SlabCache::new(Self::SIZE_CLASSES[0])),
+                Mutex::new(SlabCache::new(Self::SIZE_CLASSES[1])),
+                Mutex::new(SlabCache::new(Self::SIZE_CLASSES[2])),
+                Mutex::new(SlabCache::new(Self::SIZE_CLASSES[3])),
+                Mutex::new(SlabCache::new(Self::SIZE_CLASSES[4])),
+                Mutex::new(SlabCache::new(Self::SIZE_CLASSES[5])),
+                Mutex::new(SlabCache::new(Self::SIZE_CLASSES[6])),
+                Mutex::new(SlabCache::new(Self::SIZE_CLASSES[7])),
+            ],
+        }
+    }
+
+    /// The underlying page allocator.
+    pub fn pages(&self) -> &BlockAllocator<'map> {
+        &self.pages
+    }
+
+    /* INITIALIZATION */
+
+    pub unsafe fn init(&self, stack_frames: &mut FrameIterator) {
+        self.pages.init(stack_frames);
+
+        debug!("Slab allocator size classes: {:?}", Self::SIZE_CLASSES);
+    }
+
+    /* ALLOC & DEALLOC */
+
+    /// Index of the size class which satisfies the given layout, if any.
+    ///
+    /// Slab objects are naturally aligned to their (power-of-two) size, so
+    ///  alignment is satisfied by rounding the size class up to it.
+    fn size_class(layout: &Layout) -> Option<usize> {
+        let size = core::cmp::max(layout.size(), layout.align());
+
+        if size <= Self::MAXIMUM_SIZE {
+            let class_size = core::cmp::max(size, Self::MINIMUM_SIZE).next_power_of_two();
+
+            Some((class_size.trailing_zeros() - Self::MINIMUM_SIZE.trailing_zeros()) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Number of pages, and their page alignment, required to fulfill a large allocation.
+    fn page_fields(layout: &Layout) -> (usize, usize) {
+        (
+            align_up_div(layout.size(), 0x1000),
+            core::cmp::max(layout.align() / 0x1000, 1),
+        )
+    }
+
+    #[cfg_attr(any(feature = "heap_stats", feature = "heap_debug"), track_caller)]
+    pub fn alloc<T>(&self, layout: Layout) -> *mut T {
+        #[cfg(feature = "heap_debug")]
+        let ptr = {
+            let base_ptr = self.alloc_layout(crate::heap_debug::padded_layout(layout));
+
+            if base_ptr.is_null() {
+                base_ptr
+            } else {
+                unsafe {
+                    crate::heap_debug::on_alloc(base_ptr, layout, core::panic::Location::caller())
+                }
+            }
+        };
+        #[cfg(not(feature = "heap_debug"))]
+        let ptr = self.alloc_layout(layout);
+
+        #[cfg(feature = "heap_stats")]
+        {
+            if ptr.is_null() {
+                crate::heap_stats::record_failure(layout.size());
+            } else {
+                crate::heap_stats::record_alloc(
+                    ptr,
+                    layout.size(),
+                    Self::size_class(&layout),
+                    core::panic::Location::caller(),
+                );
+            }
+        }
+
+        ptr as *mut T
+    }
+
+    /// Allocates from the slab caches (or underlying page allocator) for exactly the given layout.
+    fn alloc_layout(&self, layout: Layout) -> *mut u8 {
+        match Self::size_class(&layout) {
+            Some(class_index) => {
+                let mut cache = self.caches[class_index].lock();
+
+                let ptr = match cache.pop() {
+                    Some(ptr) => ptr,
+                    None => {
+                        let page_ptr = self.pages.alloc_pages(1, 1);
+                        if page_ptr.is_null() {
+                            return core::ptr::null_mut();
+                        }
+
+                        unsafe { cache.refill(page_ptr) };
+                        cache.pop().unwrap()
+                    }
+                };
+
+                #[cfg(feature = "heap_debug")]
+                if !unsafe { crate::heap_debug::verify_free_object(ptr, cache.object_size) } {
+                    panic!("heap corruption detected on allocation of {:?}", ptr);
+                }
+
+                trace!(
+                    "Slab allocation fulfilled: {} bytes -> {:?}",
+                    layout.size(),
+                    ptr
+                );
+
+                ptr
+            }
+            None => {
+                let (page_count, page_alignment) = Self::page_fields(&layout);
+                self.pages.alloc_pages(page_count, page_alignment)
+            }
+        }
+    }
+
+    pub fn dealloc<T>(&self, ptr: *mut T, layout: Layout) {
+        #[cfg(feature = "heap_stats")]
+        crate::heap_stats::record_dealloc(ptr as *mut u8, layout.size(), Self::size_class(&layout));
+
+        #[cfg(feature = "heap_debug")]
+        unsafe {
+            let base_ptr = crate::heap_debug::on_dealloc(ptr as *mut u8, layout);
+            self.dealloc_layout(base_ptr, crate::heap_debug::padded_layout(layout));
+        }
+        #[cfg(not(feature = "heap_debug"))]
+        self.dealloc_layout(ptr as *mut u8, layout);
+    }
+
+    /// Resizes the allocation at `ptr` to `new_size` bytes.
+    ///
+    /// The allocation stays in place if the new size falls within the same size class, or if
+    ///  it's a page allocation the underlying page allocator can resize in place. Otherwise,
+    ///  it's moved to a new allocation. Returns a null pointer, leaving the allocation
+    ///  untouched, if it can't be resized.
+    #[cfg_attr(any(feature = "heap_stats", feature = "heap_debug"), track_caller)]
+    pub fn realloc<T>(&self, ptr: *mut T, layout: Layout, new_size: usize) -> *mut T {
+        let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+            Ok(new_layout) => new_layout,
+            Err(_) => return core::ptr::null_mut(),
+        };
+
+        // Red zones and allocation records are tied to the allocation's size, so resizing
+        //  in place is only done when neither are kept.
+        #[cfg(not(any(feature = "heap_stats", feature = "heap_debug")))]
+        match (Self::size_class(&layout), Self::size_class(&new_layout)) {
+            (Some(class_index), Some(new_class_index)) if class_index == new_class_index => {
+                return ptr;
+            }
+            (None, None) => {
+                let (page_count, _) = Self::page_fields(&layout);
+                let (new_page_count, _) = Self::page_fields(&new_layout);
+
+                if self.pages.realloc_pages(ptr, page_count, new_page_count) {
+                    return ptr;
+                }
+            }
+            _ => {}
+        }
+
+        let new_ptr = self.alloc::<T>(new_layout);
+        if !new_ptr.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    ptr as *const u8,
+                    new_ptr as *mut u8,
+                    core::cmp::min(layout.size(), new_size),
+                )
+            };
+            self.dealloc(ptr, layout);
+        }
+
+        new_ptr
+    }
+
+    /// Deallocates to the slab caches (or underlying page allocator) for exactly the given layout.
+    fn dealloc_layout(&self, ptr: *mut u8, layout: Layout) {
+        match Self::size_class(&layout) {
+            Some(class_index) => {
+                trace!(
+                    "Slab deallocation requested: {} bytes -> {:?}",
+                    layout.size(),
+                    ptr
+                );
+
+                unsafe { self.caches[class_index].lock().push(ptr) };
+            }
+            None => self.pages.dealloc_pages(ptr, Self::page_fields(&layout).0),
+        }
+    }
+
+    /// Returns pages whose objects are all unallocated to the underlying page allocator, until
+    ///  `target_frames` frames are freed. Returns the number of frames freed.
+    ///
+    /// This is the slab caches' shrinker (see `pressure::register_shrinker`), so locked caches
+    ///  are skipped, and pages are only returned if the page allocator isn't locked.
+    pub fn shrink(&self, target_frames: usize) -> usize {
+        let mut freed_frames = 0;
+
+        for mut cache in self.caches.iter().filter_map(|cache| cache.try_lock()) {
+            if freed_frames >= target_frames {
+                break;
+            }
+
+            freed_frames += unsafe {
+                cache.release_free_pages(target_frames - freed_frames, |page_ptr| {
+                    self.pages.try_dealloc_pages(page_ptr, 1)
+                })
+            };
+        }
+
+        freed_frames
+    }
+
+    /// Verifies the red zones of all live allocations, and the poison of all unallocated
+    ///  slab objects, panicking if any corruption is found.
+    ///
+    /// Any slab cache that is currently locked is skipped, so this is safe to call from
+    ///  an interrupt handler.
+    #[cfg(feature = "heap_debug")]
+    pub fn verify(&self) {
+        let mut corrupted = crate::heap_debug::verify_live();
+
+        for cache in self.caches.iter().filter_map(|cache| cache.try_lock()) {
+            corrupted += cache.verify();
+        }
+
+        if corrupted > 0 {
+            panic!("heap verification found {} corrupted objects", corrupted);
+        }
+    }
+}
+
+impl libkernel::memory::malloc::MemoryAllocator for SlabAllocator<'_> {
+    #[cfg_attr(any(feature = "heap_stats", feature = "heap_debug"), track_caller)]
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+
+    fn alloc_to(&self, frames: &FrameIterator) -> *mut u8 {
+        self.pages.alloc_to(frames)
+    }
+
+    fn dealloc_to(&self, ptr: *mut u8, frames: &FrameIterator) {
+        self.pages.dealloc_to(ptr, frames);
+    }
+
+    fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc(ptr, layout);
+    }
+
+    #[cfg_attr(any(feature = "heap_stats", feature = "heap_debug"), track_caller)]
+    fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.realloc(ptr, layout, new_size)
+    }
+
+    fn minimum_alignment(&self) -> usize {
+        Self::MINIMUM_SIZE
+    }
+
+    unsafe fn physical_memory(&self, addr: Address<Physical>) -> Address<Virtual> {
+        self.pages.physical_memory(addr)
+    }
+
+    unsafe fn map_page(&self, page: &Page, frame: &Frame) -> Result<(), PagingError> {
+        self.pages.map_page(page, frame)
+    }
+
+    unsafe fn try_map_page(&self, page: &Page, frame: &Frame) -> Option<Result<(), PagingError>> {
+        self.pages.try_map_page(page, frame)
+    }
+
+    unsafe fn map_pages(
+        &self,
+        page: &Page,
+        frame: &Frame,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError> {
+        self.pages.map_pages(page, frame, count, attribs)
+    }
+
+    unsafe fn unmap_pages(&self, page: &Page, count: usize) -> Result<(), PagingError> {
+        self.pages.unmap_pages(page, count)
+    }
+
+    unsafe fn set_pages_attribs(
+        &self,
+        page: &Page,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError> {
+        self.pages.set_pages_attribs(page, count, attribs)
+    }
+}
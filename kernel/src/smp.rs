@@ -0,0 +1,391 @@
+//! Bring-up of application processors (APs).
+//!
+//! Each enabled processor described by the MADT is started in turn with the INIT-SIPI-SIPI
+//! sequence. The startup IPI points it at a real-mode trampoline (see `ap_trampoline.s`) in
+//! low memory, which moves it to long mode on the kernel's page tables, with its own stack.
+//! From there, it loads its own GDT and TSS and the shared IDT, then idles.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use libkernel::{
+    addr_ty::Virtual,
+    memory::{
+        falloc::{self, FrameState},
+        malloc,
+        paging::{self, PagingError},
+        vma::{self, Area, AreaAttributes, AreaOwner},
+        Frame, Page,
+    },
+    registers::{CR4Flags, CR0, CR3, CR4, MSR},
+    structures::{
+        acpi::{xsdt, InterruptDevice, LocalAPICFlags, MADT},
+        apic::{self, APICDeliveryMode, IPIDestination, APIC},
+    },
+    Address,
+};
+
+global_asm!(include_str!("ap_trampoline.s"));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Parameters the trampoline reads from `ap_trampoline_data`, in the same order.
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    cr4: u64,
+    cr0: u64,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    argument: u64,
+}
+
+/// The startup IPI's vector is the page number of the trampoline, so it must be below 1MiB.
+const TRAMPOLINE_FRAME_LIMIT: usize = 0x100;
+/// Pages in each AP's stack (which is preceded by a guard page).
+const AP_STACK_PAGE_COUNT: usize = 16;
+/// Time to wait for an AP to come online, after its startup IPIs are sent.
+const AP_STARTUP_TIMEOUT_MSEC: usize = 100;
+
+/// State of the AP currently being started, which it claims on entering `ap_main`.
+///
+/// Once the BSP stops waiting for an AP, it abandons the startup, so an AP which reaches
+///  `ap_main` late parks itself rather than coming online (the BSP then parks it with an INIT
+///  IPI regardless, and frees its stack).
+static AP_STARTUP: AtomicU8 = AtomicU8::new(AP_STARTUP_WAITING);
+const AP_STARTUP_WAITING: u8 = 0;
+const AP_STARTUP_CLAIMED: u8 = 1;
+const AP_STARTUP_ABANDONED: u8 = 2;
+
+/// Number of CPUs online, including the bootstrap processor.
+pub fn cpus_online() -> usize {
+    libkernel::percpu::online_count()
+}
+
+/// The trampoline, copied into an identity-mapped frame below 1MiB. The frame is unmapped and
+///  freed when it's dropped.
+struct Trampoline {
+    frame: Frame,
+}
+
+impl Trampoline {
+    fn new() -> Option<Self> {
+        let frame = (1..TRAMPOLINE_FRAME_LIMIT).find_map(|index| unsafe {
+            falloc::get().acquire_frame(index, FrameState::Locked).ok()
+        })?;
+
+        unsafe {
+            malloc::get()
+                .map_page(&Page::from_index(frame.index()), &frame)
+                .expect("failed to identity map AP trampoline");
+
+            let start = &ap_trampoline_start as *const u8;
+            let len = (&ap_trampoline_end as *const u8).offset_from(start) as usize;
+            core::ptr::copy_nonoverlapping(start, frame.addr().as_usize() as *mut u8, len);
+        }
+
+        Some(Self { frame })
+    }
+
+    /// Vector of the startup IPI which starts an AP at the trampoline.
+    fn vector(&self) -> u8 {
+        self.frame.index() as u8
+    }
+
+    fn write_data(&mut self, data: TrampolineData) {
+        unsafe {
+            let offset =
+                (&ap_trampoline_data as *const u8).offset_from(&ap_trampoline_start) as usize;
+            // Volatile, as the data is only read by other processors.
+            core::ptr::write_volatile(
+                (self.frame.addr().as_usize() + offset) as *mut TrampolineData,
+                data,
+            );
+        }
+    }
+}
+
+impl Drop for Trampoline {
+    fn drop(&mut self) {
+        unsafe {
+            malloc::get()
+                .unmap_pages(&Page::from_index(self.frame.index()), 1)
+                .expect("failed to unmap AP trampoline");
+            falloc::get()
+                .free_frame(self.frame)
+                .expect("failed to free AP trampoline frame");
+        }
+    }
+}
+
+/// An AP's stack, preceded by a guard page.
+struct APStack {
+    area: Area,
+    frames: [Frame; AP_STACK_PAGE_COUNT],
+}
+
+impl APStack {
+    /// Reserves and maps a stack for an AP, or returns `None` if there isn't the memory for one.
+    fn new() -> Option<Self> {
+        let area = match vma::reserve(
+            AP_STACK_PAGE_COUNT + 1,
+            1,
+            "AP stack",
+            AreaOwner::Stack,
+            AreaAttributes::WRITABLE | AreaAttributes::GUARDED,
+        ) {
+            Ok(area) => area,
+            Err(vma_error) => {
+                warn!("Failed to reserve AP stack area: {:?}", vma_error);
+                return None;
+            }
+        };
+
+        // An AP runs on its stack before it loads its TSS (and so its page fault stack), so its
+        //  stack is mapped up front, rather than demand paged.
+        let mut frames = [Frame::null(); AP_STACK_PAGE_COUNT];
+        for (index, page) in area
+            .start()
+            .offset(1)
+            .iter_count(AP_STACK_PAGE_COUNT)
+            .enumerate()
+        {
+            let result = match falloc::get().lock_next() {
+                Some(frame) => match unsafe { malloc::get().map_page(&page, &frame) } {
+                    Ok(()) => {
+                        frames[index] = frame;
+                        Ok(())
+                    }
+                    Err(paging_error) => {
+                        unsafe { falloc::get().free_frame(frame).unwrap() };
+                        Err(paging_error)
+                    }
+                },
+                None => Err(PagingError::OutOfFrames),
+            };
+
+            if let Err(paging_error) = result {
+                warn!("Failed to map AP stack: {:?}", paging_error);
+                // No AP has been given the stack, so the pages mapped so far can be freed.
+                unsafe { Self::free_pages(area, &frames[..index]) };
+                return None;
+            }
+        }
+
+        Some(Self { area, frames })
+    }
+
+    fn top(&self) -> Address<Virtual> {
+        self.area.end().addr()
+    }
+
+    /// Unmaps the stack and frees its frames, then releases its area.
+    ///
+    /// Safety: no AP may be using the stack.
+    unsafe fn free(self) {
+        Self::free_pages(self.area, &self.frames);
+    }
+
+    /// Unmaps the stack's pages (after its guard page) mapped to `frames`, and frees the
+    ///  frames once the unmapping has been shot down, then releases the stack's area.
+    unsafe fn free_pages(area: Area, frames: &[Frame]) {
+        if !frames.is_empty() {
+            // `unmap_pages` flushes the shootdown before returning.
+            malloc::get()
+                .unmap_pages(&area.start().offset(1), frames.len())
+                .expect("failed to unmap AP stack");
+        }
+
+        falloc::get()
+            .free_frames(frames.iter().copied())
+            .expect("failed to free AP stack frames");
+        vma::release(area.start()).expect("failed to release AP stack area");
+    }
+}
+
+/// Starts every enabled application processor described by the MADT, returning the number of
+///  CPUs online afterwards.
+pub fn init() -> usize {
+    let madt = match xsdt::get_entry::<MADT>() {
+        Ok(madt) => madt,
+        Err(error) => {
+            warn!("No MADT found ({:?}); not starting any APs.", error);
+            return cpus_online();
+        }
+    };
+
+    // The trampoline loads CR3 before entering long mode, so only has 32 bits of it.
+    let cr3 = CR3::read_frame().addr().as_usize() as u64;
+    if cr3 > (u32::MAX as u64) {
+        warn!("Kernel page tables are above 4GiB; not starting any APs.");
+        return cpus_online();
+    }
+
+    let mut trampoline = match Trampoline::new() {
+        Some(trampoline) => trampoline,
+        None => {
+            warn!("No free frame below 1MiB for the AP trampoline; not starting any APs.");
+            return cpus_online();
+        }
+    };
+    debug!(
+        "Copied AP trampoline to frame {} (startup vector {}).",
+        trampoline.frame.index(),
+        trampoline.vector()
+    );
+
+    let apic = apic::local_apic_mut().expect("local APIC must be loaded to start APs");
+    let bsp_apic_id = apic.id();
+
+    for interrupt_device in madt.iter() {
+        if let InterruptDevice::LocalAPIC(local_apic) = interrupt_device {
+            let apic_id = local_apic.id() as u32;
+
+            if apic_id == bsp_apic_id
+                || !local_apic
+                    .flags()
+                    .contains(LocalAPICFlags::PROCESSOR_ENABLED)
+            {
+                continue;
+            }
+
+            let stack = match APStack::new() {
+                Some(stack) => stack,
+                None => {
+                    warn!("Not starting AP with APIC ID {}.", apic_id);
+                    continue;
+                }
+            };
+            trampoline.write_data(TrampolineData {
+                cr3,
+                // PCIDs can't be enabled until the AP is in long mode. The raw bits are used,
+                //  so bits unknown to `CR4Flags` are kept.
+                cr4: (CR4::read().bits() & !CR4Flags::PCIDE.bits()) as u64,
+                cr0: CR0::read().bits() as u64,
+                // EFER.LMA is set by the CPU itself, once paging is enabled.
+                efer: MSR::IA32_EFER.read() & !(1 << 10),
+                stack_top: stack.top().as_usize() as u64,
+                entry: ap_main as usize as u64,
+                argument: apic_id as u64,
+            });
+
+            if start_ap(apic, apic_id, trampoline.vector()) {
+                debug!("Started AP with APIC ID {}.", apic_id);
+            } else {
+                warn!("AP with APIC ID {} failed to start.", apic_id);
+                // The AP was parked by `start_ap`, so it can't be using its stack.
+                unsafe { stack.free() };
+            }
+        }
+    }
+
+    cpus_online()
+}
+
+/// Sends the INIT-SIPI-SIPI sequence to an AP, returning whether it came online.
+///
+/// If it doesn't come online in time, the startup is abandoned, and the AP is parked with an
+///  INIT IPI, so it no longer uses its stack or the trampoline.
+fn start_ap(apic: &mut APIC, apic_id: u32, vector: u8) -> bool {
+    let online = cpus_online();
+    AP_STARTUP.store(AP_STARTUP_WAITING, Ordering::Release);
+
+    apic.send_ipi(IPIDestination::APIC(apic_id), APICDeliveryMode::INIT, 0);
+    crate::timer::sleep_msec(10);
+
+    // The second startup IPI is only needed if the first was missed.
+    for _ in 0..2 {
+        apic.send_ipi(
+            IPIDestination::APIC(apic_id),
+            APICDeliveryMode::StartUp,
+            vector,
+        );
+        crate::timer::sleep_msec(1);
+
+        if cpus_online() > online {
+            return true;
+        }
+    }
+
+    let timeout = crate::timer::get_ticks() + AP_STARTUP_TIMEOUT_MSEC;
+    while crate::timer::get_ticks() < timeout {
+        if cpus_online() > online {
+            return true;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    match AP_STARTUP.compare_exchange(
+        AP_STARTUP_WAITING,
+        AP_STARTUP_ABANDONED,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    ) {
+        Ok(_) => {
+            apic.send_ipi(IPIDestination::APIC(apic_id), APICDeliveryMode::INIT, 0);
+            false
+        }
+        // The AP reached `ap_main` in time, so it's committed to coming online.
+        Err(_) => {
+            while cpus_online() == online {
+                core::hint::spin_loop();
+            }
+
+            true
+        }
+    }
+}
+
+/// Entry point of each AP, called by the trampoline in long mode, on the AP's own stack.
+extern "C" fn ap_main(apic_id: u64) -> ! {
+    // The GS base is cleared by loading the segment registers, so it's pointed at a null per-CPU
+    //  block until the AP initializes its own.
+    unsafe { libkernel::instructions::init_segment_registers(0x0) };
+    libkernel::percpu::init_null();
+
+    if AP_STARTUP
+        .compare_exchange(
+            AP_STARTUP_WAITING,
+            AP_STARTUP_CLAIMED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+    {
+        // The BSP gave up waiting for this AP, and will park it.
+        libkernel::instructions::interrupts::disable();
+        libkernel::instructions::hlt_indefinite();
+    }
+
+    libkernel::percpu::init();
+    libkernel::structures::gdt::init_ap();
+    libkernel::structures::idt::load();
+
+    unsafe {
+        if paging::pcid_enabled() {
+            CR4::write(CR4::read() | CR4Flags::PCIDE);
+        }
+
+        if paging::write_combining_enabled() {
+            paging::enable_write_combining();
+        }
+
+        apic::init_ap();
+    }
+
+    // The AP wasn't included in any TLB shootdown before it came online.
+    libkernel::percpu::get().set_online();
+    libkernel::instructions::tlb::invalidate_all();
+    debug!(
+        "AP with APIC ID {} online as CPU {}.",
+        apic_id,
+        libkernel::percpu!(cpu_id)
+    );
+
+    libkernel::instructions::interrupts::enable();
+    crate::timer::idle_indefinite()
+}
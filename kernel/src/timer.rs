@@ -1,34 +1,43 @@
 #![allow(dead_code)]
 
-use core::sync::atomic::AtomicUsize;
-
-static TICKS: AtomicUsize = AtomicUsize::new(0);
+use core::sync::atomic::Ordering;
 
 // Frequency of timer, or ticks per second.
 pub const TIMER_FREQUENCY: usize = 1000;
 
-pub extern "x86-interrupt" fn tick_handler(
-    _: &mut libkernel::structures::idt::InterruptStackFrame,
-) {
-    TICKS.fetch_add(1, core::sync::atomic::Ordering::Release);
+pub fn tick_handler(_: *mut ()) -> bool {
+    libkernel::percpu_count!(timer_ticks);
+    // The PIT is delivered through the PIC, which must be acknowledged separately.
     crate::pic8259::end_of_interrupt(crate::pic8259::InterruptOffset::Timer);
+
+    true
 }
 
-pub extern "x86-interrupt" fn apic_timer_handler(
-    _: &mut libkernel::structures::idt::InterruptStackFrame,
-) {
-    TICKS.fetch_add(1, core::sync::atomic::Ordering::Release);
-    libkernel::structures::apic::local_apic_mut()
-        .unwrap()
-        .end_of_interrupt();
+pub fn apic_timer_handler(_: *mut ()) -> bool {
+    libkernel::percpu_count!(timer_ticks);
+
+    #[cfg(feature = "heap_debug")]
+    if (get_ticks_unordered() % crate::heap_debug::VERIFY_INTERVAL_TICKS) == 0 {
+        crate::KERNEL_MALLOC.verify();
+    }
+
+    true
 }
 
+/// Ticks of the current CPU's timer (see `percpu::Counters::timer_ticks`), or 0 before its
+///  per-CPU data is initialized.
+///
+/// Ticks are counted per CPU, so only a CPU whose timer is running can wait on them.
 pub fn get_ticks() -> usize {
-    TICKS.load(core::sync::atomic::Ordering::Acquire)
+    libkernel::percpu::try_get().map_or(0, |percpu| {
+        percpu.counters().timer_ticks.load(Ordering::Acquire)
+    })
 }
 
 pub fn get_ticks_unordered() -> usize {
-    TICKS.load(core::sync::atomic::Ordering::Relaxed)
+    libkernel::percpu::try_get().map_or(0, |percpu| {
+        percpu.counters().timer_ticks.load(Ordering::Relaxed)
+    })
 }
 
 pub fn sleep_msec(milliseconds: usize) {
@@ -39,13 +48,32 @@ pub fn sleep_sec(seconds: usize) {
     tick_wait(get_ticks() + (seconds * 1000))
 }
 
+/// Number of frames zeroed between checks of the tick count, while waiting.
+const IDLE_ZEROING_BATCH: usize = 16;
+
+/// Waits for the tick count to reach `target_ticks`, idling in the meantime (see `idle`).
 #[inline(always)]
 fn tick_wait(target_ticks: usize) {
     while get_ticks() < target_ticks {
+        idle();
+    }
+}
+
+/// Replenishes the frame allocator's pool of pre-zeroed frames by one batch, or halts until
+///  the next interrupt if it's full.
+pub fn idle() {
+    if libkernel::memory::falloc::get().replenish_zeroed(IDLE_ZEROING_BATCH) == 0 {
         libkernel::instructions::hlt();
     }
 }
 
+/// Idles the CPU indefinitely (see `idle`). Interrupts must be enabled.
+pub fn idle_indefinite() -> ! {
+    loop {
+        idle();
+    }
+}
+
 pub struct Timer {
     ticks: usize,
     end_tick: usize,
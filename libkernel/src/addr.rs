@@ -11,7 +11,10 @@ pub mod addr_ty {
 use crate::addr_ty::*;
 use core::marker::PhantomData;
 
+/// Size of the virtual address space with 4-level paging (48-bit addresses).
 pub const VADDR_HW_MAX: usize = 0x1000000000000;
+/// Size of the virtual address space with 5-level paging (57-bit addresses).
+pub const VADDR_HW_MAX_LA57: usize = 0x200000000000000;
 
 #[repr(transparent)]
 pub struct Address<T: AddressType> {
@@ -175,17 +178,23 @@ impl core::fmt::Debug for Address<Physical> {
 }
 
 impl Address<Virtual> {
-    pub const fn new(addr: usize) -> Self {
-        match addr >> 47 {
-            0 | 0x1FFFF => Self {
-                value: addr,
-                phantom: PhantomData,
-            },
-            1 => Self::new_truncate(addr),
-            _ => panic!("given address is not canonical (bits 48..64 contain data)"),
+    /// Creates a new virtual address, which must be canonical with the active paging depth
+    ///  (48-bit with 4-level paging, or 57-bit with 5-level paging).
+    pub fn new(addr: usize) -> Self {
+        let canonical = if crate::memory::paging::five_level_paging() {
+            Self::new_truncate_la57(addr)
+        } else {
+            Self::new_truncate(addr)
+        };
+
+        if canonical.value == addr {
+            canonical
+        } else {
+            panic!("given address is not canonical with the active paging depth")
         }
     }
 
+    /// Creates a new virtual address, sign-extending it from bit 47 (as with 4-level paging).
     pub const fn new_truncate(addr: usize) -> Self {
         Self {
             value: (((addr << 16) as isize) >> 16) as usize,
@@ -193,8 +202,22 @@ impl Address<Virtual> {
         }
     }
 
-    pub const fn from_ptr<T>(ptr: *const T) -> Self {
-        Self::new(unsafe { ptr as usize })
+    /// Creates a new virtual address, sign-extending it from bit 56 (as with 5-level paging).
+    pub const fn new_truncate_la57(addr: usize) -> Self {
+        Self {
+            value: (((addr << 7) as isize) >> 7) as usize,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Whether the address is within the upper (kernel) half of the address space, with
+    ///  either paging depth.
+    pub const fn is_upper_half(&self) -> bool {
+        (self.value as isize) < 0
+    }
+
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        Self::new(ptr as usize)
     }
 
     pub const fn page_index(&self) -> usize {
@@ -228,6 +251,10 @@ impl Address<Virtual> {
     pub const fn p4_index(&self) -> usize {
         (self.value >> 12 >> 9 >> 9 >> 9) & 0x1FF
     }
+
+    pub const fn p5_index(&self) -> usize {
+        (self.value >> 12 >> 9 >> 9 >> 9 >> 9) & 0x1FF
+    }
 }
 
 impl core::fmt::Debug for Address<Virtual> {
@@ -79,3 +79,56 @@ pub fn cpu_features() -> CPUFeatures {
     let values = cpuid(0x1, 0x0);
     CPUFeatures::from_bits_truncate(((values.3 as u64) << 32) | (values.2 as u64))
 }
+
+bitflags::bitflags! {
+    /// Structured extended features (leaf 0x7, subleaf 0x0), with EBX in the low
+    ///  32 bits and ECX in the high 32 bits.
+    pub struct CPUExtendedFeatures: u64 {
+        const FSGSBASE     = 1 << 0;
+        const TSC_ADJUST   = 1 << 1;
+        const SGX          = 1 << 2;
+        const BMI1         = 1 << 3;
+        const HLE          = 1 << 4;
+        const AVX2         = 1 << 5;
+        const SMEP         = 1 << 7;
+        const BMI2         = 1 << 8;
+        const ERMS         = 1 << 9;
+        const INVPCID      = 1 << 10;
+        const RTM          = 1 << 11;
+        const MPX          = 1 << 14;
+        const AVX512F      = 1 << 16;
+        const RDSEED       = 1 << 18;
+        const ADX          = 1 << 19;
+        const SMAP         = 1 << 20;
+        const CLFLUSHOPT   = 1 << 23;
+        const CLWB         = 1 << 24;
+        const SHA          = 1 << 29;
+        const PREFETCHWT1  = 1 << 32;
+        const AVX512VBMI   = 1 << 33;
+        const UMIP         = 1 << 34;
+        const PKU          = 1 << 35;
+        const OSPKE        = 1 << 36;
+        const LA57         = 1 << 48;
+        const RDPID        = 1 << 54;
+    }
+}
+
+pub fn cpu_extended_features() -> CPUExtendedFeatures {
+    // leaf 0x7 is only valid if the maximum basic leaf includes it
+    if cpuid(0x0, 0x0).0 < 0x7 {
+        return CPUExtendedFeatures::empty();
+    }
+
+    let values = cpuid(0x7, 0x0);
+    CPUExtendedFeatures::from_bits_truncate(((values.2 as u64) << 32) | (values.1 as u64))
+}
+
+/// Initial (x2)APIC ID of the current processor, as reported by CPUID.
+pub fn apic_id() -> u32 {
+    // leaf 0xB reports the full 32-bit x2APIC ID, where leaf 0x1 only reports 8 bits
+    if cpuid(0x0, 0x0).0 >= 0xB && cpuid(0xB, 0x0).1 != 0 {
+        cpuid(0xB, 0x0).3
+    } else {
+        cpuid(0x1, 0x0).1 >> 24
+    }
+}
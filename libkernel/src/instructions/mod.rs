@@ -17,6 +17,44 @@ pub fn hlt_indefinite() -> ! {
     }
 }
 
+/// Reads the CPU's time-stamp counter.
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Writes back every modified line of the CPU's caches to memory, and invalidates them.
+pub unsafe fn wbinvd() {
+    asm!("wbinvd", options(nostack, preserves_flags));
+}
+
+/// Size of the cache lines `clflush` operates on.
+const CACHE_LINE_SIZE: usize = 64;
+
+/// Writes back and invalidates the cache lines of `len` bytes starting at `ptr`, in every cache
+///  of the coherency domain (i.e. before changing the memory's type).
+pub unsafe fn flush_cache_range(ptr: *const u8, len: usize) {
+    let start = (ptr as usize) & !(CACHE_LINE_SIZE - 1);
+
+    for line in (start..((ptr as usize) + len)).step_by(CACHE_LINE_SIZE) {
+        asm!("clflush [{}]", in(reg) line, options(nostack, preserves_flags));
+    }
+
+    asm!("mfence", options(nostack, preserves_flags));
+}
+
+/// Swaps the GS base with `IA32_KERNEL_GS_BASE`, i.e. when entering or leaving the kernel from
+///  user mode.
+pub unsafe fn swapgs() {
+    asm!("swapgs", options(nomem, nostack, preserves_flags));
+}
+
 pub unsafe fn init_segment_registers(value: u16) {
     asm!(
         "mov ds, ax",
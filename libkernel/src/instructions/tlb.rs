@@ -1,4 +1,21 @@
-use crate::memory::Page;
+use crate::{
+    memory::{falloc, paging, Frame, Page},
+    percpu,
+    registers::{CR4Flags, CR4},
+    structures::{
+        apic::{self, APICDeliveryMode, IPIDestination},
+        irq::{self, IRQError},
+    },
+};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+/// Incremented whenever entries tagged with process-context identifiers other than the current
+///  one may have become stale: when a mapping shared with the kernel's address space is removed
+///  or changed, or when an identifier is released for reuse.
+///
+/// `invlpg` only invalidates entries tagged with the current identifier, so each CPU compares
+///  this against the generation it last flushed at when switching address spaces.
+static CONTEXT_GENERATION: AtomicUsize = AtomicUsize::new(0);
 
 pub fn invalidate(page: &Page) {
     unsafe {
@@ -9,3 +26,254 @@ pub fn invalidate(page: &Page) {
 pub fn invalidate_all() {
     crate::registers::CR3::refresh();
 }
+
+/// Invalidates every TLB entry, including global entries and those tagged with any
+///  process-context identifier, by toggling CR4.PGE.
+pub fn invalidate_all_contexts() {
+    let cr4 = CR4::read();
+
+    unsafe {
+        CR4::write(cr4 ^ CR4Flags::PGE);
+        CR4::write(cr4);
+    }
+}
+
+/// Number of pages past which invalidating a range reloads CR3, rather than invalidating
+///  each page individually.
+pub const INVALIDATE_ALL_THRESHOLD: usize = 64;
+
+/// Invalidates `count` contiguous pages starting at `page`, reloading CR3 if the
+///  range exceeds `INVALIDATE_ALL_THRESHOLD`.
+pub fn invalidate_range(page: &Page, count: usize) {
+    if count > INVALIDATE_ALL_THRESHOLD {
+        invalidate_all();
+    } else {
+        page.iter_count(count).for_each(|page| invalidate(&page));
+    }
+}
+
+/// Notes that the mappings of `count` pages starting at `page` were removed or changed.
+///
+/// Pages shared with the kernel's address space (which every CPU uses) are marked stale in
+///  every other address space, and a shootdown of them on every other CPU is deferred until
+///  `flush_shootdowns` is called.
+pub fn note_modified(page: &Page, count: usize) {
+    let last_page = page.offset(count.saturating_sub(1));
+
+    if is_kernel_page(page) || is_kernel_page(&last_page) {
+        CONTEXT_GENERATION.fetch_add(1, Ordering::AcqRel);
+        defer_shootdown(page, count);
+    }
+}
+
+/// Notes that entries tagged with any process-context identifier may be stale (i.e. one was
+///  released, and may be reused by another address space).
+pub fn note_contexts_stale() {
+    CONTEXT_GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+fn is_kernel_page(page: &Page) -> bool {
+    let addr = page.addr();
+
+    paging::is_kernel_root_entry(if paging::five_level_paging() {
+        addr.p5_index()
+    } else {
+        addr.p4_index()
+    })
+}
+
+pub fn context_generation() -> usize {
+    CONTEXT_GENERATION.load(Ordering::Acquire)
+}
+
+/// Invalidates every process-context identifier's entries on the current CPU if any may have
+///  become stale since it last did (see `CONTEXT_GENERATION`). Called when switching address
+///  spaces.
+pub fn invalidate_stale_contexts() {
+    let generation = context_generation();
+    let stale = percpu::try_get().map_or(true, |percpu| {
+        percpu.swap_context_generation(generation) != generation
+    });
+
+    if stale {
+        invalidate_all_contexts();
+    }
+}
+
+/* SHOOTDOWN */
+
+/// Vector shootdown IPIs are sent on, or 0 until `init_shootdown` allocates it.
+static SHOOTDOWN_VECTOR: AtomicU8 = AtomicU8::new(0);
+/// Held by the CPU with an outstanding shootdown, so there's only ever one.
+static SHOOTDOWN_LOCK: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_PAGE: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// Number of CPUs which haven't yet acknowledged the outstanding shootdown.
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocates the vector of shootdown IPIs and registers its handler, returning the vector.
+/// Until this is called, shootdowns are skipped (as no other CPU can be online).
+pub fn init_shootdown() -> Result<u8, IRQError> {
+    let vector = irq::allocate_vector()?;
+    irq::register_handler(vector, shootdown_handler, core::ptr::null_mut())?;
+    SHOOTDOWN_VECTOR.store(vector, Ordering::Release);
+
+    Ok(vector)
+}
+
+fn shootdown_handler(_: *mut ()) -> bool {
+    service_shootdown()
+}
+
+/// Performs the outstanding shootdown on the current CPU, if it was requested of it, then
+///  acknowledges it. Returns whether one was requested.
+fn service_shootdown() -> bool {
+    match percpu::try_get() {
+        Some(percpu) if percpu.take_shootdown_request() => {
+            invalidate_range(
+                &Page::from_index(SHOOTDOWN_PAGE.load(Ordering::Acquire)),
+                SHOOTDOWN_COUNT.load(Ordering::Acquire),
+            );
+            percpu
+                .counters()
+                .tlb_shootdowns
+                .fetch_add(1, Ordering::Relaxed);
+            SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Adds `count` pages starting at `page` to the current CPU's deferred shootdown, which is sent
+///  by `flush_shootdowns`.
+///
+/// Deferred shootdowns are batched: the pages modified by one operation (between flushes) are
+///  invalidated with a single shootdown, covering every page between the lowest and highest.
+pub fn defer_shootdown(page: &Page, count: usize) {
+    if SHOOTDOWN_VECTOR.load(Ordering::Relaxed) > 0 {
+        if let Some(percpu) = percpu::try_get() {
+            percpu.defer_shootdown(page.index(), page.index().saturating_add(count));
+        }
+    }
+}
+
+/// Sends the current CPU's deferred shootdown (see `defer_shootdown`), if it has one.
+///
+/// This must be called once the locks held while modifying the pages are released (i.e. the
+///  default allocator's addressor lock), for the reason given by `shootdown`.
+pub fn flush_shootdowns() {
+    if let Some((start, end)) =
+        percpu::try_get().and_then(|percpu| percpu.take_deferred_shootdown())
+    {
+        shootdown(&Page::from_index(start), end - start);
+    }
+}
+
+/// Frames released by unmapping pages, which are only freed once the unmapping has been shot
+///  down, so no CPU can still reach them through a stale TLB entry.
+pub struct ReleasedFrames {
+    frames: [Frame; Self::CAPACITY],
+    len: usize,
+}
+
+impl ReleasedFrames {
+    /// Maximum number of frames released between flushes.
+    pub const CAPACITY: usize = 32;
+
+    pub const fn new() -> Self {
+        Self {
+            frames: [Frame::null(); Self::CAPACITY],
+            len: 0,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == Self::CAPACITY
+    }
+
+    /// Adds a frame (or a reference to one) to be freed by `flush_and_free`.
+    pub fn push(&mut self, frame: Frame) {
+        assert!(
+            !self.is_full(),
+            "more than {} frames released between flushes",
+            Self::CAPACITY
+        );
+
+        self.frames[self.len] = frame;
+        self.len += 1;
+    }
+
+    /// Sends the current CPU's deferred shootdown (see `flush_shootdowns`), then frees the
+    ///  released frames, dropping a single reference to each.
+    ///
+    /// As with `flush_shootdowns`, this must be called once the locks held while unmapping
+    ///  are released.
+    pub fn flush_and_free(&mut self) {
+        flush_shootdowns();
+
+        for frame in &self.frames[..self.len] {
+            unsafe { falloc::get().free_frame(*frame).unwrap() };
+        }
+
+        self.len = 0;
+    }
+}
+
+/// Invalidates `count` pages starting at `page` on every other online CPU, waiting until each
+///  has acknowledged it. The current CPU's TLB isn't invalidated.
+///
+/// A CPU only acknowledges a shootdown with interrupts enabled (or while waiting to start its
+///  own), so this mustn't be called while another CPU could be spinning with interrupts
+///  disabled on a lock the caller holds (as the page fault and interrupt handlers may).
+pub fn shootdown(page: &Page, count: usize) {
+    let vector = SHOOTDOWN_VECTOR.load(Ordering::Acquire);
+    let current = match percpu::try_get() {
+        Some(current) if vector > 0 => current,
+        _ => return,
+    };
+
+    // Interrupts are disabled so a handler on this CPU can't start a shootdown of its own
+    //  while this one holds the lock.
+    crate::instructions::interrupts::without_interrupts(|| {
+        // Shootdowns requested of this CPU are performed while waiting, so CPUs shooting down
+        //  at the same time can't deadlock.
+        while SHOOTDOWN_LOCK
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            service_shootdown();
+            core::hint::spin_loop();
+        }
+
+        SHOOTDOWN_PAGE.store(page.index(), Ordering::Release);
+        SHOOTDOWN_COUNT.store(count, Ordering::Release);
+
+        let mut requested = false;
+        for percpu in percpu::iter()
+            .filter(|percpu| percpu.is_online() && percpu.cpu_id() != current.cpu_id())
+        {
+            SHOOTDOWN_PENDING.fetch_add(1, Ordering::AcqRel);
+            percpu.request_shootdown();
+            requested = true;
+        }
+
+        if requested {
+            // CPUs which weren't asked ignore the IPI, so it's simply broadcast.
+            apic::local_apic_mut()
+                .expect("local APIC must be loaded to shoot down TLB entries")
+                .send_ipi(
+                    IPIDestination::AllExcludingCurrent,
+                    APICDeliveryMode::Fixed,
+                    vector,
+                );
+
+            while SHOOTDOWN_PENDING.load(Ordering::Acquire) > 0 {
+                core::hint::spin_loop();
+            }
+        }
+
+        SHOOTDOWN_LOCK.store(false, Ordering::Release);
+    });
+}
@@ -18,19 +18,13 @@ impl PCIeBus {
         let devices = (0..32)
             .filter_map(|device_index| {
                 let offset_addr = base_addr + (device_index << 15);
-                let header = &*crate::memory::malloc::get()
+                // Absent devices read all ones for their vendor ID.
+                let vendor_id = crate::memory::malloc::get()
                     .physical_memory(offset_addr)
-                    .as_ptr::<crate::io::pci::PCIDeviceHeader>();
-
-                if header.is_valid() {
-                    debug!(
-                        "Found PCIe device: {} {} [0x{:X}:0x{:X}]",
-                        header.vendor_str(),
-                        header.device_str(),
-                        header.vendor_id(),
-                        header.device_id()
-                    );
+                    .as_ptr::<u16>()
+                    .read_volatile();
 
+                if vendor_id != u16::MAX {
                     let mmio_frames = crate::memory::falloc::get()
                         .acquire_frame(
                             offset_addr.frame_index(),
@@ -39,11 +33,22 @@ impl PCIeBus {
                         .unwrap()
                         .into_iter();
 
-                    Some(PCIeDevice::new(
+                    let device = PCIeDevice::new(
                         crate::memory::mmio::unmapped_mmio(mmio_frames)
                             .unwrap()
                             .map(),
-                    ))
+                    );
+
+                    let header = device.base_header();
+                    debug!(
+                        "Found PCIe device: {} {} [0x{:X}:0x{:X}]",
+                        header.vendor_str(),
+                        header.device_str(),
+                        header.vendor_id(),
+                        header.device_id()
+                    );
+
+                    Some(device)
                 } else {
                     None
                 }
@@ -1,6 +1,6 @@
 use crate::{
     io::pci::PCIDeviceHeader,
-    memory::mmio::{Mapped, MMIO},
+    memory::mmio::{Mapped, ReadOnlyRegister, ReadWriteRegister, RegisterValue, MMIO},
 };
 use core::fmt;
 
@@ -12,10 +12,28 @@ impl BARLayoutVariant for MemorySpace {}
 pub enum IOSpace {}
 impl BARLayoutVariant for IOSpace {}
 
-#[derive(Debug)]
-pub enum BaseAddressRegisterType<'a> {
-    MemorySpace(&'a BaseAddressRegister<MemorySpace>),
-    IOSpace(&'a BaseAddressRegister<IOSpace>),
+#[derive(Debug, Clone, Copy)]
+pub enum BaseAddressRegisterType {
+    MemorySpace(BaseAddressRegister<MemorySpace>),
+    IOSpace(BaseAddressRegister<IOSpace>),
+}
+
+impl RegisterValue for BaseAddressRegisterType {
+    type Raw = u32;
+
+    fn from_raw(raw: Self::Raw) -> Self {
+        match (raw & 0b1) > 0 {
+            false => Self::MemorySpace(BaseAddressRegister::from_raw(raw)),
+            true => Self::IOSpace(BaseAddressRegister::from_raw(raw)),
+        }
+    }
+
+    fn into_raw(self) -> Self::Raw {
+        match self {
+            Self::MemorySpace(register) => register.into_raw(),
+            Self::IOSpace(register) => register.into_raw(),
+        }
+    }
 }
 
 #[repr(u32)]
@@ -33,6 +51,29 @@ pub struct BaseAddressRegister<T: BARLayoutVariant> {
     phantom: core::marker::PhantomData<T>,
 }
 
+impl<T: BARLayoutVariant> Clone for BaseAddressRegister<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: BARLayoutVariant> Copy for BaseAddressRegister<T> {}
+
+impl<T: BARLayoutVariant> RegisterValue for BaseAddressRegister<T> {
+    type Raw = u32;
+
+    fn from_raw(raw: Self::Raw) -> Self {
+        Self {
+            value: raw,
+            phantom: core::marker::PhantomData,
+        }
+    }
+
+    fn into_raw(self) -> Self::Raw {
+        self.value
+    }
+}
+
 impl BaseAddressRegister<MemorySpace> {
     pub fn base_address<T>(&self) -> *const T {
         (self.value & !0b1111) as *const _
@@ -91,101 +132,111 @@ impl PCIeDeviceVariant for PCI2CardBus {}
 
 #[derive(Debug)]
 pub enum PCIeDeviceType<'a> {
-    Standard(&'a PCIeDeviceHeader<Standard>),
-    PCI2PCI(&'a PCIeDeviceHeader<PCI2PCI>),
-    PCI2CardBus(&'a PCIeDeviceHeader<PCI2CardBus>),
+    Standard(PCIeDeviceHeader<'a, Standard>),
+    PCI2PCI(PCIeDeviceHeader<'a, PCI2PCI>),
+    PCI2CardBus(PCIeDeviceHeader<'a, PCI2CardBus>),
 }
 
-pub struct PCIeDeviceHeader<T: PCIeDeviceVariant> {
+/// The header-type specific portion of a PCIe device's configuration space.
+pub struct PCIeDeviceHeader<'a, T: PCIeDeviceVariant> {
+    mmio: &'a MMIO<Mapped>,
     phantom: core::marker::PhantomData<T>,
 }
 
-impl PCIeDeviceHeader<Standard> {
-    unsafe fn offset<T: Copy>(&self, offset: usize) -> &T {
-        &*((self as *const _ as *const u8).add(offset) as *const T)
-    }
-
-    unsafe fn base_addr_register(&self, offset: usize) -> BaseAddressRegisterType {
-        let register = (self as *const _ as *const u8).add(offset) as *const u32;
-
-        match (*register & 0b1) > 0 {
-            false => BaseAddressRegisterType::MemorySpace(
-                &*(register as *const BaseAddressRegister<MemorySpace>),
-            ),
-            true => BaseAddressRegisterType::IOSpace(
-                &*(register as *const BaseAddressRegister<IOSpace>),
-            ),
+impl<'a, T: PCIeDeviceVariant> PCIeDeviceHeader<'a, T> {
+    fn new(mmio: &'a MMIO<Mapped>) -> Self {
+        Self {
+            mmio,
+            phantom: core::marker::PhantomData,
         }
     }
+}
+
+impl PCIeDeviceHeader<'_, Standard> {
+    pub const BAR_0: ReadWriteRegister<BaseAddressRegisterType> = ReadWriteRegister::new(0x10);
+    pub const BAR_1: ReadWriteRegister<BaseAddressRegisterType> = ReadWriteRegister::new(0x14);
+    pub const BAR_2: ReadWriteRegister<BaseAddressRegisterType> = ReadWriteRegister::new(0x18);
+    pub const BAR_3: ReadWriteRegister<BaseAddressRegisterType> = ReadWriteRegister::new(0x1C);
+    pub const BAR_4: ReadWriteRegister<BaseAddressRegisterType> = ReadWriteRegister::new(0x20);
+    pub const BAR_5: ReadWriteRegister<BaseAddressRegisterType> = ReadWriteRegister::new(0x24);
+    pub const CARDBUS_CIS_PTR: ReadOnlyRegister<u32> = ReadOnlyRegister::new(0x28);
+    pub const SUBSYSTEM_VENDOR_ID: ReadOnlyRegister<u16> = ReadOnlyRegister::new(0x2C);
+    pub const SUBSYSTEM_ID: ReadOnlyRegister<u16> = ReadOnlyRegister::new(0x2E);
+    pub const EXPANSION_ROM_BASE_ADDR: ReadWriteRegister<u32> = ReadWriteRegister::new(0x30);
+    pub const CAPABILITIES_PTR: ReadOnlyRegister<u8> = ReadOnlyRegister::new(0x34);
+    pub const INTERRUPT_LINE: ReadWriteRegister<u8> = ReadWriteRegister::new(0x3C);
+    pub const INTERRUPT_PIN: ReadOnlyRegister<u8> = ReadOnlyRegister::new(0x3D);
+    pub const MIN_GRANT: ReadOnlyRegister<u8> = ReadOnlyRegister::new(0x3E);
+    pub const MAX_LATENCY: ReadOnlyRegister<u8> = ReadOnlyRegister::new(0x3F);
 
     pub fn bar_0(&self) -> BaseAddressRegisterType {
-        unsafe { self.base_addr_register(0x10) }
+        self.mmio.read_register(Self::BAR_0)
     }
 
     pub fn bar_1(&self) -> BaseAddressRegisterType {
-        unsafe { self.base_addr_register(0x14) }
+        self.mmio.read_register(Self::BAR_1)
     }
 
     pub fn bar_2(&self) -> BaseAddressRegisterType {
-        unsafe { self.base_addr_register(0x18) }
+        self.mmio.read_register(Self::BAR_2)
     }
 
     pub fn bar_3(&self) -> BaseAddressRegisterType {
-        unsafe { self.base_addr_register(0x1C) }
+        self.mmio.read_register(Self::BAR_3)
     }
 
     pub fn bar_4(&self) -> BaseAddressRegisterType {
-        unsafe { self.base_addr_register(0x20) }
+        self.mmio.read_register(Self::BAR_4)
     }
 
     pub fn bar_5(&self) -> BaseAddressRegisterType {
-        unsafe { self.base_addr_register(0x24) }
+        self.mmio.read_register(Self::BAR_5)
     }
 
     pub fn cardbus_cis_ptr(&self) -> u32 {
-        unsafe { *self.offset(0x28) }
+        self.mmio.read_register(Self::CARDBUS_CIS_PTR)
     }
 
     pub fn subsystem_vendor_id(&self) -> u16 {
-        unsafe { *self.offset(0x2C) }
+        self.mmio.read_register(Self::SUBSYSTEM_VENDOR_ID)
     }
 
     pub fn subsystem_id(&self) -> u16 {
-        unsafe { *self.offset(0x2E) }
+        self.mmio.read_register(Self::SUBSYSTEM_ID)
     }
 
     pub fn expansion_rom_base_addr(&self) -> u32 {
-        unsafe { *self.offset(0x30) }
+        self.mmio.read_register(Self::EXPANSION_ROM_BASE_ADDR)
     }
 
     pub fn capabilities_ptr(&self) -> u8 {
-        unsafe { *self.offset::<u8>(0x34) & !0b11 }
+        self.mmio.read_register(Self::CAPABILITIES_PTR) & !0b11
     }
 
     pub fn interrupt_line(&self) -> Option<u8> {
-        match unsafe { self.offset(0x3C) } {
+        match self.mmio.read_register(Self::INTERRUPT_LINE) {
             0xFF => None,
-            value => Some(*value),
+            value => Some(value),
         }
     }
 
     pub fn interrupt_pin(&self) -> Option<u8> {
-        match unsafe { self.offset(0x3D) } {
+        match self.mmio.read_register(Self::INTERRUPT_PIN) {
             0x0 => None,
-            value => Some(*value),
+            value => Some(value),
         }
     }
 
     pub fn min_grant(&self) -> u8 {
-        unsafe { *self.offset(0x3E) }
+        self.mmio.read_register(Self::MIN_GRANT)
     }
 
     pub fn max_latency(&self) -> u8 {
-        unsafe { *self.offset(0x3F) }
+        self.mmio.read_register(Self::MAX_LATENCY)
     }
 }
 
-impl fmt::Debug for PCIeDeviceHeader<Standard> {
+impl fmt::Debug for PCIeDeviceHeader<'_, Standard> {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         formatter
             .debug_struct("Extended PCI Device Header")
@@ -211,13 +262,13 @@ impl fmt::Debug for PCIeDeviceHeader<Standard> {
     }
 }
 
-impl fmt::Debug for PCIeDeviceHeader<PCI2PCI> {
+impl fmt::Debug for PCIeDeviceHeader<'_, PCI2PCI> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_tuple("ss").finish()
     }
 }
 
-impl fmt::Debug for PCIeDeviceHeader<PCI2CardBus> {
+impl fmt::Debug for PCIeDeviceHeader<'_, PCI2CardBus> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_tuple("ss").finish()
     }
@@ -232,15 +283,15 @@ impl PCIeDevice {
         Self { mmio }
     }
 
-    pub fn base_header(&self) -> &PCIDeviceHeader {
-        unsafe { self.mmio.read(0).unwrap() }
+    pub fn base_header(&self) -> PCIDeviceHeader {
+        PCIDeviceHeader::new(&self.mmio)
     }
 
     pub fn ext_header(&self) -> PCIeDeviceType {
         match self.base_header().header_type() {
-            0x0 => PCIeDeviceType::Standard(unsafe { self.mmio.read(0).unwrap() }),
-            0x1 => PCIeDeviceType::PCI2PCI(unsafe { self.mmio.read(0).unwrap() }),
-            0x2 => PCIeDeviceType::PCI2CardBus(unsafe { self.mmio.read(0).unwrap() }),
+            0x0 => PCIeDeviceType::Standard(PCIeDeviceHeader::new(&self.mmio)),
+            0x1 => PCIeDeviceType::PCI2PCI(PCIeDeviceHeader::new(&self.mmio)),
+            0x2 => PCIeDeviceType::PCI2CardBus(PCIeDeviceHeader::new(&self.mmio)),
             header_type => panic!("invalid header type: 0x{:X}", header_type),
         }
     }
@@ -254,7 +305,7 @@ impl core::fmt::Debug for PCIeDevice {
     fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         formatter
             .debug_struct("PCIe Bus")
-            .field("Header", self.base_header())
+            .field("Header", &self.base_header())
             .field("Extended Header", &self.ext_header())
             .finish()
     }
@@ -3,6 +3,7 @@
 pub mod express;
 pub mod legacy;
 
+use crate::memory::mmio::{Mapped, ReadOnlyRegister, ReadWriteRegister, RegisterValue, MMIO};
 use bitflags::bitflags;
 use core::fmt;
 
@@ -43,6 +44,30 @@ bitflags! {
     }
 }
 
+impl RegisterValue for PCICommandRegister {
+    type Raw = u16;
+
+    fn from_raw(raw: Self::Raw) -> Self {
+        Self::from_bits_truncate(raw)
+    }
+
+    fn into_raw(self) -> Self::Raw {
+        self.bits()
+    }
+}
+
+impl RegisterValue for PCIStatusRegister {
+    type Raw = u16;
+
+    fn from_raw(raw: Self::Raw) -> Self {
+        Self::from_bits_truncate(raw)
+    }
+
+    fn into_raw(self) -> Self::Raw {
+        self.bits()
+    }
+}
+
 impl PCIStatusRegister {
     pub fn devsel_timing(&self) -> PCIDeviceSELTiming {
         PCIDeviceSELTiming::from_bits_truncate((self.bits() >> 9) & 0b11)
@@ -76,7 +101,43 @@ pub enum PCIDeviceClass {
     Unassigned = 0xFF,
 }
 
+impl RegisterValue for PCIDeviceClass {
+    type Raw = u8;
+
+    fn from_raw(raw: Self::Raw) -> Self {
+        match raw {
+            0x0 => Self::Unclassified,
+            0x1 => Self::MassStorageController,
+            0x2 => Self::NetworkController,
+            0x3 => Self::DisplayController,
+            0x4 => Self::MultimediaController,
+            0x5 => Self::MemoryController,
+            0x6 => Self::Bridge,
+            0x7 => Self::CommunicationController,
+            0x8 => Self::GenericSystemPeripheral,
+            0x9 => Self::InputDeviceController,
+            0xA => Self::DockingStation,
+            0xB => Self::Processor,
+            0xC => Self::SerialBusController,
+            0xD => Self::WirelessController,
+            0xE => Self::IntelligentController,
+            0xF => Self::SatelliteCommunicationsController,
+            0x10 => Self::EncryptionController,
+            0x11 => Self::SignalProcessingController,
+            0x12 => Self::ProcessingAccelerators,
+            0x13 => Self::NonEssentialInstrumentation,
+            0x40 => Self::Coprocessor,
+            _ => Self::Unassigned,
+        }
+    }
+
+    fn into_raw(self) -> Self::Raw {
+        self as u8
+    }
+}
+
 #[repr(transparent)]
+#[derive(Clone, Copy)]
 pub struct PCIBISTRegister {
     data: u8,
 }
@@ -95,6 +156,18 @@ impl PCIBISTRegister {
     }
 }
 
+impl RegisterValue for PCIBISTRegister {
+    type Raw = u8;
+
+    fn from_raw(raw: Self::Raw) -> Self {
+        Self { data: raw }
+    }
+
+    fn into_raw(self) -> Self::Raw {
+        self.data
+    }
+}
+
 impl core::fmt::Debug for PCIBISTRegister {
     fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         formatter
@@ -106,33 +179,39 @@ impl core::fmt::Debug for PCIBISTRegister {
     }
 }
 
-#[repr(C, align(0x100))]
-pub struct PCIDeviceHeader {
-    vendor_id: u16,
-    device_id: u16,
-    command: PCICommandRegister,
-    status: PCIStatusRegister,
-    revision_id: u8,
-    prog_interface: u8,
+/// The header common to every PCI device's configuration space.
+pub struct PCIDeviceHeader<'a> {
+    mmio: &'a MMIO<Mapped>,
+}
+
+impl<'a> PCIDeviceHeader<'a> {
+    pub const VENDOR_ID: ReadOnlyRegister<u16> = ReadOnlyRegister::new(0x0);
+    pub const DEVICE_ID: ReadOnlyRegister<u16> = ReadOnlyRegister::new(0x2);
+    pub const COMMAND: ReadWriteRegister<PCICommandRegister> = ReadWriteRegister::new(0x4);
+    pub const STATUS: ReadWriteRegister<PCIStatusRegister> = ReadWriteRegister::new(0x6);
+    pub const REVISION_ID: ReadOnlyRegister<u8> = ReadOnlyRegister::new(0x8);
+    pub const PROGRAM_INTERFACE: ReadOnlyRegister<u8> = ReadOnlyRegister::new(0x9);
     /// Subclass of the PCI device class.
     ///
     /// An explanation for this value can be found at:
     ///   https://pcisig.com/sites/default/files/files/PCI_Code-ID_r_1_11__v24_Jan_2019.pdf
-    subclass: u8,
-    class: PCIDeviceClass,
-    cache_line_size: u8,
-    latency_timer: u8,
-    header_type: u8,
-    bist: PCIBISTRegister,
-}
+    pub const SUBCLASS: ReadOnlyRegister<u8> = ReadOnlyRegister::new(0xA);
+    pub const CLASS: ReadOnlyRegister<PCIDeviceClass> = ReadOnlyRegister::new(0xB);
+    pub const CACHE_LINE_SIZE: ReadWriteRegister<u8> = ReadWriteRegister::new(0xC);
+    pub const LATENCY_TIMER: ReadWriteRegister<u8> = ReadWriteRegister::new(0xD);
+    pub const HEADER_TYPE: ReadOnlyRegister<u8> = ReadOnlyRegister::new(0xE);
+    pub const BIST: ReadWriteRegister<PCIBISTRegister> = ReadWriteRegister::new(0xF);
+
+    pub fn new(mmio: &'a MMIO<Mapped>) -> Self {
+        Self { mmio }
+    }
 
-impl PCIDeviceHeader {
     pub fn is_valid(&self) -> bool {
         self.vendor_id() != u16::MAX
     }
 
     pub fn vendor_id(&self) -> u16 {
-        self.vendor_id
+        self.mmio.read_register(Self::VENDOR_ID)
     }
 
     pub fn vendor_str(&self) -> &str {
@@ -145,7 +224,7 @@ impl PCIDeviceHeader {
     }
 
     pub fn device_id(&self) -> u16 {
-        self.device_id
+        self.mmio.read_register(Self::DEVICE_ID)
     }
 
     pub fn device_str(&self) -> &str {
@@ -160,51 +239,51 @@ impl PCIDeviceHeader {
     }
 
     pub fn command(&self) -> PCICommandRegister {
-        self.command
+        self.mmio.read_register(Self::COMMAND)
     }
 
     pub fn status(&self) -> PCIStatusRegister {
-        self.status
+        self.mmio.read_register(Self::STATUS)
     }
 
     pub fn revision_id(&self) -> u8 {
-        self.revision_id
+        self.mmio.read_register(Self::REVISION_ID)
     }
 
     pub fn program_interface(&self) -> u8 {
-        self.prog_interface
+        self.mmio.read_register(Self::PROGRAM_INTERFACE)
     }
 
     pub fn subclass(&self) -> u8 {
-        self.subclass
+        self.mmio.read_register(Self::SUBCLASS)
     }
 
     pub fn class(&self) -> PCIDeviceClass {
-        self.class
+        self.mmio.read_register(Self::CLASS)
     }
 
     pub fn cache_line_size(&self) -> u8 {
-        self.cache_line_size
+        self.mmio.read_register(Self::CACHE_LINE_SIZE)
     }
 
     pub fn latency_timer(&self) -> u8 {
-        self.latency_timer
+        self.mmio.read_register(Self::LATENCY_TIMER)
     }
 
     pub fn header_type(&self) -> u8 {
-        self.header_type & 0b111111
+        self.mmio.read_register(Self::HEADER_TYPE) & 0b111111
     }
 
     pub fn multi_function(&self) -> bool {
-        (self.header_type & (1 << 7)) > 0
+        (self.mmio.read_register(Self::HEADER_TYPE) & (1 << 7)) > 0
     }
 
-    pub fn bist(&self) -> &PCIBISTRegister {
-        &self.bist
+    pub fn bist(&self) -> PCIBISTRegister {
+        self.mmio.read_register(Self::BIST)
     }
 }
 
-impl fmt::Debug for PCIDeviceHeader {
+impl fmt::Debug for PCIDeviceHeader<'_> {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         formatter
             .debug_struct("PCIDeviceHeader")
@@ -222,7 +301,7 @@ impl fmt::Debug for PCIDeviceHeader {
             .field("Latency Timer", &self.latency_timer())
             .field("Multi-Function", &self.multi_function())
             .field("Header Type", &self.header_type())
-            .field("BIST", self.bist())
+            .field("BIST", &self.bist())
             .finish()
     }
 }
@@ -1,6 +1,7 @@
 #![no_std]
 #![feature(
     asm,
+    global_asm,
     const_fn,
     once_cell,
     raw_ref_op,
@@ -30,6 +31,7 @@ pub mod elf;
 pub mod instructions;
 pub mod io;
 pub mod memory;
+pub mod percpu;
 pub mod registers;
 pub mod structures;
 pub use addr::*;
@@ -54,6 +56,11 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 #[alloc_error_handler]
 fn alloc_error(error: core::alloc::Layout) -> ! {
     error!("KERNEL ALLOCATOR PANIC: {:?}", error);
+    error!(
+        "  {} allocations failed after reclaim, {} bytes of memory free.",
+        crate::memory::pressure::out_of_memory_count(),
+        crate::memory::falloc::get().total_memory(Some(crate::memory::falloc::FrameState::Free))
+    );
 
     crate::instructions::hlt_indefinite()
 }
@@ -0,0 +1,303 @@
+use crate::{
+    addr_ty::{Physical, Virtual},
+    memory::{
+        falloc, malloc,
+        paging::{self, PageAttributes, PagingError},
+        vma::{self, AreaAttributes, AreaOwner, VMAError},
+        Frame, FrameIterator, Page,
+    },
+    Address,
+};
+use core::marker::PhantomData;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaCaching {
+    /// Every access goes directly to memory (i.e. descriptor rings the device polls).
+    Uncached,
+    /// Writes are combined before reaching memory (i.e. framebuffers), falling back to
+    ///  uncached if write-combining isn't enabled (see `paging::enable_write_combining`).
+    WriteCombined,
+}
+
+impl DmaCaching {
+    fn page_attribs(self) -> PageAttributes {
+        let attribs = PageAttributes::PRESENT | PageAttributes::WRITABLE;
+
+        match self {
+            DmaCaching::WriteCombined if paging::write_combining_enabled() => {
+                attribs | PageAttributes::PAT
+            }
+            _ => attribs | PageAttributes::WRITE_THROUGH | PageAttributes::DISABLE_CACHE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// No run of contiguous frames of the given length is free.
+    OutOfFrames(usize),
+    /// The requested size overflows the address space.
+    TooLarge,
+    VMA(VMAError),
+    Paging(PagingError),
+}
+
+/// A physically contiguous region of memory, mapped into the kernel's address space, whose
+///  physical (bus) address can be handed to a device.
+///
+/// The region is zeroed when allocated, and unmapped and freed when dropped. While it exists,
+///  the physical memory map's alias of its frames takes the region's memory type, as accessing
+///  memory through mappings of differing types is undefined.
+pub struct DmaRegion {
+    frames: FrameIterator,
+    start: Page,
+    caching: DmaCaching,
+}
+
+impl DmaRegion {
+    /// Attributes the physical memory map's pages are mapped with (see
+    ///  `VirtualAddressor::modify_mapped_page`), which an alias is restored to.
+    const ALIAS_ATTRIBS: PageAttributes = PageAttributes::PRESENT.union(PageAttributes::WRITABLE);
+
+    /// Allocates a region of at least `size` bytes.
+    pub fn new(size: usize, caching: DmaCaching) -> Result<Self, DmaError> {
+        Self::new_aligned(size, 1, caching)
+    }
+
+    /// Allocates a region of at least `size` bytes, with its bus address aligned to
+    ///  `frame_alignment` frames.
+    pub fn new_aligned(
+        size: usize,
+        frame_alignment: usize,
+        caching: DmaCaching,
+    ) -> Result<Self, DmaError> {
+        assert!(size > 0, "DMA regions must be nonzero");
+
+        let frame_count = crate::align_up_div(size, 0x1000);
+        let frames = crate::memory::pressure::reclaim(frame_count, || {
+            falloc::get().lock_contiguous(frame_count, frame_alignment)
+        })
+        .ok_or(DmaError::OutOfFrames(frame_count))?;
+
+        let area = match vma::reserve(
+            frame_count,
+            1,
+            "dma",
+            AreaOwner::DMA,
+            AreaAttributes::WRITABLE | AreaAttributes::UNCACHEABLE,
+        ) {
+            Ok(area) => area,
+            Err(vma_error) => {
+                unsafe { falloc::get().free_frames(frames).unwrap() };
+                return Err(DmaError::VMA(vma_error));
+            }
+        };
+
+        let alias = Self::alias_of(frames.start());
+        if let Err(paging_error) =
+            unsafe { malloc::get().set_pages_attribs(&alias, frame_count, caching.page_attribs()) }
+        {
+            vma::release(area.start()).unwrap();
+            unsafe { falloc::get().free_frames(frames).unwrap() };
+            return Err(DmaError::Paging(paging_error));
+        }
+        // Lines cached through the alias while it was write-back mustn't later be written back
+        //  over what the device sees.
+        unsafe { crate::instructions::flush_cache_range(alias.as_ptr(), frame_count * 0x1000) };
+
+        if let Err(paging_error) = unsafe {
+            malloc::get().map_pages(
+                &area.start(),
+                frames.start(),
+                frame_count,
+                caching.page_attribs(),
+            )
+        } {
+            unsafe {
+                malloc::get()
+                    .set_pages_attribs(&alias, frame_count, Self::ALIAS_ATTRIBS)
+                    .unwrap()
+            };
+            vma::release(area.start()).unwrap();
+            unsafe { falloc::get().free_frames(frames).unwrap() };
+            return Err(DmaError::Paging(paging_error));
+        }
+
+        unsafe { core::ptr::write_bytes(area.start().as_mut_ptr::<u8>(), 0, frame_count * 0x1000) };
+
+        trace!(
+            "Allocated DMA region: {:?} -> {:?} ({:?})",
+            area.start(),
+            frames.start(),
+            caching
+        );
+        Ok(Self {
+            frames,
+            start: area.start(),
+            caching,
+        })
+    }
+
+    /// The page aliasing `frame` in the physical memory map.
+    fn alias_of(frame: &Frame) -> Page {
+        Page::containing_addr(unsafe { malloc::get().physical_memory(frame.addr()) })
+    }
+
+    /// Size of the region, in bytes.
+    pub fn len(&self) -> usize {
+        self.frames.len() * 0x1000
+    }
+
+    pub fn caching(&self) -> DmaCaching {
+        self.caching
+    }
+
+    pub fn virt_addr(&self) -> Address<Virtual> {
+        self.start.addr()
+    }
+
+    /// Physical address of the region, as seen by devices.
+    pub fn bus_addr(&self) -> Address<Physical> {
+        self.frames.start().addr()
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.start.as_ptr()
+    }
+
+    pub fn as_mut_ptr<T>(&mut self) -> *mut T {
+        self.start.as_mut_ptr()
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        unsafe {
+            malloc::get()
+                .unmap_pages(&self.start, self.frames.len())
+                .unwrap();
+            vma::release(self.start).unwrap();
+            malloc::get()
+                .set_pages_attribs(
+                    &Self::alias_of(self.frames.start()),
+                    self.frames.len(),
+                    Self::ALIAS_ATTRIBS,
+                )
+                .unwrap();
+
+            self.frames.reset();
+            falloc::get().free_frames(&mut self.frames).unwrap();
+        }
+
+        trace!("Freed DMA region: {:?}", self.start);
+    }
+}
+
+impl core::fmt::Debug for DmaRegion {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        formatter
+            .debug_struct("DmaRegion")
+            .field("Virtual Address", &self.virt_addr())
+            .field("Bus Address", &self.bus_addr())
+            .field("Length", &self.len())
+            .field("Caching", &self.caching())
+            .finish()
+    }
+}
+
+/// A typed array (i.e. of descriptors) within a `DmaRegion`.
+pub struct DmaBuffer<T> {
+    region: DmaRegion,
+    len: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Copy + Default> DmaBuffer<T> {
+    /// Allocates a buffer of `len` elements, each initialized to its default.
+    pub fn new(len: usize, caching: DmaCaching) -> Result<Self, DmaError> {
+        Self::new_aligned(len, 1, caching)
+    }
+
+    /// Allocates a buffer of `len` elements, with its bus address aligned to
+    ///  `frame_alignment` frames.
+    pub fn new_aligned(
+        len: usize,
+        frame_alignment: usize,
+        caching: DmaCaching,
+    ) -> Result<Self, DmaError> {
+        assert!(
+            core::mem::align_of::<T>() <= 0x1000,
+            "DMA buffer elements can't be aligned beyond a frame"
+        );
+
+        let size = len
+            .checked_mul(core::mem::size_of::<T>())
+            .ok_or(DmaError::TooLarge)?;
+        let mut region = DmaRegion::new_aligned(core::cmp::max(size, 1), frame_alignment, caching)?;
+        let ptr = region.as_mut_ptr::<T>();
+        (0..len).for_each(|index| unsafe { ptr.add(index).write(T::default()) });
+
+        Ok(Self {
+            region,
+            len,
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<T> DmaBuffer<T> {
+    /// Number of elements in the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn region(&self) -> &DmaRegion {
+        &self.region
+    }
+
+    /// Physical address of the buffer's first element, as seen by devices.
+    pub fn bus_addr(&self) -> Address<Physical> {
+        self.region.bus_addr()
+    }
+
+    /// Physical address of the element at `index`, as seen by devices.
+    pub fn bus_addr_of(&self, index: usize) -> Address<Physical> {
+        assert!(index < self.len(), "index must be within the buffer");
+
+        self.bus_addr() + (index * core::mem::size_of::<T>())
+    }
+}
+
+impl<T: Copy> DmaBuffer<T> {
+    /// Performs a volatile read of the element at `index`, as the device may modify it.
+    pub fn read(&self, index: usize) -> T {
+        assert!(index < self.len(), "index must be within the buffer");
+
+        unsafe { self.region.as_ptr::<T>().add(index).read_volatile() }
+    }
+
+    pub fn write(&mut self, index: usize, value: T) {
+        assert!(index < self.len(), "index must be within the buffer");
+
+        unsafe {
+            self.region
+                .as_mut_ptr::<T>()
+                .add(index)
+                .write_volatile(value)
+        };
+    }
+}
+
+impl<T> core::ops::Deref for DmaBuffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { core::slice::from_raw_parts(self.region.as_ptr(), self.len) }
+    }
+}
+
+impl<T> core::ops::DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { core::slice::from_raw_parts_mut(self.region.as_mut_ptr(), self.len) }
+    }
+}
@@ -1,10 +1,14 @@
 use crate::{
-    addr_ty::Virtual, cell::SyncOnceCell, memory::Frame, Address, BitValue, RwBitArray,
-    RwBitArrayIterator,
+    cell::SyncOnceCell,
+    memory::{numa, Frame},
+    BitValue, RwBitArray, RwBitArrayIterator,
 };
-use spin::RwLock;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
 
 static DEFAULT_FALLOCATOR: SyncOnceCell<FrameAllocator> = SyncOnceCell::new();
+/// Set by `zeroed_pool_shrinker` under memory pressure, until `replenish_zeroed` sees free
+///  memory has recovered.
+static ZEROING_SUSPENDED: AtomicBool = AtomicBool::new(false);
 
 pub unsafe fn load(ptr: *mut usize, total_memory: usize) {
     if !DEFAULT_FALLOCATOR.get().is_some() {
@@ -22,8 +26,17 @@ pub fn get() -> &'static FrameAllocator<'static> {
         .expect("frame allocator has not been configured")
 }
 
-pub fn virtual_map_offset() -> Address<Virtual> {
-    Address::<Virtual>::new(crate::VADDR_HW_MAX - get().total_memory(None))
+/// Shrinker for the pool of pre-zeroed frames (see `pressure::register_shrinker`).
+///
+/// Zeroed frames are already taken by `lock_next` once no other free frames remain, so the
+///  pool holds no frames back, and this frees none. Instead, it suspends `replenish_zeroed`
+///  until free memory recovers, so idle CPUs stop locking scarce frames to zero them.
+pub fn zeroed_pool_shrinker(_: usize) -> usize {
+    if !ZEROING_SUSPENDED.swap(true, Ordering::AcqRel) {
+        debug!("Memory pressure: suspended zeroing frames.");
+    }
+
+    0
 }
 
 #[repr(usize)]
@@ -34,6 +47,8 @@ pub enum FrameState {
     Reserved,
     NonUsable,
     MMIO,
+    /// Free, and known to be zeroed (see `replenish_zeroed`).
+    Zeroed,
 }
 
 impl crate::BitValue for FrameState {
@@ -47,6 +62,7 @@ impl crate::BitValue for FrameState {
             2 => FrameState::Reserved,
             3 => FrameState::NonUsable,
             4 => FrameState::MMIO,
+            5 => FrameState::Zeroed,
             _ => panic!("invalid value for frame type: {:?}", value),
         }
     }
@@ -56,11 +72,20 @@ impl crate::BitValue for FrameState {
     }
 }
 
+impl FrameState {
+    /// Whether frames in this state are free to be locked.
+    pub const fn is_free(&self) -> bool {
+        matches!(self, FrameState::Free | FrameState::Zeroed)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FrameAllocatorError {
     ExpectedFrameState(usize, FrameState),
     NonMMIOFrameState(usize, FrameState),
     FreeWithAcquire,
+    /// The frame at the given index has the maximum number of references.
+    TooManyReferences(usize),
 }
 
 /// Structure for deterministically reserving, locking, and freeing RAM frames.
@@ -77,12 +102,47 @@ pub enum FrameAllocatorError {
 /// This example encapsulates the core idea, that the `Frame` struct shouldn't be instantiated
 /// out of thin air. Its creation should be carefully controlled, to ensure each individual frame's
 /// lifetime matches up with how it is used or consumed in hardware and software.
+///
+/// Zeroed Frames
+/// -------------
+/// Free frames may be zeroed ahead of time (i.e. while the CPU is idle) by `replenish_zeroed`,
+/// building a pool of up to `ZEROED_POOL_TARGET` frames which `lock_zeroed` takes from, so
+/// callers which require zeroed memory needn't zero it on their hot path. Zeroed frames are
+/// still free, so any other allocation may take them once no plain free frames remain.
+///
+/// Under memory pressure, replenishing is suspended (see `zeroed_pool_shrinker`).
+///
+/// NUMA Nodes
+/// ----------
+/// Once a NUMA topology is loaded (see `numa::init`), free memory is tracked per node, and
+/// `lock_next` prefers frames local to the current processor's node, falling back to the
+/// remaining nodes from nearest to furthest. Each of a node's memory ranges keeps its own
+/// next-fit hint, so searching one node doesn't disturb the search position of another.
+///
+/// Shared Frames
+/// -------------
+/// A locked frame may be referenced more than once (i.e. by several copy-on-write mappings),
+/// with each reference taken by `reference_frame`. Each call to `free_frame` drops a single
+/// reference, and the frame is only freed once its last reference is dropped.
 pub struct FrameAllocator<'arr> {
     memory_map: RwBitArray<'arr, FrameState>,
-    memory: RwLock<[usize; FrameState::MASK + 1]>,
+    /// References to each frame beyond its first (which is implied by it being locked).
+    shares: &'arr [AtomicU16],
+    /// Bytes of memory in each frame state, with the total in the last counter.
+    memory: [AtomicUsize; FrameState::MASK + 1],
+    /// Bytes of free memory on each NUMA node.
+    node_memory: [AtomicUsize; numa::MAX_NODES],
+    /// Next-fit hint (frame index) for each of the NUMA topology's memory ranges.
+    range_hints: [AtomicUsize; numa::MAX_MEMORY_RANGES],
 }
 
 impl<'arr> FrameAllocator<'arr> {
+    /// Number of frames `replenish_zeroed` keeps zeroed ahead of time.
+    pub const ZEROED_POOL_TARGET: usize = 512;
+    /// Number of free frames (not counting zeroed frames) past which `replenish_zeroed`
+    ///  resumes after being suspended under memory pressure.
+    const ZEROING_RESUME_FRAMES: usize = Self::ZEROED_POOL_TARGET * 4;
+
     /// Provides a hint as to the total memory usage (in frames, i.e. 0x1000 aligned)
     ///  a frame allocator will use given a specified total amount of memory.
     pub fn frame_count_hint(total_memory: usize) -> usize {
@@ -95,8 +155,11 @@ impl<'arr> FrameAllocator<'arr> {
         let frame_count = total_memory / 0x1000;
         crate::align_up_div(
             // each index of a RwBitArray is a `usize`, so multiply the index count with the `size_of` a `usize`
-            (frame_count * FrameState::BIT_WIDTH) / 8, /* 8 bits per byte */
-            // divide total RwBitArray memory size by frame size to get total frame count
+            (RwBitArray::<FrameState>::section_length_hint(frame_count)
+                * core::mem::size_of::<usize>())
+                // each frame's share count follows the RwBitArray
+                + (frame_count * core::mem::size_of::<AtomicU16>()),
+            // divide total memory size by frame size to get total frame count
             0x1000,
         )
     }
@@ -113,20 +176,30 @@ impl<'arr> FrameAllocator<'arr> {
             "system memory should be page-aligned"
         );
 
-        let mut memory_counters = [0; FrameState::MASK + 1];
-        memory_counters[FrameState::Free.as_usize()] = total_memory;
-        memory_counters[FrameState::MASK] = total_memory;
+        const EMPTY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let memory_counters = [EMPTY_COUNTER; FrameState::MASK + 1];
+        memory_counters[FrameState::Free.as_usize()].store(total_memory, Ordering::Relaxed);
+        memory_counters[FrameState::MASK].store(total_memory, Ordering::Relaxed);
 
         let total_frames = total_memory / 0x1000;
+        let section_length = RwBitArray::<FrameState>::section_length_hint(total_frames);
+        let shares = &*core::ptr::slice_from_raw_parts(
+            base_ptr.add(section_length) as *const AtomicU16,
+            total_frames,
+        );
+        shares
+            .iter()
+            .for_each(|share_count| share_count.store(0, Ordering::Relaxed));
+
         let this = Self {
             memory_map: RwBitArray::from_slice(
-                &mut *core::ptr::slice_from_raw_parts_mut(
-                    base_ptr,
-                    RwBitArray::<FrameState>::section_length_hint(total_frames),
-                ),
+                &mut *core::ptr::slice_from_raw_parts_mut(base_ptr, section_length),
                 total_frames,
             ),
-            memory: RwLock::new(memory_counters),
+            shares,
+            memory: memory_counters,
+            node_memory: [EMPTY_COUNTER; numa::MAX_NODES],
+            range_hints: [EMPTY_COUNTER; numa::MAX_MEMORY_RANGES],
         };
 
         this.acquire_frames(
@@ -142,14 +215,22 @@ impl<'arr> FrameAllocator<'arr> {
     /* FREE / LOCK / RESERVE / STACK - SINGLE */
 
     /// Attempts to free a specific frame in the allocator.
+    ///
+    /// If the frame is shared, only a single reference to it is dropped.
     pub unsafe fn free_frame(&self, frame: Frame) -> Result<(), FrameAllocatorError> {
-        if self
+        if self.shares[frame.index()]
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |share_count| {
+                share_count.checked_sub(1)
+            })
+            .is_ok()
+        {
+            trace!("Dropped reference to shared frame {}", frame.index());
+            Ok(())
+        } else if self
             .memory_map
             .set_eq(frame.index(), FrameState::Free, FrameState::Locked)
         {
-            let mut mem_write = self.memory.write();
-            mem_write[FrameState::Free.as_usize()] += 0x1000;
-            mem_write[FrameState::Locked.as_usize()] -= 0x1000;
+            self.move_memory(frame.index(), FrameState::Locked, FrameState::Free);
 
             trace!(
                 "Freed frame {}: {:?} -> {:?}",
@@ -172,26 +253,19 @@ impl<'arr> FrameAllocator<'arr> {
         acq_state: FrameState,
     ) -> Result<Frame, FrameAllocatorError> {
         match acq_state {
-            FrameState::Free => Err(FrameAllocatorError::FreeWithAcquire),
+            FrameState::Free | FrameState::Zeroed => Err(FrameAllocatorError::FreeWithAcquire),
             FrameState::MMIO => match self.memory_map.get(index) {
-                cur_state if matches!(cur_state, FrameState::Reserved | FrameState::NonUsable) => {
-                    self.memory_map.set(index, acq_state);
-
-                    let mut mem_write = self.memory.write();
-                    mem_write[cur_state.as_usize()] -= 0x1000;
-                    mem_write[acq_state.as_usize()] += 0x1000;
+                cur_state
+                    if matches!(cur_state, FrameState::Reserved | FrameState::NonUsable)
+                        && self.memory_map.set_eq(index, acq_state, cur_state) =>
+                {
+                    self.move_memory(index, cur_state, acq_state);
 
                     Ok(Frame::from_index(index))
                 }
                 cur_state => Err(FrameAllocatorError::NonMMIOFrameState(index, cur_state)),
             },
-            _ if self.memory_map.set_eq(index, acq_state, FrameState::Free) => {
-                let mut mem_write = self.memory.write();
-                mem_write[FrameState::Free.as_usize()] -= 0x1000;
-                mem_write[acq_state.as_usize()] += 0x1000;
-
-                Ok(Frame::from_index(index))
-            }
+            _ if self.acquire_free(index, acq_state) => Ok(Frame::from_index(index)),
             _ => Err(FrameAllocatorError::ExpectedFrameState(
                 index,
                 FrameState::Free,
@@ -199,6 +273,30 @@ impl<'arr> FrameAllocator<'arr> {
         }
     }
 
+    /// Takes an additional reference to a locked frame, returning its new reference count.
+    pub fn reference_frame(&self, frame: &Frame) -> Result<usize, FrameAllocatorError> {
+        match self.memory_map.get(frame.index()) {
+            FrameState::Locked => self.shares[frame.index()]
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |share_count| {
+                    share_count.checked_add(1)
+                })
+                .map(|share_count| (share_count as usize) + 2)
+                .map_err(|_| FrameAllocatorError::TooManyReferences(frame.index())),
+            _ => Err(FrameAllocatorError::ExpectedFrameState(
+                frame.index(),
+                FrameState::Locked,
+            )),
+        }
+    }
+
+    /// Number of references to the frame, or 0 if it isn't locked.
+    pub fn reference_count(&self, frame: &Frame) -> usize {
+        match self.memory_map.get(frame.index()) {
+            FrameState::Locked => (self.shares[frame.index()].load(Ordering::Acquire) as usize) + 1,
+            _ => 0,
+        }
+    }
+
     /* FREE / LOCK / RESERVE / STACK - ITER */
 
     /// Attempts to free many frames from an iterator.
@@ -235,34 +333,248 @@ impl<'arr> FrameAllocator<'arr> {
         ))
     }
 
+    /// Transitions a free (or zeroed) frame to `acq_state`, returning whether it was free.
+    fn acquire_free(&self, index: usize, acq_state: FrameState) -> bool {
+        [FrameState::Free, FrameState::Zeroed]
+            .iter()
+            .copied()
+            .find(|free_state| self.memory_map.set_eq(index, acq_state, *free_state))
+            .map(|free_state| self.move_memory(index, free_state, acq_state))
+            .is_some()
+    }
+
+    /// Completes locking the frame at `index`, which was just set from `free_state`.
+    fn locked_from(&self, index: usize, free_state: FrameState) -> Frame {
+        debug_assert_eq!(
+            self.memory_map.get(index),
+            FrameState::Locked,
+            "failed to allocate next frame"
+        );
+
+        self.move_memory(index, free_state, FrameState::Locked);
+
+        let frame = unsafe { Frame::from_index(index) };
+        trace!("Locked next {:?} frame: {:?}", free_state, frame);
+        frame
+    }
+
+    /// Locks the next frame in the given free state.
+    fn lock_next_from(&self, free_state: FrameState) -> Option<Frame> {
+        self.memory_map
+            .set_eq_next(FrameState::Locked, free_state)
+            .map(|index| self.locked_from(index, free_state))
+    }
+
+    /// Locks the next frame in any of the given free states (in order of preference), searching
+    ///  the nodes nearest to `node` first, and then any memory outside of the NUMA topology.
+    fn lock_next_near(&self, node: usize, free_states: &[FrameState]) -> Option<Frame> {
+        if let Some(topology) = numa::get() {
+            for near_node in topology.nodes_by_distance(node) {
+                if self.free_memory_on(near_node) == 0 {
+                    continue;
+                }
+
+                for free_state in free_states.iter().copied() {
+                    for (range_index, range) in topology.memory_ranges(near_node) {
+                        let range = range.start.min(self.memory_map.len())
+                            ..range.end.min(self.memory_map.len());
+                        let range_hint = &self.range_hints[range_index];
+
+                        if let Some(index) = self.memory_map.set_eq_next_in(
+                            FrameState::Locked,
+                            free_state,
+                            range,
+                            range_hint.load(Ordering::Relaxed),
+                        ) {
+                            range_hint.store(index, Ordering::Relaxed);
+                            return Some(self.locked_from(index, free_state));
+                        }
+                    }
+                }
+            }
+        }
+
+        free_states
+            .iter()
+            .find_map(|free_state| self.lock_next_from(*free_state))
+    }
+
     /// Attempts to iterate the allocator's frames, and returns the first unallocated frame.
+    ///
+    /// Frames local to the current processor's NUMA node are preferred, and zeroed frames
+    ///  are only taken once no other free frames remain on a node.
     pub fn lock_next(&self) -> Option<Frame> {
-        self.memory_map
-            .set_eq_next(FrameState::Locked, FrameState::Free)
-            .map(|index| {
-                debug_assert_eq!(
-                    self.memory_map.get(index),
-                    FrameState::Locked,
-                    "failed to allocate next frame"
-                );
-
-                let mut mem_write = self.memory.write();
-                mem_write[FrameState::Free.as_usize()] -= 0x1000;
-                mem_write[FrameState::Locked.as_usize()] += 0x1000;
-
-                let frame = unsafe { Frame::from_index(index) };
-                trace!("Locked next free frame: {:?}", frame);
-                frame
-            })
+        self.lock_next_on(numa::current_node())
+    }
+
+    /// Locks the next free frame, preferring frames on the given NUMA node, and then those
+    ///  on its nearest nodes.
+    pub fn lock_next_on(&self, node: usize) -> Option<Frame> {
+        self.lock_next_near(node, &[FrameState::Free, FrameState::Zeroed])
+    }
+
+    /// Locks a frame from the pool of pre-zeroed frames, if it isn't empty, preferring frames
+    ///  local to the current processor's NUMA node.
+    ///
+    /// Callers should fall back to `lock_next` (and zero the frame themselves) otherwise.
+    pub fn lock_zeroed(&self) -> Option<Frame> {
+        self.lock_next_near(numa::current_node(), &[FrameState::Zeroed])
+    }
+
+    /// Zeroes up to `max_count` free frames, adding them to the pool of pre-zeroed frames,
+    ///  until the pool reaches `ZEROED_POOL_TARGET` frames. Returns the number of frames zeroed.
+    ///
+    /// This is intended to be called while the CPU is idle, so it zeroes frames through the
+    ///  default allocator's physical memory mapping.
+    pub fn replenish_zeroed(&self, max_count: usize) -> usize {
+        if ZEROING_SUSPENDED.load(Ordering::Acquire) {
+            if self.memory[FrameState::Free.as_usize()].load(Ordering::Relaxed)
+                < (Self::ZEROING_RESUME_FRAMES * 0x1000)
+            {
+                return 0;
+            }
+
+            ZEROING_SUSPENDED.store(false, Ordering::Release);
+            debug!("Memory pressure relieved: resumed zeroing frames.");
+        }
+
+        let mut zeroed_count = 0;
+
+        while zeroed_count < max_count
+            && self.total_memory(Some(FrameState::Zeroed)) < (Self::ZEROED_POOL_TARGET * 0x1000)
+        {
+            // The frame is locked while it's zeroed, so nothing else can take it.
+            let frame = match self.lock_next_from(FrameState::Free) {
+                Some(frame) => frame,
+                None => break,
+            };
+
+            unsafe {
+                core::ptr::write_bytes(
+                    crate::memory::malloc::get()
+                        .physical_memory(frame.addr())
+                        .as_mut_ptr::<u8>(),
+                    0,
+                    0x1000,
+                )
+            };
+
+            self.memory_map.set(frame.index(), FrameState::Zeroed);
+            self.move_memory(frame.index(), FrameState::Locked, FrameState::Zeroed);
+            zeroed_count += 1;
+        }
+
+        zeroed_count
+    }
+
+    /// Locks `count` physically contiguous free frames, with the first frame's index aligned
+    ///  to `frame_alignment`.
+    ///
+    /// This is a first-fit search from the lowest frame, so it's considerably slower than
+    ///  `lock_next`; it's intended for memory which must be contiguous (i.e. DMA buffers).
+    pub fn lock_contiguous(
+        &self,
+        count: usize,
+        frame_alignment: usize,
+    ) -> Option<crate::memory::FrameIterator> {
+        assert!(count > 0, "contiguous frame count must be nonzero");
+        assert!(
+            frame_alignment.is_power_of_two(),
+            "frame alignment must be a power of two"
+        );
+
+        let mut start_index = 0;
+        while (start_index + count) <= self.memory_map.len() {
+            let end_index = start_index + count;
+
+            // Lock the run frame by frame. If a frame isn't free (or was locked concurrently),
+            //  the frames locked so far are freed again, and the search resumes past it.
+            match (start_index..end_index)
+                .find(|index| !self.acquire_free(*index, FrameState::Locked))
+            {
+                Some(failed_index) => {
+                    (start_index..failed_index).for_each(|index| {
+                        self.memory_map.set(index, FrameState::Free);
+                        self.move_memory(index, FrameState::Locked, FrameState::Free);
+                    });
+                    start_index = crate::align_up(failed_index + 1, frame_alignment);
+                }
+                None => {
+                    trace!("Locked contiguous frames {}..{}", start_index, end_index);
+                    return Some(unsafe {
+                        crate::memory::FrameIterator::new(
+                            Frame::from_index(start_index),
+                            Frame::from_index(end_index),
+                        )
+                    });
+                }
+            }
+        }
+
+        None
     }
 
     /// Total memory of a given type represented by frame allocator. If `None` is
     ///  provided for type, the total of all memory types is returned instead.
+    ///
+    /// Zeroed frames are free, so they're included in the total of `FrameState::Free`.
     pub fn total_memory(&self, of_type: Option<FrameState>) -> usize {
-        let mem_read = self.memory.read();
         match of_type {
-            Some(frame_type) => mem_read[frame_type.as_usize()],
-            None => *mem_read.last().unwrap(),
+            Some(FrameState::Free) => {
+                self.memory[FrameState::Free.as_usize()].load(Ordering::Relaxed)
+                    + self.memory[FrameState::Zeroed.as_usize()].load(Ordering::Relaxed)
+            }
+            Some(frame_type) => self.memory[frame_type.as_usize()].load(Ordering::Relaxed),
+            None => self.memory[FrameState::MASK].load(Ordering::Relaxed),
+        }
+    }
+
+    /// Bytes of free memory on the given NUMA node, or 0 if no topology is loaded.
+    pub fn free_memory_on(&self, node: usize) -> usize {
+        self.node_memory
+            .get(node)
+            .map_or(0, |node_memory| node_memory.load(Ordering::Relaxed))
+    }
+
+    /// Counts the free memory on each of the topology's nodes.
+    pub(crate) fn init_node_memory(&self, topology: &numa::Topology) {
+        for node in 0..topology.node_count() {
+            let free_frames = topology
+                .memory_ranges(node)
+                .flat_map(|(_, range)| {
+                    range.start.min(self.memory_map.len())..range.end.min(self.memory_map.len())
+                })
+                .filter(|index| self.memory_map.get(*index).is_free())
+                .count();
+
+            self.node_memory[node].store(free_frames * 0x1000, Ordering::Relaxed);
+            debug!(
+                "NUMA node {}: {} MB free.",
+                node,
+                crate::memory::to_mibibytes(free_frames * 0x1000)
+            );
+        }
+    }
+
+    /// Moves the memory of the frame at `index` between the counters of two states.
+    fn move_memory(&self, index: usize, from: FrameState, to: FrameState) {
+        self.memory[from.as_usize()].fetch_sub(0x1000, Ordering::Relaxed);
+        self.memory[to.as_usize()].fetch_add(0x1000, Ordering::Relaxed);
+
+        if from.is_free() != to.is_free() {
+            if let Some(node) = numa::get().and_then(|topology| topology.node_of_frame(index)) {
+                if to.is_free() {
+                    self.node_memory[node].fetch_add(0x1000, Ordering::Relaxed);
+                } else {
+                    // A frame freed while the topology was being loaded may not have been
+                    //  counted, so the counter saturates rather than underflowing.
+                    self.node_memory[node]
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |node_memory| {
+                            Some(node_memory.saturating_sub(0x1000))
+                        })
+                        .ok();
+                }
+            }
         }
     }
 
@@ -16,6 +16,15 @@ unsafe impl core::alloc::GlobalAlloc for DefaultAllocatorProxy {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
         crate::memory::malloc::get().dealloc(ptr, layout);
     }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: core::alloc::Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        crate::memory::malloc::get().realloc(ptr, layout, new_size)
+    }
 }
 
 #[global_allocator]
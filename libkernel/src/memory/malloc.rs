@@ -1,16 +1,52 @@
 use crate::{
     addr_ty::{Physical, Virtual},
     cell::SyncRefCell,
+    memory::{
+        paging::{PageAttributes, PagingError},
+        Frame, Page,
+    },
     Address,
 };
 use core::alloc::Layout;
 
 pub trait MemoryAllocator {
+    #[cfg_attr(feature = "alloc_track_caller", track_caller)]
     fn alloc(&self, layout: Layout) -> *mut u8;
     fn alloc_to(&self, frames: &crate::memory::FrameIterator) -> *mut u8;
+    /// Releases an allocation made by `alloc_to` (leaving its frames to the caller).
+    fn dealloc_to(&self, ptr: *mut u8, frames: &crate::memory::FrameIterator);
     fn dealloc(&self, ptr: *mut u8, layout: Layout);
+    /// Resizes the allocation at `ptr` to `new_size` bytes (keeping its alignment), in place
+    ///  where possible. Returns a null pointer, leaving the allocation untouched, if it can't
+    ///  be resized.
+    #[cfg_attr(feature = "alloc_track_caller", track_caller)]
+    fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8;
     fn minimum_alignment(&self) -> usize;
     unsafe fn physical_memory(&self, addr: Address<Physical>) -> Address<Virtual>;
+    /// Maps the page to the frame, within the allocator's address space.
+    unsafe fn map_page(&self, page: &Page, frame: &Frame) -> Result<(), PagingError>;
+    /// Maps the page to the frame, as `map_page`, or returns `None` if the allocator's address
+    ///  space is locked (i.e. within a page fault handler, which mustn't wait on the lock).
+    unsafe fn try_map_page(&self, page: &Page, frame: &Frame) -> Option<Result<(), PagingError>>;
+    /// Maps `count` contiguous pages to contiguous frames with the given attributes, within
+    ///  the allocator's address space.
+    unsafe fn map_pages(
+        &self,
+        page: &Page,
+        frame: &Frame,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError>;
+    /// Unmaps `count` contiguous pages, within the allocator's address space.
+    unsafe fn unmap_pages(&self, page: &Page, count: usize) -> Result<(), PagingError>;
+    /// Replaces the attributes of `count` contiguous mapped pages, within the allocator's
+    ///  address space.
+    unsafe fn set_pages_attribs(
+        &self,
+        page: &Page,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError>;
 }
 
 static DEFAULT_MALLOCATOR: SyncRefCell<&'static dyn MemoryAllocator> = SyncRefCell::new();
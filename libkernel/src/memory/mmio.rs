@@ -1,4 +1,5 @@
 use crate::{addr_ty::Virtual, memory::FrameIterator, Address};
+use core::marker::PhantomData;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MMIOError {
@@ -48,6 +49,19 @@ impl MMIO<Unmapped> {
 }
 
 impl MMIO<Mapped> {
+    /// Unmaps the region, releasing the virtual memory it was mapped to.
+    ///
+    /// SAFETY: No alias of the mapping (see `alias`) may be used afterwards.
+    pub unsafe fn unmap(self) -> MMIO<Unmapped> {
+        crate::dealloc_to!(self.mapped_addr.as_mut_ptr::<u8>(), &self.frames);
+
+        MMIO::<Unmapped> {
+            frames: self.frames,
+            mapped_addr: Address::zero(),
+            phantom: core::marker::PhantomData,
+        }
+    }
+
     fn max_offset(&self) -> usize {
         self.frames.len() * 0x1000
     }
@@ -68,27 +82,89 @@ impl MMIO<Mapped> {
         }
     }
 
+    fn register_overruns<T>(&self, offset: usize) -> bool {
+        (offset + core::mem::size_of::<T>()) > self.max_offset()
+    }
+
     pub unsafe fn write<T>(&mut self, offset: usize, value: T) -> Result<(), MMIOError> {
         match self.mapped_offset_mut::<T>(offset) {
             Ok(ptr) => {
-                ptr.write(value);
+                ptr.write_volatile(value);
                 Ok(())
             }
             Err(mmio_err) => Err(mmio_err),
         }
     }
 
+    /// Reads a reference to the value at the offset.
+    ///
+    /// Reads through the reference aren't volatile, so registers should be accessed with
+    ///  `read_register` instead.
     pub unsafe fn read<T>(&self, offset: usize) -> Result<&T, MMIOError> {
         self.mapped_offset::<T>(offset).map(|ptr| &*ptr)
     }
 
+    /// Reads a mutable reference to the value at the offset.
+    ///
+    /// Accesses through the reference aren't volatile, so registers should be accessed with
+    ///  `read_register` and `write_register` instead.
     pub unsafe fn read_mut<T>(&mut self, offset: usize) -> Result<&mut T, MMIOError> {
         self.mapped_offset_mut::<T>(offset).map(|ptr| &mut *ptr)
     }
 
+    /// Performs a volatile read of the register.
+    pub fn read_register<R: ReadableRegister>(&self, register: R) -> R::Value {
+        let offset = register.offset();
+        if self.register_overruns::<<R::Value as RegisterValue>::Raw>(offset) {
+            panic!("register at offset {:#X} overruns MMIO region", offset);
+        }
+
+        R::Value::from_raw(unsafe {
+            self.mapped_offset::<<R::Value as RegisterValue>::Raw>(offset)
+                .unwrap()
+                .read_volatile()
+        })
+    }
+
+    /// Performs a volatile write of the register.
+    pub fn write_register<R: WritableRegister>(&mut self, register: R, value: R::Value) {
+        let offset = register.offset();
+        if self.register_overruns::<<R::Value as RegisterValue>::Raw>(offset) {
+            panic!("register at offset {:#X} overruns MMIO region", offset);
+        }
+
+        unsafe {
+            self.mapped_offset_mut::<<R::Value as RegisterValue>::Raw>(offset)
+                .unwrap()
+                .write_volatile(value.into_raw())
+        };
+    }
+
+    /// Reads the register, then writes the value returned by `func` back to it.
+    pub fn update_register<T: RegisterValue, F: FnOnce(T) -> T>(
+        &mut self,
+        register: ReadWriteRegister<T>,
+        func: F,
+    ) {
+        let value = self.read_register(register);
+        self.write_register(register, func(value));
+    }
+
     pub fn mapped_addr(&self) -> Address<Virtual> {
         self.mapped_addr
     }
+
+    /// Creates another handle to the same mapping, i.e. for registers which each CPU sees its
+    ///  own instance of (like the local APIC's).
+    ///
+    /// SAFETY: Accesses through each handle mustn't conflict.
+    pub unsafe fn alias(&self) -> Self {
+        Self {
+            frames: FrameIterator::new(*self.frames.start(), *self.frames.end()),
+            mapped_addr: self.mapped_addr,
+            phantom: core::marker::PhantomData,
+        }
+    }
 }
 
 pub fn unmapped_mmio(frames: FrameIterator) -> Result<MMIO<Unmapped>, MMIOError> {
@@ -98,3 +174,129 @@ pub fn unmapped_mmio(frames: FrameIterator) -> Result<MMIO<Unmapped>, MMIOError>
         phantom: core::marker::PhantomData,
     })
 }
+
+/* TYPED REGISTERS */
+
+/// A value which can be read from or written to a register: a primitive integer, or a type
+///  represented by one (i.e. a bitfield type).
+pub trait RegisterValue: Copy {
+    type Raw: Copy;
+
+    fn from_raw(raw: Self::Raw) -> Self;
+    fn into_raw(self) -> Self::Raw;
+}
+
+macro_rules! primitive_register_value {
+    ($($ty:ty),*) => {
+        $(
+            impl RegisterValue for $ty {
+                type Raw = Self;
+
+                fn from_raw(raw: Self::Raw) -> Self {
+                    raw
+                }
+
+                fn into_raw(self) -> Self::Raw {
+                    self
+                }
+            }
+        )*
+    };
+}
+
+primitive_register_value!(u8, u16, u32, u64);
+
+pub trait ReadableRegister: Copy {
+    type Value: RegisterValue;
+
+    fn offset(&self) -> usize;
+}
+
+pub trait WritableRegister: Copy {
+    type Value: RegisterValue;
+
+    fn offset(&self) -> usize;
+}
+
+/* READ ONLY REGISTER */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOnlyRegister<T: RegisterValue> {
+    offset: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T: RegisterValue> ReadOnlyRegister<T> {
+    /// Constructs a register at the given offset within an MMIO region.
+    pub const fn new(offset: usize) -> Self {
+        Self {
+            offset,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: RegisterValue> ReadableRegister for ReadOnlyRegister<T> {
+    type Value = T;
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/* WRITE ONLY REGISTER */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOnlyRegister<T: RegisterValue> {
+    offset: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T: RegisterValue> WriteOnlyRegister<T> {
+    /// Constructs a register at the given offset within an MMIO region.
+    pub const fn new(offset: usize) -> Self {
+        Self {
+            offset,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: RegisterValue> WritableRegister for WriteOnlyRegister<T> {
+    type Value = T;
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/* READ/WRITE REGISTER */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadWriteRegister<T: RegisterValue> {
+    offset: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T: RegisterValue> ReadWriteRegister<T> {
+    /// Constructs a register at the given offset within an MMIO region.
+    pub const fn new(offset: usize) -> Self {
+        Self {
+            offset,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: RegisterValue> ReadableRegister for ReadWriteRegister<T> {
+    type Value = T;
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<T: RegisterValue> WritableRegister for ReadWriteRegister<T> {
+    type Value = T;
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+}
@@ -8,10 +8,14 @@ mod galloc;
 pub use frame::*;
 pub use page::*;
 pub use uefi::*;
+pub mod dma;
 pub mod falloc;
 pub mod malloc;
 pub mod mmio;
+pub mod numa;
 pub mod paging;
+pub mod pressure;
+pub mod vma;
 
 pub const KIBIBYTE: usize = 0x400; // 1024
 pub const MIBIBYTE: usize = KIBIBYTE * KIBIBYTE;
@@ -41,3 +45,10 @@ macro_rules! alloc_to {
         $crate::memory::malloc::get().alloc_to($frames) as *mut _
     };
 }
+
+#[macro_export]
+macro_rules! dealloc_to {
+    ($ptr:expr, $frames:expr) => {
+        $crate::memory::malloc::get().dealloc_to($ptr as *mut u8, $frames)
+    };
+}
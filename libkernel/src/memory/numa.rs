@@ -0,0 +1,303 @@
+//! NUMA topology, as described by the ACPI SRAT and SLIT.
+//!
+//! Proximity domains are mapped to dense node indices (in the order the SRAT first mentions
+//! them), which the frame allocator uses to prefer memory local to the current processor.
+//! Without an SRAT, no topology is loaded, and the system is treated as a single node.
+
+use crate::{
+    cell::SyncOnceCell,
+    memory::falloc,
+    structures::acpi::{
+        xsdt, AffinityEntry, MemoryAffinityFlags, ProcessorAffinityFlags, SLIT, SRAT,
+    },
+};
+use core::ops::Range;
+
+/// Maximum number of nodes (proximity domains) the topology can represent.
+pub const MAX_NODES: usize = 8;
+/// Maximum number of memory ranges (across all nodes) the topology can represent.
+pub const MAX_MEMORY_RANGES: usize = 32;
+/// Maximum number of processors the topology can represent.
+const MAX_PROCESSORS: usize = 256;
+
+/// Distance from a node to itself, as normalized by the SLIT.
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Distance assumed between distinct nodes when the SLIT doesn't describe them.
+pub const REMOTE_DISTANCE: u8 = 20;
+
+static TOPOLOGY: SyncOnceCell<Topology> = SyncOnceCell::new();
+
+#[derive(Debug, Clone, Copy)]
+struct MemoryRange {
+    node: usize,
+    start_frame: usize,
+    end_frame: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Processor {
+    apic_id: u32,
+    node: usize,
+}
+
+pub struct Topology {
+    /// Proximity domain of each node.
+    domains: [u32; MAX_NODES],
+    node_count: usize,
+    memory_ranges: [Option<MemoryRange>; MAX_MEMORY_RANGES],
+    processors: [Option<Processor>; MAX_PROCESSORS],
+    distances: [[u8; MAX_NODES]; MAX_NODES],
+}
+
+impl Topology {
+    fn from_acpi(srat: &xsdt::XSDTEntry<SRAT>, slit: Option<&xsdt::XSDTEntry<SLIT>>) -> Self {
+        let mut topology = Self {
+            domains: [0; MAX_NODES],
+            node_count: 0,
+            memory_ranges: [None; MAX_MEMORY_RANGES],
+            processors: [None; MAX_PROCESSORS],
+            distances: [[REMOTE_DISTANCE; MAX_NODES]; MAX_NODES],
+        };
+
+        for entry in srat.iter() {
+            trace!("{:?}", entry);
+
+            match entry {
+                AffinityEntry::LocalAPIC(affinity)
+                    if affinity.flags().contains(ProcessorAffinityFlags::ENABLED) =>
+                {
+                    topology.add_processor(affinity.apic_id() as u32, affinity.proximity_domain())
+                }
+                AffinityEntry::X2APIC(affinity)
+                    if affinity.flags().contains(ProcessorAffinityFlags::ENABLED) =>
+                {
+                    topology.add_processor(affinity.x2apic_id(), affinity.proximity_domain())
+                }
+                AffinityEntry::Memory(affinity)
+                    if affinity.flags().contains(MemoryAffinityFlags::ENABLED)
+                        && affinity.len() > 0 =>
+                {
+                    topology.add_memory_range(
+                        affinity.base_addr().frame_index(),
+                        crate::align_up_div(
+                            affinity.base_addr().as_usize() + affinity.len(),
+                            0x1000,
+                        ),
+                        affinity.proximity_domain(),
+                    )
+                }
+                _ => {}
+            }
+        }
+
+        for node in 0..topology.node_count {
+            topology.distances[node][node] = LOCAL_DISTANCE;
+        }
+
+        if let Some(slit) = slit {
+            let locality_count = slit.locality_count();
+
+            for from in 0..topology.node_count {
+                for to in 0..topology.node_count {
+                    let (from_domain, to_domain) = (
+                        topology.domains[from] as usize,
+                        topology.domains[to] as usize,
+                    );
+
+                    if from_domain < locality_count && to_domain < locality_count {
+                        topology.distances[from][to] = slit.distance(from_domain, to_domain);
+                    }
+                }
+            }
+        }
+
+        topology
+    }
+
+    /// Node index of the given proximity domain, adding it as a new node if it isn't known.
+    fn node_of_domain(&mut self, domain: u32) -> Option<usize> {
+        match self.domains[..self.node_count]
+            .iter()
+            .position(|node_domain| *node_domain == domain)
+        {
+            Some(node) => Some(node),
+            None if self.node_count < MAX_NODES => {
+                self.domains[self.node_count] = domain;
+                self.node_count += 1;
+
+                Some(self.node_count - 1)
+            }
+            None => {
+                warn!("Too many NUMA nodes; ignoring proximity domain {}.", domain);
+                None
+            }
+        }
+    }
+
+    fn add_processor(&mut self, apic_id: u32, domain: u32) {
+        if let Some(node) = self.node_of_domain(domain) {
+            match self.processors.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => *slot = Some(Processor { apic_id, node }),
+                None => warn!(
+                    "Too many processors; ignoring affinity of APIC {}.",
+                    apic_id
+                ),
+            }
+        }
+    }
+
+    fn add_memory_range(&mut self, start_frame: usize, end_frame: usize, domain: u32) {
+        if let Some(node) = self.node_of_domain(domain) {
+            match self.memory_ranges.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => {
+                    *slot = Some(MemoryRange {
+                        node,
+                        start_frame,
+                        end_frame,
+                    })
+                }
+                None => warn!(
+                    "Too many memory ranges; ignoring affinity of frames {}..{}.",
+                    start_frame, end_frame
+                ),
+            }
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    /// Proximity domain (as described by ACPI) of the given node.
+    pub fn proximity_domain(&self, node: usize) -> u32 {
+        assert!(node < self.node_count(), "node must be within the topology");
+
+        self.domains[node]
+    }
+
+    /// Relative distance (memory latency) from one node to another.
+    pub fn distance(&self, from: usize, to: usize) -> u8 {
+        assert!(
+            from < self.node_count() && to < self.node_count(),
+            "node must be within the topology"
+        );
+
+        self.distances[from][to]
+    }
+
+    /// Every node, ordered from nearest to furthest from `from` (which is always first).
+    pub fn nodes_by_distance(&self, from: usize) -> impl Iterator<Item = usize> + '_ {
+        let mut visited = [false; MAX_NODES];
+
+        (0..self.node_count).map(move |_| {
+            let node = (0..self.node_count)
+                .filter(|node| !visited[*node])
+                .min_by_key(|node| (self.distance(from, *node), *node != from, *node))
+                .unwrap();
+            visited[node] = true;
+
+            node
+        })
+    }
+
+    /// Frame ranges belonging to the given node, each with its index among all of the
+    ///  topology's memory ranges.
+    pub fn memory_ranges(&self, node: usize) -> impl Iterator<Item = (usize, Range<usize>)> + '_ {
+        self.memory_ranges
+            .iter()
+            .enumerate()
+            .filter_map(move |(range_index, range)| match range {
+                Some(range) if range.node == node => {
+                    Some((range_index, range.start_frame..range.end_frame))
+                }
+                _ => None,
+            })
+    }
+
+    /// Node the frame at the given index belongs to, if any memory range covers it.
+    pub fn node_of_frame(&self, frame_index: usize) -> Option<usize> {
+        self.memory_ranges
+            .iter()
+            .flatten()
+            .find(|range| (range.start_frame..range.end_frame).contains(&frame_index))
+            .map(|range| range.node)
+    }
+
+    /// Node the processor with the given (x2)APIC ID belongs to.
+    pub fn node_of_processor(&self, apic_id: u32) -> Option<usize> {
+        self.processors
+            .iter()
+            .flatten()
+            .find(|processor| processor.apic_id == apic_id)
+            .map(|processor| processor.node)
+    }
+}
+
+impl core::fmt::Debug for Topology {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        formatter
+            .debug_struct("NUMA Topology")
+            .field("Proximity Domains", &&self.domains[..self.node_count])
+            .field(
+                "Memory Ranges",
+                &self.memory_ranges.iter().flatten().count(),
+            )
+            .field("Processors", &self.processors.iter().flatten().count())
+            .finish()
+    }
+}
+
+/// Loads the NUMA topology from the ACPI SRAT (and SLIT, if present), and counts each node's
+///  free memory in the frame allocator. Returns whether a topology was loaded.
+pub fn init() -> bool {
+    let srat = match xsdt::get_entry::<SRAT>() {
+        Ok(srat) => srat,
+        Err(error) => {
+            debug!("No SRAT found ({:?}); assuming a single NUMA node.", error);
+            return false;
+        }
+    };
+
+    let topology = Topology::from_acpi(srat, xsdt::get_entry::<SLIT>().ok());
+    if topology.node_count() == 0 {
+        debug!("SRAT describes no NUMA nodes; assuming a single NUMA node.");
+        return false;
+    }
+
+    debug!("{:?}", topology);
+    // The per-node counters are filled before the topology is published, as the frame
+    //  allocator only updates them once it's published.
+    falloc::get().init_node_memory(&topology);
+    if TOPOLOGY.set(topology).is_err() {
+        panic!("NUMA topology has already been loaded")
+    }
+
+    true
+}
+
+/// The NUMA topology, if one has been loaded.
+pub fn get() -> Option<&'static Topology> {
+    TOPOLOGY.get()
+}
+
+/// Node of the current processor, or node 0 if no topology is loaded (or it doesn't describe
+///  the current processor).
+///
+/// The node is cached in the processor's per-CPU data, once it has any.
+pub fn current_node() -> usize {
+    let topology = match get() {
+        Some(topology) => topology,
+        None => return 0,
+    };
+
+    match crate::percpu::try_get() {
+        Some(percpu) => percpu.numa_node().unwrap_or_else(|| {
+            let node = topology.node_of_processor(percpu.apic_id()).unwrap_or(0);
+            percpu.set_numa_node(node);
+            node
+        }),
+        None => topology
+            .node_of_processor(crate::instructions::apic_id())
+            .unwrap_or(0),
+    }
+}
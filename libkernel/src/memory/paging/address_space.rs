@@ -0,0 +1,258 @@
+use crate::{
+    addr_ty::Virtual,
+    instructions::{cpu_features, tlb, CPUFeatures},
+    memory::{
+        paging::{PageAttributes, PagingError, VirtualAddressor, KERNEL_HALF_ROOT_INDEX},
+        Page,
+    },
+    registers::{CR4Flags, CR3, CR4},
+    Address,
+};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/// Number of process-context identifiers (identifier 0 is used by the kernel's address space).
+const PCID_COUNT: usize = 0x1000;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Number of address spaces using each process-context identifier.
+///
+/// Identifiers are only shared once every one is in use, and an address space whose identifier
+///  is shared flushes it whenever it's switched to.
+const NO_OWNERS: AtomicU16 = AtomicU16::new(0);
+static PCID_OWNERS: [AtomicU16; PCID_COUNT] = [NO_OWNERS; PCID_COUNT];
+/// Next identifier to share, once every one is in use.
+static NEXT_SHARED_PCID: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocates an unused process-context identifier, or shares one (round-robin) if every one is
+///  in use. The kernel's identifier is never handed out.
+fn alloc_pcid() -> u16 {
+    let pcid = (1..PCID_COUNT)
+        .find(|pcid| {
+            PCID_OWNERS[*pcid]
+                .compare_exchange(0, 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })
+        .unwrap_or_else(|| {
+            let pcid = 1 + (NEXT_SHARED_PCID.fetch_add(1, Ordering::Relaxed) % (PCID_COUNT - 1));
+            PCID_OWNERS[pcid].fetch_add(1, Ordering::AcqRel);
+            trace!("Process-context identifiers exhausted; sharing {}.", pcid);
+
+            pcid
+        });
+
+    pcid as u16
+}
+
+fn free_pcid(pcid: u16) {
+    PCID_OWNERS[pcid as usize].fetch_sub(1, Ordering::AcqRel);
+    // Any CPU may still hold entries tagged with the identifier, which its next owner
+    //  mustn't see.
+    tlb::note_contexts_stale();
+}
+
+fn is_pcid_shared(pcid: u16) -> bool {
+    PCID_OWNERS[pcid as usize].load(Ordering::Acquire) > 1
+}
+
+/// Enables process-context identifiers if the CPU supports them, returning whether they were enabled.
+///
+/// Safety: CR3 must not currently be tagged with a process-context identifier.
+pub unsafe fn enable_pcid() -> bool {
+    if cpu_features().contains(CPUFeatures::PCIDE) {
+        CR4::write(CR4::read() | CR4Flags::PCIDE);
+        PCID_ENABLED.store(true, Ordering::Release);
+
+        debug!("Enabled process-context identifiers.");
+        true
+    } else {
+        false
+    }
+}
+
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Acquire)
+}
+
+/// Whether the root table entry at the given index is shared with the kernel's address space.
+///
+/// This includes the kernel half, and the lower-half entries covering identity-mapped memory.
+pub fn is_kernel_root_entry(index: usize) -> bool {
+    // bytes of the address space covered by each PML5 or PML4 entry
+    let root_entry_size = if super::five_level_paging() {
+        1 << 48
+    } else {
+        1 << 39
+    };
+    let identity_entries = crate::align_up_div(
+        crate::memory::falloc::get().total_memory(None),
+        root_entry_size,
+    );
+
+    index < identity_entries || index >= KERNEL_HALF_ROOT_INDEX
+}
+
+/// Switches to the kernel's address space.
+///
+/// Safety: `kernel` must be the kernel's virtual addressor.
+pub unsafe fn switch_to_kernel(kernel: &VirtualAddressor) {
+    if let Some(percpu) = crate::percpu::try_get() {
+        percpu.set_address_space(core::ptr::null());
+    }
+
+    if pcid_enabled() {
+        tlb::invalidate_stale_contexts();
+        CR3::write_pcid(kernel.root_frame(), 0, true);
+    } else {
+        kernel.swap_into();
+    }
+}
+
+/// Attempts to resolve a page fault at `addr` by copying its page, if it's copy-on-write
+///  within the active address space.
+///
+/// Returns `false` (i.e. the fault is genuine) if the fault wasn't caused by a write to a
+///  present page, or the page isn't copy-on-write or accessible from the faulting mode.
+///
+/// This takes the active address space's addressor lock, so copy-on-write pages mustn't be
+///  written while it's held.
+pub fn copy_on_write_fault(addr: Address<Virtual>, error_code: PageFaultErrorCode) -> bool {
+    if !error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+    {
+        return false;
+    }
+
+    // The kernel's address space has no copy-on-write pages.
+    let address_space = match crate::percpu::try_get()
+        .and_then(|percpu| unsafe { percpu.address_space().as_ref() })
+    {
+        Some(address_space) => address_space,
+        None => return false,
+    };
+
+    let page = Page::containing_addr(addr);
+    let result = {
+        let mut addressor = address_space.addressor();
+
+        match addressor.page_attribs(&page) {
+            Some(attribs)
+                if attribs.contains(PageAttributes::COPY_ON_WRITE)
+                    && (!error_code.contains(PageFaultErrorCode::USER_MODE)
+                        || attribs.contains(PageAttributes::USER_ACCESSIBLE)) => {}
+            _ => return false,
+        }
+
+        addressor.resolve_copy_on_write(&page)
+    };
+    tlb::flush_shootdowns();
+
+    match result {
+        Ok(resolved) => resolved,
+        Err(paging_error) => {
+            error!("Failed to copy {:?} on write: {:?}", page, paging_error);
+            false
+        }
+    }
+}
+
+/// An address space which shares the kernel's half of its page tables with every
+///  other address space.
+pub struct AddressSpace {
+    /// Locked by every modification of the address space's page tables, including by the page
+    ///  fault handler (see `copy_on_write_fault`).
+    addressor: Mutex<VirtualAddressor>,
+    pcid: Option<u16>,
+}
+
+impl AddressSpace {
+    /// Creates a new address space, sharing the kernel entries of `kernel`'s root table.
+    ///
+    /// Kernel-half tables must already exist (see `VirtualAddressor::populate_kernel_half`).
+    pub fn new(kernel: &VirtualAddressor) -> Result<Self, PagingError> {
+        let mut addressor = unsafe { VirtualAddressor::try_new(kernel.mapped_page())? };
+        addressor.share_root_entries(kernel, is_kernel_root_entry);
+
+        Ok(Self {
+            addressor: Mutex::new(addressor),
+            pcid: if pcid_enabled() {
+                Some(alloc_pcid())
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Creates a copy of the address space, with every page it doesn't share with the kernel
+    ///  shared copy-on-write between both.
+    pub fn clone_copy_on_write(&self, kernel: &VirtualAddressor) -> Result<Self, PagingError> {
+        let mut clone = Self::new(kernel)?;
+
+        let result = self
+            .addressor()
+            .share_copy_on_write(clone.addressor.get_mut(), |index| {
+                !is_kernel_root_entry(index)
+            });
+        // Other CPUs may be running this address space, with writable TLB entries.
+        tlb::flush_shootdowns();
+
+        match result {
+            Ok(()) => Ok(clone),
+            Err(paging_error) => {
+                unsafe { clone.destroy() };
+                Err(paging_error)
+            }
+        }
+    }
+
+    /// The address space's virtual addressor, locked.
+    ///
+    /// Mappings made through it must be outside the kernel's root table entries (see
+    ///  `is_kernel_root_entry`), or they'll be visible to every address space.
+    pub fn addressor(&self) -> MutexGuard<VirtualAddressor> {
+        self.addressor.lock()
+    }
+
+    pub fn pcid(&self) -> Option<u16> {
+        self.pcid
+    }
+
+    /// Switches to this address space.
+    ///
+    /// If it has a process-context identifier, the TLB is only flushed when it may hold
+    ///  stale entries, or the identifier is shared with another address space.
+    ///
+    /// Safety: the address space mustn't be moved or destroyed while it's active on any CPU.
+    pub unsafe fn switch_to(&self) {
+        if let Some(percpu) = crate::percpu::try_get() {
+            percpu.set_address_space(self);
+        }
+
+        let addressor = self.addressor();
+        match self.pcid {
+            Some(pcid) => {
+                tlb::invalidate_stale_contexts();
+                CR3::write_pcid(addressor.root_frame(), pcid, !is_pcid_shared(pcid));
+            }
+            None => addressor.swap_into(),
+        }
+    }
+
+    /// Destroys the address space, freeing every page table it doesn't share with the kernel.
+    ///
+    /// Frames mapped by the address space's pages aren't freed; they're owned by whoever mapped
+    ///  them. Pages mapped copy-on-write have their reference to the frame dropped.
+    ///
+    /// Safety: the address space must not be active.
+    pub unsafe fn destroy(mut self) {
+        self.addressor
+            .get_mut()
+            .free_tables(|index| !is_kernel_root_entry(index));
+
+        if let Some(pcid) = self.pcid {
+            free_pcid(pcid);
+        }
+    }
+}
@@ -1,7 +1,82 @@
+mod address_space;
 mod page_table;
 mod page_table_entry;
 mod virtual_addressor;
 
+pub use address_space::*;
 pub use page_table::*;
 pub use page_table_entry::*;
 pub use virtual_addressor::*;
+
+/// Number of levels of the active paging hierarchy, or 0 until `five_level_paging` reads it.
+static PAGING_DEPTH: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
+
+/// Whether 5-level paging (LA57) is active, in which case the root page table is a PML5
+///  rather than a PML4.
+///
+/// The depth is chosen by the bootloader (which enables 5-level paging if the CPU supports
+///  it), and can't change while in long mode, so CR4 is only read the first time this is
+///  called, and cached in `PAGING_DEPTH` (as every virtual address constructed checks it).
+pub fn five_level_paging() -> bool {
+    use core::sync::atomic::Ordering;
+
+    match PAGING_DEPTH.load(Ordering::Relaxed) {
+        0 => {
+            let five_level =
+                crate::registers::CR4::read().contains(crate::registers::CR4Flags::LA57);
+            PAGING_DEPTH.store(if five_level { 5 } else { 4 }, Ordering::Relaxed);
+
+            five_level
+        }
+        depth => depth == 5,
+    }
+}
+
+static WRITE_COMBINING: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Memory type of the page attribute table entry selected by `PageAttributes::PAT` alone.
+const PAT_WRITE_COMBINING_ENTRY: usize = 4;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+
+/// Programs the page attribute table so 4KiB pages mapped with `PageAttributes::PAT` (and
+///  neither `WRITE_THROUGH` nor `DISABLE_CACHE`) are write-combining, returning whether the
+///  CPU supports it.
+///
+/// Safety: no pages may be mapped with `PageAttributes::PAT` when this is called, and every
+///  CPU must be programmed the same way.
+pub unsafe fn enable_write_combining() -> bool {
+    use crate::instructions::{cpu_features, CPUFeatures};
+
+    if cpu_features().contains(CPUFeatures::PAT) {
+        let entry_shift = PAT_WRITE_COMBINING_ENTRY * 8;
+        let pat = crate::registers::MSR::IA32_PAT.read();
+        crate::registers::MSR::IA32_PAT
+            .write((pat & !(0xFF << entry_shift)) | (PAT_WRITE_COMBINING << entry_shift));
+        // Lines cached, and translations made, under the old memory type mustn't outlive it.
+        crate::instructions::wbinvd();
+        crate::instructions::tlb::invalidate_all_contexts();
+        WRITE_COMBINING.store(true, core::sync::atomic::Ordering::Release);
+
+        true
+    } else {
+        false
+    }
+}
+
+pub fn write_combining_enabled() -> bool {
+    WRITE_COMBINING.load(core::sync::atomic::Ordering::Acquire)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// The page isn't mapped.
+    NotMapped,
+    /// No frame could be allocated (for a new page table, or a copy of a shared page).
+    OutOfFrames,
+    /// A huge page already maps the region a table walk must pass through.
+    HugePageConflict,
+    /// The frame already has the maximum number of references, so can't be shared again.
+    TooManyReferences,
+}
@@ -1,13 +1,19 @@
-use crate::{addr_ty::Virtual, memory::paging::PageTableEntry, Address};
+use crate::{
+    addr_ty::Virtual,
+    memory::paging::{PageAttributes, PageTableEntry, PagingError},
+    Address,
+};
 use core::marker::PhantomData;
 
 pub trait TableLevel {}
 
+pub enum Level5 {}
 pub enum Level4 {}
 pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
+impl TableLevel for Level5 {}
 impl TableLevel for Level4 {}
 impl TableLevel for Level3 {}
 impl TableLevel for Level2 {}
@@ -16,6 +22,9 @@ impl TableLevel for Level1 {}
 pub trait HeirarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
 }
+impl HeirarchicalLevel for Level5 {
+    type NextLevel = Level4;
+}
 impl HeirarchicalLevel for Level4 {
     type NextLevel = Level3;
 }
@@ -82,37 +91,59 @@ impl<L: HeirarchicalLevel> PageTable<L> {
             .map(|frame| &mut *(phys_mapped_addr + frame.addr().as_usize()).as_mut_ptr())
     }
 
+    /// Mutable sub table at the given index, failing if the entry isn't present or
+    ///  maps a huge page (rather than a sub table).
+    pub unsafe fn sub_table_try_mut(
+        &mut self,
+        index: usize,
+        phys_mapped_addr: Address<Virtual>,
+    ) -> Result<&mut PageTable<L::NextLevel>, PagingError> {
+        if self.get_entry(index).is_huge() {
+            Err(PagingError::HugePageConflict)
+        } else {
+            self.sub_table_mut(index, phys_mapped_addr)
+                .ok_or(PagingError::NotMapped)
+        }
+    }
+
     pub unsafe fn sub_table_create(
         &mut self,
         index: usize,
         phys_mapped_addr: Address<Virtual>,
-    ) -> &mut PageTable<L::NextLevel> {
+    ) -> Result<&mut PageTable<L::NextLevel>, PagingError> {
         let entry = self.get_entry_mut(index);
-        let (frame, created) = match entry.frame() {
+        let (frame, requires_clear) = match entry.frame() {
+            Some(_) if entry.is_huge() => return Err(PagingError::HugePageConflict),
             Some(frame) => (frame, false),
             None => {
-                let alloc_frame = crate::memory::falloc::get()
-                    .lock_next()
-                    .expect("failed to allocate a frame for new page table");
+                let falloc = crate::memory::falloc::get();
+                let (alloc_frame, zeroed) = match falloc.lock_zeroed() {
+                    Some(frame) => (frame, true),
+                    None => (
+                        crate::memory::pressure::lock_next_with_reclaim()
+                            .ok_or(PagingError::OutOfFrames)?,
+                        false,
+                    ),
+                };
                 trace!("Allocated frame for nonpresent entry: {:?}", alloc_frame);
 
                 entry.set(
                     &alloc_frame,
-                    crate::memory::paging::PageAttributes::PRESENT
-                        | crate::memory::paging::PageAttributes::WRITABLE,
+                    PageAttributes::PRESENT | PageAttributes::WRITABLE,
                 );
 
-                (alloc_frame, true)
+                (alloc_frame, !zeroed)
             }
         };
 
         let sub_table: &mut PageTable<L::NextLevel> =
             &mut *(phys_mapped_addr + frame.addr().as_usize()).as_mut_ptr();
 
-        if created {
+        // Tables taken from the pre-zeroed pool are already clear.
+        if requires_clear {
             sub_table.clear();
         }
 
-        sub_table
+        Ok(sub_table)
     }
 }
@@ -11,8 +11,14 @@ bitflags! {
         const ACCESSED = 1 << 5;
         const DIRTY = 1 << 6;
         const HUGE_PAGE = 1 << 7;
+        /// Selects the upper half of the page attribute table, with `WRITE_THROUGH` and
+        ///  `DISABLE_CACHE` (only in entries mapping 4KiB pages, where it replaces `HUGE_PAGE`).
+        const PAT = 1 << 7;
         const GLOBAL = 1 << 8;
-        // 3 bits free for use by OS
+        /// Available to the OS: the entry holds a reference to its (shared) frame, and is
+        ///  copied on the first write while the frame has other references.
+        const COPY_ON_WRITE = 1 << 9;
+        // 2 bits free for use by OS
         const NO_EXECUTE = 1 << 63;
     }
 }
@@ -22,6 +28,9 @@ bitflags! {
 pub struct PageTableEntry(usize);
 
 impl PageTableEntry {
+    const OCCUPANCY_SHIFT: usize = 52;
+    const OCCUPANCY_MASK: usize = 0x3FF << Self::OCCUPANCY_SHIFT;
+
     pub const fn unused() -> Self {
         Self { 0: 0 }
     }
@@ -46,17 +55,38 @@ impl PageTableEntry {
         }
     }
 
+    /// Points the entry at the frame with the given attributes, keeping its occupancy (so an
+    ///  entry pointing to a sub table can have its attributes changed).
     pub fn set(&mut self, frame: &Frame, attribs: PageAttributes) {
-        self.0 = frame.addr().as_usize() | attribs.bits();
+        self.0 = (self.0 & Self::OCCUPANCY_MASK) | frame.addr().as_usize() | attribs.bits();
     }
 
     pub fn is_present(&self) -> bool {
         self.attribs().contains(PageAttributes::PRESENT)
     }
 
+    /// Whether the entry is present, and maps a huge page (rather than a sub table).
+    pub fn is_huge(&self) -> bool {
+        self.attribs()
+            .contains(PageAttributes::PRESENT | PageAttributes::HUGE_PAGE)
+    }
+
     pub fn set_nonpresent(&mut self) {
         self.0 ^= PageAttributes::PRESENT.bits();
     }
+
+    /// Number of present entries in the sub table this entry points to.
+    ///
+    /// This is stored in bits 52..62, which the CPU ignores in entries pointing to
+    ///  sub tables, so it's meaningless for entries which map a page.
+    pub fn occupancy(&self) -> usize {
+        (self.0 & Self::OCCUPANCY_MASK) >> Self::OCCUPANCY_SHIFT
+    }
+
+    pub fn set_occupancy(&mut self, occupancy: usize) {
+        debug_assert!(occupancy <= 512, "invalid page table occupancy");
+        self.0 = (self.0 & !Self::OCCUPANCY_MASK) | (occupancy << Self::OCCUPANCY_SHIFT);
+    }
 }
 
 impl core::fmt::Debug for PageTableEntry {
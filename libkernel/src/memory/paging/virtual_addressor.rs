@@ -1,22 +1,34 @@
 use crate::{
     addr_ty::Virtual,
+    instructions::tlb::ReleasedFrames,
     memory::{
-        paging::{Level4, PageAttributes, PageTable, PageTableEntry},
+        paging::{
+            Level1, Level4, Level5, PageAttributes, PageTable, PageTableEntry, PagingError,
+            TableLevel,
+        },
         Frame, Page,
     },
     Address,
 };
 
+/// Index of the first root table entry in the kernel half of the address space.
+///
+/// The root table is the PML4, or the PML5 with 5-level paging.
+pub const KERNEL_HALF_ROOT_INDEX: usize = 256;
+
 pub struct VirtualAddressor {
     mapped_page: Page,
-    pml4_frame: Frame,
+    root_frame: Frame,
+    /// Whether the root table is a PML5 (see `paging::five_level_paging`).
+    five_level: bool,
 }
 
 impl VirtualAddressor {
     pub const fn null() -> Self {
         Self {
             mapped_page: Page::null(),
-            pml4_frame: Frame::null(),
+            root_frame: Frame::null(),
+            five_level: false,
         }
     }
 
@@ -26,15 +38,55 @@ impl VirtualAddressor {
     /// Safety: this method is unsafe because `mapped_page` can be any value; that is, not necessarily
     /// a valid address in which physical memory is already mapped.
     pub unsafe fn new(mapped_page: Page) -> Self {
-        let pml4_frame = crate::memory::falloc::get()
-            .lock_next()
-            .expect("failed to lock frame for PML4 of VirtualAddressor");
+        Self::try_new(mapped_page)
+            .expect("failed to lock frame for root page table of VirtualAddressor")
+    }
 
-        Self {
+    /// Fallible variant of `new`.
+    ///
+    /// The addressor uses 5-level paging if it's currently active.
+    pub unsafe fn try_new(mapped_page: Page) -> Result<Self, PagingError> {
+        let falloc = crate::memory::falloc::get();
+        let (root_frame, zeroed) = match falloc.lock_zeroed() {
+            Some(frame) => (frame, true),
+            None => (
+                crate::memory::pressure::lock_next_with_reclaim()
+                    .ok_or(PagingError::OutOfFrames)?,
+                false,
+            ),
+        };
+
+        let mut addressor = Self {
             // we don't know where physical memory is mapped at this point,
             // so rely on what the caller specifies for us
             mapped_page,
-            pml4_frame,
+            root_frame,
+            five_level: super::five_level_paging(),
+        };
+
+        // Root tables taken from the pre-zeroed pool are already clear.
+        if !zeroed {
+            if addressor.five_level {
+                addressor.pml5_mut().clear();
+            } else {
+                addressor.root_pml4_mut().clear();
+            }
+        }
+
+        Ok(addressor)
+    }
+
+    /// Creates a VirtualAddressor for the currently active PML4 (i.e. of whichever address
+    ///  space is loaded into CR3).
+    ///
+    /// Safety: `mapped_page` must be where the entirety of the system physical memory is mapped,
+    ///  and the returned addressor must not outlive the active address space, or be used to
+    ///  modify it concurrently with its owner.
+    pub unsafe fn active(mapped_page: Page) -> Self {
+        Self {
+            mapped_page,
+            root_frame: crate::registers::CR3::read_frame(),
+            five_level: super::five_level_paging(),
         }
     }
 
@@ -42,18 +94,91 @@ impl VirtualAddressor {
         self.mapped_page
     }
 
+    /// Frame of the root page table (the PML4, or the PML5 with 5-level paging).
+    pub fn root_frame(&self) -> &Frame {
+        &self.root_frame
+    }
+
+    pub fn is_five_level(&self) -> bool {
+        self.five_level
+    }
+
     /* ACQUIRE STATE */
 
-    fn pml4_page(&self) -> Page {
-        self.mapped_page.offset(self.pml4_frame.index())
+    fn root_page(&self) -> Page {
+        self.mapped_page.offset(self.root_frame.index())
+    }
+
+    fn pml5(&self) -> &PageTable<Level5> {
+        debug_assert!(self.five_level, "root page table is not a PML5");
+        unsafe { &*self.root_page().as_ptr() }
+    }
+
+    fn pml5_mut(&mut self) -> &mut PageTable<Level5> {
+        debug_assert!(self.five_level, "root page table is not a PML5");
+        unsafe { &mut *self.root_page().as_mut_ptr() }
     }
 
-    fn pml4(&self) -> &PageTable<Level4> {
-        unsafe { &*self.pml4_page().as_ptr() }
+    fn root_pml4(&self) -> &PageTable<Level4> {
+        debug_assert!(!self.five_level, "root page table is not a PML4");
+        unsafe { &*self.root_page().as_ptr() }
     }
 
-    fn pml4_mut(&mut self) -> &mut PageTable<Level4> {
-        unsafe { &mut *self.pml4_page().as_mut_ptr() }
+    fn root_pml4_mut(&mut self) -> &mut PageTable<Level4> {
+        debug_assert!(!self.five_level, "root page table is not a PML4");
+        unsafe { &mut *self.root_page().as_mut_ptr() }
+    }
+
+    fn root_entry(&self, index: usize) -> &PageTableEntry {
+        if self.five_level {
+            self.pml5().get_entry(index)
+        } else {
+            self.root_pml4().get_entry(index)
+        }
+    }
+
+    fn root_entry_mut(&mut self, index: usize) -> &mut PageTableEntry {
+        if self.five_level {
+            self.pml5_mut().get_entry_mut(index)
+        } else {
+            self.root_pml4_mut().get_entry_mut(index)
+        }
+    }
+
+    /// The PML4 covering `addr`, which is the root table unless 5-level paging is used.
+    fn pml4(&self, addr: Address<Virtual>) -> Option<&PageTable<Level4>> {
+        if self.five_level {
+            unsafe {
+                self.pml5()
+                    .sub_table(addr.p5_index(), self.mapped_page.addr())
+            }
+        } else {
+            Some(self.root_pml4())
+        }
+    }
+
+    fn pml4_mut(&mut self, addr: Address<Virtual>) -> Result<&mut PageTable<Level4>, PagingError> {
+        if self.five_level {
+            let offset = self.mapped_page.addr();
+            unsafe { self.pml5_mut().sub_table_try_mut(addr.p5_index(), offset) }
+        } else {
+            Ok(self.root_pml4_mut())
+        }
+    }
+
+    /// The PML4 covering `addr`, creating it if 5-level paging is used and it doesn't exist.
+    ///
+    /// PML4s under the PML5 aren't tracked, so they're only freed along with the addressor.
+    fn pml4_create(
+        &mut self,
+        addr: Address<Virtual>,
+    ) -> Result<&mut PageTable<Level4>, PagingError> {
+        if self.five_level {
+            let offset = self.mapped_page.addr();
+            unsafe { self.pml5_mut().sub_table_create(addr.p5_index(), offset) }
+        } else {
+            Ok(self.root_pml4_mut())
+        }
     }
 
     fn get_page_entry(&self, page: &Page) -> Option<&PageTableEntry> {
@@ -61,7 +186,7 @@ impl VirtualAddressor {
         let addr = page.addr();
 
         unsafe {
-            self.pml4()
+            self.pml4(addr)?
                 .sub_table(addr.p4_index(), offset)
                 .and_then(|p3| p3.sub_table(addr.p3_index(), offset))
                 .and_then(|p2| p2.sub_table(addr.p2_index(), offset))
@@ -69,61 +194,644 @@ impl VirtualAddressor {
         }
     }
 
-    fn get_page_entry_mut(&mut self, page: &Page) -> Option<&mut PageTableEntry> {
+    /// Walks to the P1 table containing `page`, returning the P2 entry which points to
+    ///  it (and so holds its occupancy), along with the table itself.
+    fn walk_p1_mut(
+        &mut self,
+        page: &Page,
+    ) -> Result<(&mut PageTableEntry, &mut PageTable<Level1>), PagingError> {
         let offset = self.mapped_page.addr();
         let addr = page.addr();
 
         unsafe {
-            self.pml4_mut()
-                .sub_table_mut(addr.p4_index(), offset)
-                .and_then(|p3| p3.sub_table_mut(addr.p3_index(), offset))
-                .and_then(|p2| p2.sub_table_mut(addr.p2_index(), offset))
-                .and_then(|p1| Some(p1.get_entry_mut(addr.p1_index())))
+            let p2 = self
+                .pml4_mut(addr)?
+                .sub_table_try_mut(addr.p4_index(), offset)?
+                .sub_table_try_mut(addr.p3_index(), offset)?;
+            let p1_entry: *mut PageTableEntry = p2.get_entry_mut(addr.p2_index());
+            let p1 = p2.sub_table_try_mut(addr.p2_index(), offset)?;
+
+            Ok((&mut *p1_entry, p1))
         }
     }
 
-    fn get_page_entry_create(&mut self, page: &Page) -> &mut PageTableEntry {
+    /// Walks to the P1 table containing `page`, creating any tables along the way, and
+    ///  returning the P2 entry which points to it along with the table itself.
+    ///
+    /// If this fails, empty tables may be left along the walk; see `free_empty_tables`.
+    fn walk_p1_create(
+        &mut self,
+        page: &Page,
+    ) -> Result<(&mut PageTableEntry, &mut PageTable<Level1>), PagingError> {
         let offset = self.mapped_page.addr();
         let addr = page.addr();
 
         unsafe {
-            self.pml4_mut()
-                .sub_table_create(addr.p4_index(), offset)
-                .sub_table_create(addr.p3_index(), offset)
-                .sub_table_create(addr.p2_index(), offset)
-                .get_entry_mut(addr.p1_index())
+            let pml4 = self.pml4_create(addr)?;
+            let p3_entry: *mut PageTableEntry = pml4.get_entry_mut(addr.p4_index());
+            let p3 = pml4.sub_table_create(addr.p4_index(), offset)?;
+
+            let p2_entry: *mut PageTableEntry = p3.get_entry_mut(addr.p3_index());
+            let p2_created = !(*p2_entry).is_present();
+            let p2 = p3.sub_table_create(addr.p3_index(), offset)?;
+            if p2_created {
+                (*p3_entry).set_occupancy((*p3_entry).occupancy() + 1);
+            }
+
+            let p1_entry: *mut PageTableEntry = p2.get_entry_mut(addr.p2_index());
+            let p1_created = !(*p1_entry).is_present();
+            let p1 = p2.sub_table_create(addr.p2_index(), offset)?;
+            if p1_created {
+                (*p2_entry).set_occupancy((*p2_entry).occupancy() + 1);
+            }
+
+            Ok((&mut *p1_entry, p1))
+        }
+    }
+
+    /// Frees the tables along the walk to `page` which have no present entries (from the
+    ///  P1 table upwards), returning their frames to the frame allocator.
+    fn free_empty_tables(&mut self, page: &Page) {
+        let offset = self.mapped_page.addr();
+        let addr = page.addr();
+        let five_level = self.five_level;
+
+        unsafe {
+            let pml4 = match self.pml4_mut(addr) {
+                Ok(pml4) => pml4,
+                Err(_) => return,
+            };
+            let p3_entry: *mut PageTableEntry = pml4.get_entry_mut(addr.p4_index());
+            let p3 = match pml4.sub_table_try_mut(addr.p4_index(), offset) {
+                Ok(p3) => p3,
+                Err(_) => return,
+            };
+
+            let p2_entry: *mut PageTableEntry = p3.get_entry_mut(addr.p3_index());
+            if let Ok(p2) = p3.sub_table_try_mut(addr.p3_index(), offset) {
+                if Self::free_if_empty(p2.get_entry_mut(addr.p2_index())) {
+                    Self::release_occupancy(&mut *p2_entry, p2, 1);
+                }
+            }
+
+            // PML4s aren't tracked, since they're never freed. With 4-level paging, P3 tables
+            //  under root entries shared with the kernel (the kernel half, and the identity map)
+            //  are never freed either: every address space holds its own copy of the root entry,
+            //  and so its own occupancy of the table, which only counts its own mappings (and
+            //  so can't be released for tables another address space created).
+            let p3_tracked = five_level || !super::is_kernel_root_entry(addr.p4_index());
+            if Self::free_if_empty(&mut *p2_entry) && p3_tracked {
+                Self::release_occupancy(&mut *p3_entry, p3, 1);
+            }
+
+            if p3_tracked {
+                Self::free_if_empty(&mut *p3_entry);
+            }
+        }
+    }
+
+    /// Decreases the occupancy `entry` holds of `table` (the sub table it points to) by `count`.
+    ///
+    /// An underflow means the occupancy is corrupt: it's caught in debug builds, and otherwise the
+    ///  occupancy is recounted from the table, so a table still in use is never freed.
+    fn release_occupancy<L: TableLevel>(
+        entry: &mut PageTableEntry,
+        table: &PageTable<L>,
+        count: usize,
+    ) {
+        let occupancy = match entry.occupancy().checked_sub(count) {
+            Some(occupancy) => occupancy,
+            None => {
+                debug_assert!(false, "page table occupancy underflowed: {:?}", entry);
+                table.iter().filter(|entry| entry.is_present()).count()
+            }
+        };
+
+        entry.set_occupancy(occupancy);
+    }
+
+    /// Frees the sub table the entry points to if it has no present entries, returning
+    ///  whether it was freed.
+    unsafe fn free_if_empty(entry: &mut PageTableEntry) -> bool {
+        if entry.is_present() && !entry.is_huge() && entry.occupancy() == 0 {
+            let frame = entry.frame().unwrap();
+            trace!("Freeing empty page table: {:?}", frame);
+
+            entry.set_unused();
+            crate::memory::falloc::get().free_frame(frame).unwrap();
+            true
+        } else {
+            false
         }
     }
 
     /* MAP / UNMAP */
 
-    pub fn map(&mut self, page: &Page, frame: &Frame) {
-        assert!(
-            !self.is_mapped(page.addr()),
-            "page already mapped: {:?}",
-            page
-        );
+    pub fn map(&mut self, page: &Page, frame: &Frame) -> Result<(), PagingError> {
+        self.map_with_attribs(
+            page,
+            frame,
+            PageAttributes::PRESENT | PageAttributes::WRITABLE,
+        )
+    }
 
-        self.get_page_entry_create(page)
-            .set(&frame, PageAttributes::PRESENT | PageAttributes::WRITABLE);
+    fn map_with_attribs(
+        &mut self,
+        page: &Page,
+        frame: &Frame,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError> {
+        let (p1_entry, p1) = match self.walk_p1_create(page) {
+            Ok(walk) => walk,
+            Err(paging_error) => {
+                self.free_empty_tables(page);
+                return Err(paging_error);
+            }
+        };
+        let entry = p1.get_entry_mut(page.addr().p1_index());
+
+        if entry.is_present() {
+            return Err(PagingError::AlreadyMapped);
+        }
+
+        entry.set(&frame, attribs);
+        p1_entry.set_occupancy(p1_entry.occupancy() + 1);
         crate::instructions::tlb::invalidate(page);
         trace!("Mapped {:?} -> {:?}", page, frame);
 
-        assert!(self.is_mapped_to(page, frame), "failed to map page",);
+        Ok(())
     }
 
-    pub fn unmap(&mut self, page: &Page) {
-        assert!(self.is_mapped(page.addr()), "page already unmapped");
+    /// Unmaps the page.
+    ///
+    /// If the page was mapped copy-on-write, its reference to the frame is added to `released`,
+    ///  to be dropped once the unmapping has been shot down.
+    pub fn unmap(&mut self, page: &Page, released: &mut ReleasedFrames) -> Result<(), PagingError> {
+        let (p1_entry, p1) = self.walk_p1_mut(page)?;
+        let entry = p1.get_entry_mut(page.addr().p1_index());
+
+        let frame = entry.frame().ok_or(PagingError::NotMapped)?;
+        let shared = entry.attribs().contains(PageAttributes::COPY_ON_WRITE);
+
+        entry.set_nonpresent();
+        Self::release_occupancy(p1_entry, p1, 1);
+        if p1_entry.occupancy() == 0 {
+            self.free_empty_tables(page);
+        }
 
-        self.get_page_entry_mut(page).unwrap().set_nonpresent();
         crate::instructions::tlb::invalidate(page);
+        crate::instructions::tlb::note_modified(page, 1);
+        if shared {
+            released.push(frame);
+        }
         trace!("Unmapped {:?}", page);
 
-        assert!(!self.is_mapped(page.addr()), "failed to unmap page",);
+        Ok(())
+    }
+
+    /// Maps an already-mapped page to a new frame, returning the frame it was previously mapped to.
+    pub fn remap(&mut self, page: &Page, frame: &Frame) -> Result<Frame, PagingError> {
+        let entry = self
+            .walk_p1_mut(page)?
+            .1
+            .get_entry_mut(page.addr().p1_index());
+        let old_frame = entry.frame().ok_or(PagingError::NotMapped)?;
+        let attribs = entry.attribs();
+
+        entry.set(&frame, attribs);
+        crate::instructions::tlb::invalidate(page);
+        crate::instructions::tlb::note_modified(page, 1);
+        trace!("Remapped {:?}: {:?} -> {:?}", page, old_frame, frame);
+
+        Ok(old_frame)
+    }
+
+    /// Maps `count` contiguous pages, starting at `page`, to contiguous frames starting at `frame`.
+    ///
+    /// Table walks are shared by every page within the same P1 table, and the TLB is
+    ///  invalidated once for the entire range. If mapping fails, any pages of the range
+    ///  which were mapped are unmapped again.
+    pub fn map_range(
+        &mut self,
+        page: &Page,
+        frame: &Frame,
+        count: usize,
+    ) -> Result<(), PagingError> {
+        self.map_range_with_attribs(
+            page,
+            frame,
+            count,
+            PageAttributes::PRESENT | PageAttributes::WRITABLE,
+        )
+    }
+
+    /// Maps `count` contiguous pages to contiguous frames, as `map_range`, with the given
+    ///  attributes (i.e. to control caching).
+    pub fn map_range_with_attribs(
+        &mut self,
+        page: &Page,
+        frame: &Frame,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError> {
+        let mut offset = 0;
+        let mut result = Ok(());
+
+        while offset < count {
+            let cur_page = page.offset(offset);
+            let p1_index = cur_page.addr().p1_index();
+            let batch_len = core::cmp::min(512 - p1_index, count - offset);
+
+            let (p1_entry, p1) = match self.walk_p1_create(&cur_page) {
+                Ok(walk) => walk,
+                Err(paging_error) => {
+                    self.free_empty_tables(&cur_page);
+                    result = Err(paging_error);
+                    break;
+                }
+            };
+
+            let mut mapped_len = 0;
+            while mapped_len < batch_len {
+                let entry = p1.get_entry_mut(p1_index + mapped_len);
+                if entry.is_present() {
+                    result = Err(PagingError::AlreadyMapped);
+                    break;
+                }
+
+                entry.set(
+                    unsafe { &Frame::from_index(frame.index() + offset + mapped_len) },
+                    attribs,
+                );
+                mapped_len += 1;
+            }
+
+            p1_entry.set_occupancy(p1_entry.occupancy() + mapped_len);
+            offset += mapped_len;
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                crate::instructions::tlb::invalidate_range(page, count);
+                trace!("Mapped {:?} -> {:?} ({} pages)", page, frame, count);
+            }
+            Err(_) if offset > 0 => {
+                // Every page before `offset` was mapped by this call, so this can't fail. The
+                //  mappings took no references to their frames, so none are released.
+                self.unmap_range_with(page, offset, |_| {}).unwrap();
+            }
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    /// Unmaps `count` contiguous pages, starting at `page`.
+    ///
+    /// Table walks are shared by every page within the same P1 table, and the TLB is
+    ///  invalidated once for the entire range. If any page isn't mapped, the range is
+    ///  left unmodified. Pages mapped copy-on-write have their reference to the frame added
+    ///  to `released` (so at most `ReleasedFrames::CAPACITY` may be unmapped between flushes).
+    pub fn unmap_range(
+        &mut self,
+        page: &Page,
+        count: usize,
+        released: &mut ReleasedFrames,
+    ) -> Result<(), PagingError> {
+        self.unmap_range_with(page, count, |frame| released.push(frame))
+    }
+
+    /// Unmaps a range of pages, as `unmap_range`, passing the frame of each page mapped
+    ///  copy-on-write to `release`.
+    fn unmap_range_with<F: FnMut(Frame)>(
+        &mut self,
+        page: &Page,
+        count: usize,
+        mut release: F,
+    ) -> Result<(), PagingError> {
+        // Validate the entire range first, so a failure doesn't leave it partially unmapped.
+        let mut offset = 0;
+        while offset < count {
+            let cur_page = page.offset(offset);
+            let p1_index = cur_page.addr().p1_index();
+            let batch_len = core::cmp::min(512 - p1_index, count - offset);
+
+            let (_, p1) = self.walk_p1_mut(&cur_page)?;
+            if !(p1_index..(p1_index + batch_len)).all(|index| p1.get_entry(index).is_present()) {
+                return Err(PagingError::NotMapped);
+            }
+
+            offset += batch_len;
+        }
+
+        let mut offset = 0;
+        while offset < count {
+            let cur_page = page.offset(offset);
+            let p1_index = cur_page.addr().p1_index();
+            let batch_len = core::cmp::min(512 - p1_index, count - offset);
+
+            let (p1_entry, p1) = self.walk_p1_mut(&cur_page)?;
+            for index in p1_index..(p1_index + batch_len) {
+                let entry = p1.get_entry_mut(index);
+                if entry.attribs().contains(PageAttributes::COPY_ON_WRITE) {
+                    release(entry.frame().unwrap());
+                }
+
+                entry.set_nonpresent();
+            }
+
+            Self::release_occupancy(p1_entry, p1, batch_len);
+            if p1_entry.occupancy() == 0 {
+                self.free_empty_tables(&cur_page);
+            }
+
+            offset += batch_len;
+        }
+
+        crate::instructions::tlb::invalidate_range(page, count);
+        crate::instructions::tlb::note_modified(page, count);
+        trace!("Unmapped {:?} ({} pages)", page, count);
+
+        Ok(())
+    }
+
+    /// Replaces the attributes of `count` contiguous mapped pages, starting at `page`, keeping
+    ///  the frames they're mapped to (i.e. to change their caching).
+    ///
+    /// If any page isn't mapped, the range is left unmodified.
+    pub fn set_range_attribs(
+        &mut self,
+        page: &Page,
+        count: usize,
+        attribs: PageAttributes,
+    ) -> Result<(), PagingError> {
+        // Validate the entire range first, so a failure doesn't leave it partially modified.
+        let mut offset = 0;
+        while offset < count {
+            let cur_page = page.offset(offset);
+            let p1_index = cur_page.addr().p1_index();
+            let batch_len = core::cmp::min(512 - p1_index, count - offset);
+
+            let (_, p1) = self.walk_p1_mut(&cur_page)?;
+            if !(p1_index..(p1_index + batch_len)).all(|index| p1.get_entry(index).is_present()) {
+                return Err(PagingError::NotMapped);
+            }
+
+            offset += batch_len;
+        }
+
+        let mut offset = 0;
+        while offset < count {
+            let cur_page = page.offset(offset);
+            let p1_index = cur_page.addr().p1_index();
+            let batch_len = core::cmp::min(512 - p1_index, count - offset);
+
+            let (_, p1) = self.walk_p1_mut(&cur_page)?;
+            for index in p1_index..(p1_index + batch_len) {
+                let entry = p1.get_entry_mut(index);
+                let frame = entry.frame().unwrap();
+                entry.set(&frame, attribs);
+            }
+
+            offset += batch_len;
+        }
+
+        crate::instructions::tlb::invalidate_range(page, count);
+        crate::instructions::tlb::note_modified(page, count);
+        trace!(
+            "Set attributes of {:?} ({} pages): {:?}",
+            page,
+            count,
+            attribs
+        );
+
+        Ok(())
     }
 
-    pub fn identity_map(&mut self, frame: &Frame) {
-        self.map(&Page::from_index(frame.index()), frame);
+    pub fn identity_map(&mut self, frame: &Frame) -> Result<(), PagingError> {
+        self.map(&Page::from_index(frame.index()), frame)
+    }
+
+    /* COPY-ON-WRITE */
+
+    /// Maps the page read-only to a shared frame, to be copied on the first write.
+    ///
+    /// The mapping takes its own reference to the frame (which must be locked), dropped when
+    ///  the page is unmapped. Any writable mapping of the frame should be made copy-on-write
+    ///  too (see `copy_on_write`), or its writes will be visible through this page.
+    pub fn map_copy_on_write(&mut self, page: &Page, frame: &Frame) -> Result<(), PagingError> {
+        let falloc = crate::memory::falloc::get();
+        falloc
+            .reference_frame(frame)
+            .map_err(|_| PagingError::TooManyReferences)?;
+
+        self.map_with_attribs(
+            page,
+            frame,
+            PageAttributes::PRESENT | PageAttributes::COPY_ON_WRITE,
+        )
+        .map_err(|paging_error| {
+            unsafe { falloc.free_frame(*frame).unwrap() };
+            paging_error
+        })
+    }
+
+    /// Makes an existing mapping of the page read-only, to be copied on the first write,
+    ///  returning the frame it's mapped to.
+    ///
+    /// If it wasn't already copy-on-write, the mapping takes ownership of the caller's
+    ///  reference to the frame.
+    pub fn copy_on_write(&mut self, page: &Page) -> Result<Frame, PagingError> {
+        let entry = self
+            .walk_p1_mut(page)?
+            .1
+            .get_entry_mut(page.addr().p1_index());
+        let frame = entry.frame().ok_or(PagingError::NotMapped)?;
+        let attribs = (entry.attribs() | PageAttributes::COPY_ON_WRITE) & !PageAttributes::WRITABLE;
+
+        entry.set(&frame, attribs);
+        crate::instructions::tlb::invalidate(page);
+        crate::instructions::tlb::note_modified(page, 1);
+
+        Ok(frame)
+    }
+
+    /// Resolves a write to a copy-on-write page, returning whether the page was copy-on-write.
+    ///
+    /// The page is copied to a new frame if its frame has other references; otherwise, it's
+    ///  simply made writable.
+    pub fn resolve_copy_on_write(&mut self, page: &Page) -> Result<bool, PagingError> {
+        let mapped_page = self.mapped_page;
+        let entry = self
+            .walk_p1_mut(page)?
+            .1
+            .get_entry_mut(page.addr().p1_index());
+        let frame = entry.frame().ok_or(PagingError::NotMapped)?;
+        let attribs = entry.attribs();
+
+        if !attribs.contains(PageAttributes::COPY_ON_WRITE) {
+            return Ok(false);
+        } else if !attribs.contains(PageAttributes::WRITABLE) {
+            let falloc = crate::memory::falloc::get();
+
+            if falloc.reference_count(&frame) > 1 {
+                let copy = crate::memory::pressure::lock_next_with_reclaim()
+                    .ok_or(PagingError::OutOfFrames)?;
+
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        mapped_page.offset(frame.index()).as_ptr::<u8>(),
+                        mapped_page.offset(copy.index()).as_mut_ptr::<u8>(),
+                        0x1000,
+                    );
+                }
+
+                entry.set(&copy, attribs | PageAttributes::WRITABLE);
+                unsafe { falloc.free_frame(frame).unwrap() };
+                trace!("Copied on write {:?}: {:?} -> {:?}", page, frame, copy);
+
+                // Other CPUs (or identifiers) may still map the page to the original frame.
+                crate::instructions::tlb::note_contexts_stale();
+                crate::instructions::tlb::defer_shootdown(page, 1);
+            } else {
+                entry.set(&frame, attribs | PageAttributes::WRITABLE);
+                trace!("Took sole ownership on write {:?}: {:?}", page, frame);
+            }
+        }
+
+        // If the entry was already writable, the fault was from a stale TLB entry.
+        crate::instructions::tlb::invalidate(page);
+        Ok(true)
+    }
+
+    /// Shares every page mapped by the root table entries (for which `shared` returns true)
+    ///  with `other`, copy-on-write.
+    ///
+    /// Pages become copy-on-write in this addressor too. Mappings which weren't already
+    ///  copy-on-write take their own reference to the frame, so whoever owns the frame (i.e.
+    ///  the allocator which mapped it) still frees it as before. On failure, pages which were
+    ///  already shared remain so; `other`'s mappings can be released with `free_tables`.
+    pub fn share_copy_on_write<F: Fn(usize) -> bool>(
+        &mut self,
+        other: &mut VirtualAddressor,
+        shared: F,
+    ) -> Result<(), PagingError> {
+        let offset = self.mapped_page.addr();
+
+        let result = if self.five_level {
+            let pml5 = self.pml5_mut();
+
+            (0..512)
+                .filter(|index| shared(*index))
+                .try_for_each(|p5_index| {
+                    match unsafe { pml5.sub_table_try_mut(p5_index, offset) } {
+                        Ok(pml4) => Self::share_pml4_copy_on_write(
+                            pml4,
+                            offset,
+                            p5_index << 36,
+                            0..512,
+                            other,
+                        ),
+                        Err(_) => Ok(()),
+                    }
+                })
+        } else {
+            Self::share_pml4_copy_on_write(
+                self.root_pml4_mut(),
+                offset,
+                0,
+                (0..512).filter(|index| shared(*index)),
+                other,
+            )
+        };
+
+        // Pages which were writable are now read-only, so any writable TLB entries are flushed
+        //  under every process-context identifier, and on every other CPU (once the caller
+        //  calls `tlb::flush_shootdowns`).
+        crate::instructions::tlb::invalidate_all_contexts();
+        crate::instructions::tlb::note_contexts_stale();
+        crate::instructions::tlb::defer_shootdown(&Page::null(), usize::MAX);
+        result
+    }
+
+    /// Shares every page mapped by the given entries of `pml4` with `other`, copy-on-write.
+    ///
+    /// `page_prefix` holds the bits of the pages' indexes above those the PML4 covers.
+    fn share_pml4_copy_on_write(
+        pml4: &mut PageTable<Level4>,
+        offset: Address<Virtual>,
+        page_prefix: usize,
+        p4_indexes: impl Iterator<Item = usize>,
+        other: &mut VirtualAddressor,
+    ) -> Result<(), PagingError> {
+        let falloc = crate::memory::falloc::get();
+        let five_level = other.five_level;
+
+        for p4_index in p4_indexes {
+            let p3 = match unsafe { pml4.sub_table_try_mut(p4_index, offset) } {
+                Ok(p3) => p3,
+                Err(_) => continue,
+            };
+
+            for p3_index in 0..512 {
+                let p2 = match unsafe { p3.sub_table_try_mut(p3_index, offset) } {
+                    Ok(p2) => p2,
+                    Err(_) => continue,
+                };
+
+                for p2_index in 0..512 {
+                    let p1 = match unsafe { p2.sub_table_try_mut(p2_index, offset) } {
+                        Ok(p1) => p1,
+                        Err(_) => continue,
+                    };
+
+                    for p1_index in 0..512 {
+                        let entry = p1.get_entry_mut(p1_index);
+                        let frame = match entry.frame() {
+                            Some(frame) => frame,
+                            None => continue,
+                        };
+                        let attribs = (entry.attribs() | PageAttributes::COPY_ON_WRITE)
+                            & !PageAttributes::WRITABLE;
+
+                        if !entry.attribs().contains(PageAttributes::COPY_ON_WRITE) {
+                            falloc
+                                .reference_frame(&frame)
+                                .map_err(|_| PagingError::TooManyReferences)?;
+                            entry.set(&frame, attribs);
+                        }
+
+                        falloc
+                            .reference_frame(&frame)
+                            .map_err(|_| PagingError::TooManyReferences)?;
+
+                        let addr = (page_prefix
+                            | (p4_index << 27)
+                            | (p3_index << 18)
+                            | (p2_index << 9)
+                            | p1_index)
+                            * 0x1000;
+                        // sign-extend kernel-half addresses
+                        let page = Page::containing_addr(if five_level {
+                            Address::<Virtual>::new_truncate_la57(addr)
+                        } else {
+                            Address::<Virtual>::new_truncate(addr)
+                        });
+
+                        if let Err(paging_error) = other.map_with_attribs(&page, &frame, attribs) {
+                            unsafe { falloc.free_frame(frame).unwrap() };
+                            return Err(paging_error);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 
     /* STATE QUERYING */
@@ -145,19 +853,142 @@ impl VirtualAddressor {
         self.get_page_entry(page).and_then(|entry| entry.frame())
     }
 
+    /// Attributes of the page's entry, if it's mapped.
+    pub fn page_attribs(&self, page: &Page) -> Option<PageAttributes> {
+        self.get_page_entry(page)
+            .filter(|entry| entry.is_present())
+            .map(|entry| entry.attribs())
+    }
+
     /* STATE CHANGING */
 
-    pub unsafe fn modify_mapped_page(&mut self, page: Page) {
-        let total_memory_pages = crate::memory::falloc::get().total_memory(None) / 0x1000;
-        for index in 0..total_memory_pages {
-            self.map(&page.offset(index), &Frame::from_index(index));
+    /// Creates the sub table of every kernel-half root table entry, so the entries can be
+    ///  shared by other address spaces before any kernel-half memory is mapped.
+    pub fn populate_kernel_half(&mut self) -> Result<(), PagingError> {
+        let offset = self.mapped_page.addr();
+
+        for index in KERNEL_HALF_ROOT_INDEX..512 {
+            unsafe {
+                if self.five_level {
+                    self.pml5_mut().sub_table_create(index, offset)?;
+                } else {
+                    self.root_pml4_mut().sub_table_create(index, offset)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies the root table entries (for which `shared` returns true) of `other` into this
+    ///  addressor, so the tables they point to are shared between both.
+    pub fn share_root_entries<F: Fn(usize) -> bool>(
+        &mut self,
+        other: &VirtualAddressor,
+        shared: F,
+    ) {
+        debug_assert_eq!(
+            self.five_level, other.five_level,
+            "addressors must have the same paging depth"
+        );
+
+        for index in (0..512).filter(|index| shared(*index)) {
+            *self.root_entry_mut(index) = *other.root_entry(index);
+        }
+    }
+
+    /// Frees every table reachable from the root table entries for which `owned` returns true,
+    ///  along with the root table itself.
+    ///
+    /// Frames mapped by pages are not freed, except that pages mapped copy-on-write have their
+    ///  reference to the frame dropped.
+    ///
+    /// Safety: the addressor must not be active, and must not be used afterwards.
+    pub unsafe fn free_tables<F: Fn(usize) -> bool>(&mut self, owned: F) {
+        let offset = self.mapped_page.addr();
+        let falloc = crate::memory::falloc::get();
+
+        if self.five_level {
+            let pml5 = self.pml5_mut();
+
+            for p5_index in (0..512).filter(|index| owned(*index)) {
+                if let Ok(pml4) = pml5.sub_table_try_mut(p5_index, offset) {
+                    Self::free_pml4_tables(pml4, offset, 0..512);
+
+                    falloc
+                        .free_frame(pml5.get_entry(p5_index).frame().unwrap())
+                        .unwrap();
+                    pml5.get_entry_mut(p5_index).set_unused();
+                }
+            }
+        } else {
+            Self::free_pml4_tables(
+                self.root_pml4_mut(),
+                offset,
+                (0..512).filter(|index| owned(*index)),
+            );
         }
 
+        falloc.free_frame(self.root_frame).unwrap();
+    }
+
+    /// Frees every table reachable from the given entries of `pml4`, leaving the entries unused.
+    unsafe fn free_pml4_tables(
+        pml4: &mut PageTable<Level4>,
+        offset: Address<Virtual>,
+        p4_indexes: impl Iterator<Item = usize>,
+    ) {
+        let falloc = crate::memory::falloc::get();
+
+        for p4_index in p4_indexes {
+            let p3 = match pml4.sub_table_try_mut(p4_index, offset) {
+                Ok(p3) => p3,
+                Err(_) => continue,
+            };
+
+            for p3_index in 0..512 {
+                let p2 = match p3.sub_table_try_mut(p3_index, offset) {
+                    Ok(p2) => p2,
+                    Err(_) => continue,
+                };
+
+                for p2_index in 0..512 {
+                    let p1 = match p2.sub_table_try_mut(p2_index, offset) {
+                        Ok(p1) => p1,
+                        Err(_) => continue,
+                    };
+
+                    p1.iter()
+                        .filter(|entry| entry.attribs().contains(PageAttributes::COPY_ON_WRITE))
+                        .filter_map(|entry| entry.frame())
+                        .for_each(|frame| falloc.free_frame(frame).unwrap());
+                    falloc
+                        .free_frame(p2.get_entry(p2_index).frame().unwrap())
+                        .unwrap();
+                }
+
+                falloc
+                    .free_frame(p3.get_entry(p3_index).frame().unwrap())
+                    .unwrap();
+            }
+
+            falloc
+                .free_frame(pml4.get_entry(p4_index).frame().unwrap())
+                .unwrap();
+            pml4.get_entry_mut(p4_index).set_unused();
+        }
+    }
+
+    pub unsafe fn modify_mapped_page(&mut self, page: Page) -> Result<(), PagingError> {
+        let total_memory_pages = crate::memory::falloc::get().total_memory(None) / 0x1000;
+        self.map_range(&page, &Frame::null(), total_memory_pages)?;
+
         self.mapped_page = page;
+        Ok(())
     }
 
     pub unsafe fn swap_into(&self) {
-        crate::registers::CR3::write(&self.pml4_frame, crate::registers::CR3Flags::empty());
+        crate::registers::CR3::write(&self.root_frame, crate::registers::CR3Flags::empty());
     }
 
     /* MISC */
@@ -165,25 +996,40 @@ impl VirtualAddressor {
     #[cfg(debug_assertions)]
     pub unsafe fn pretty_log(&self) {
         let offset = self.mapped_page.addr();
-        let pml4 = self.pml4();
 
-        info!("PML4");
+        if self.five_level {
+            let pml5 = self.pml5();
+
+            info!("PML5");
+            for (p5_index, p5_entry) in pml5.iter().enumerate().filter(|tuple| tuple.1.is_present())
+            {
+                info!("5 {:?}", p5_entry);
+                Self::pretty_log_pml4(pml5.sub_table(p5_index, offset).unwrap(), offset, "  ");
+            }
+        } else {
+            info!("PML4");
+            Self::pretty_log_pml4(self.root_pml4(), offset, "");
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    unsafe fn pretty_log_pml4(pml4: &PageTable<Level4>, offset: Address<Virtual>, indent: &str) {
         for (p4_index, p4_entry) in pml4.iter().enumerate().filter(|tuple| tuple.1.is_present()) {
-            info!("4 {:?}", p4_entry);
+            info!("{}4 {:?}", indent, p4_entry);
 
             let p3 = pml4.sub_table(p4_index, offset).unwrap();
             for (p3_index, p3_entry) in p3.iter().enumerate().filter(|tuple| tuple.1.is_present()) {
-                info!("  3 {:?}", p3_entry);
+                info!("{}  3 {:?}", indent, p3_entry);
 
                 let p2 = p3.sub_table(p3_index, offset).unwrap();
                 for (p2_index, p2_entry) in
                     p2.iter().enumerate().filter(|tuple| tuple.1.is_present())
                 {
-                    info!("    2 {:?}", p2_entry);
+                    info!("{}    2 {:?}", indent, p2_entry);
 
                     let p1 = p2.sub_table(p2_index, offset).unwrap();
                     for p1_entry in p1.iter().filter(|entry| entry.is_present()) {
-                        info!("      1 {:?}", p1_entry);
+                        info!("{}      1 {:?}", indent, p1_entry);
                     }
                 }
             }
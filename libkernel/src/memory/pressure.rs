@@ -0,0 +1,154 @@
+//! Memory pressure handling.
+//!
+//! Subsystems holding memory they can release on demand (i.e. caches) register shrinkers,
+//! which allocators invoke when an allocation fails, before failing it for good.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Releases memory under pressure, aiming to free at least `target_frames` frames, and
+///  returns the number of frames it freed.
+///
+/// Shrinkers are invoked from whichever context's allocation failed, which may hold allocator
+///  locks (i.e. a page table allocated while mapping a page), or be the page fault handler. So
+///  they mustn't allocate, and must only try to take any lock an allocator holds.
+pub type Shrinker = fn(target_frames: usize) -> usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShrinkerError {
+    /// The shrinker registry is at capacity.
+    TooManyShrinkers,
+    /// A shrinker with the given name is already registered.
+    AlreadyRegistered(&'static str),
+}
+
+/// Maximum number of shrinkers that can be registered at once.
+const SHRINKER_CAPACITY: usize = 16;
+/// Number of times a failed allocation is retried after shrinkers have freed memory.
+const RECLAIM_ATTEMPTS: usize = 3;
+
+static SHRINKERS: Mutex<[Option<(&'static str, Shrinker)>; SHRINKER_CAPACITY]> =
+    Mutex::new([None; SHRINKER_CAPACITY]);
+/// Set while shrinkers are being invoked before per-CPU data is initialized (when only the
+///  bootstrap processor runs). Afterwards, each CPU has its own flag (see `PerCPU`).
+static SHRINKING: AtomicBool = AtomicBool::new(false);
+/// Number of allocations which failed even after invoking shrinkers.
+static OUT_OF_MEMORY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers a shrinker, which is invoked (in registration order) under memory pressure.
+pub fn register_shrinker(name: &'static str, shrinker: Shrinker) -> Result<(), ShrinkerError> {
+    let mut shrinkers = SHRINKERS.lock();
+
+    if shrinkers
+        .iter()
+        .flatten()
+        .any(|(registered_name, _)| *registered_name == name)
+    {
+        Err(ShrinkerError::AlreadyRegistered(name))
+    } else if let Some(slot) = shrinkers.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some((name, shrinker));
+
+        debug!("Registered memory shrinker: {}", name);
+        Ok(())
+    } else {
+        Err(ShrinkerError::TooManyShrinkers)
+    }
+}
+
+/// Unregisters the shrinker with the given name, returning whether it was registered.
+pub fn unregister_shrinker(name: &'static str) -> bool {
+    match SHRINKERS
+        .lock()
+        .iter_mut()
+        .find(|slot| matches!(slot, Some((registered_name, _)) if *registered_name == name))
+    {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Sets whether the current CPU is invoking shrinkers, returning whether it already was.
+fn swap_shrinking(shrinking: bool) -> bool {
+    match crate::percpu::try_get() {
+        Some(percpu) => percpu.swap_shrinking(shrinking),
+        None => SHRINKING.swap(shrinking, Ordering::AcqRel),
+    }
+}
+
+/// Invokes shrinkers until at least `target_frames` frames have been freed, or every
+///  shrinker has been invoked. Returns the number of frames freed.
+///
+/// An allocation failing within a shrinker doesn't invoke them again on the same CPU. Other
+///  CPUs may invoke them concurrently, so they still reclaim memory rather than failing.
+pub fn shrink(target_frames: usize) -> usize {
+    if swap_shrinking(true) {
+        return 0;
+    }
+
+    // The registry is copied so it isn't locked while shrinkers run.
+    let shrinkers = *SHRINKERS.lock();
+    let mut freed_frames = 0;
+    for (name, shrinker) in shrinkers.iter().flatten() {
+        if freed_frames >= target_frames {
+            break;
+        }
+
+        let shrinker_freed = shrinker(target_frames - freed_frames);
+        trace!("Shrinker '{}' freed {} frames.", name, shrinker_freed);
+        freed_frames += shrinker_freed;
+    }
+
+    swap_shrinking(false);
+
+    debug!(
+        "Memory pressure: shrinkers freed {}/{} frames.",
+        freed_frames, target_frames
+    );
+    freed_frames
+}
+
+/// Attempts an allocation with `alloc`, invoking shrinkers to free `target_frames` frames and
+///  retrying each time it fails, for as long as the shrinkers free memory.
+///
+/// Returns `None` if the allocation still fails.
+pub fn reclaim<T, F: FnMut() -> Option<T>>(target_frames: usize, mut alloc: F) -> Option<T> {
+    let mut allocation = alloc();
+
+    for _ in 0..RECLAIM_ATTEMPTS {
+        if allocation.is_some() || shrink(core::cmp::max(target_frames, 1)) == 0 {
+            break;
+        }
+
+        allocation = alloc();
+    }
+
+    if allocation.is_none() {
+        OUT_OF_MEMORY_COUNT.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Out of memory: allocation of {} frames failed after reclaim.",
+            target_frames
+        );
+    }
+
+    allocation
+}
+
+/// As `reclaim`, for allocations which fail with a null pointer.
+pub fn alloc_with_reclaim<T, F: FnMut() -> *mut T>(target_frames: usize, mut alloc: F) -> *mut T {
+    reclaim(target_frames, || core::ptr::NonNull::new(alloc()))
+        .map_or(core::ptr::null_mut(), core::ptr::NonNull::as_ptr)
+}
+
+/// Locks the next free frame (see `FrameAllocator::lock_next`), invoking shrinkers if there
+///  are none.
+pub fn lock_next_with_reclaim() -> Option<crate::memory::Frame> {
+    reclaim(1, || crate::memory::falloc::get().lock_next())
+}
+
+/// Number of allocations which have failed, even after invoking shrinkers.
+pub fn out_of_memory_count() -> usize {
+    OUT_OF_MEMORY_COUNT.load(Ordering::Relaxed)
+}
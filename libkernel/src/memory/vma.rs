@@ -0,0 +1,413 @@
+use crate::{
+    addr_ty::Virtual,
+    memory::{falloc, malloc, Page},
+    Address, SYSTEM_SLICE_SIZE,
+};
+use spin::RwLock;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+bitflags::bitflags! {
+    pub struct AreaAttributes: u8 {
+        const WRITABLE = 1 << 0;
+        const EXECUTABLE = 1 << 1;
+        const USER_ACCESSIBLE = 1 << 2;
+        const UNCACHEABLE = 1 << 3;
+        /// The first page of the area is left unmapped, to catch overruns (i.e. of a stack).
+        const GUARDED = 1 << 4;
+        /// Pages are backed by zeroed frames on first access, rather than when reserved.
+        const DEMAND_PAGED = 1 << 5;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaOwner {
+    Kernel,
+    Allocator,
+    Stack,
+    MMIO,
+    DMA,
+    PhysicalMap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VMAError {
+    /// Requested area overlaps the existing area starting at the given page.
+    Overlaps(Page),
+    /// No free range of the given page count exists in the reservation window.
+    NoSpace(usize),
+    /// No area starts at the given page.
+    NotReserved(Page),
+    /// The area map is at capacity.
+    TooManyAreas,
+}
+
+/// A named, contiguous range of reserved virtual memory.
+#[derive(Clone, Copy)]
+pub struct Area {
+    start: Page,
+    page_count: usize,
+    name: &'static str,
+    owner: AreaOwner,
+    attribs: AreaAttributes,
+}
+
+impl Area {
+    const fn empty() -> Self {
+        Self {
+            start: Page::null(),
+            page_count: 0,
+            name: "",
+            owner: AreaOwner::Kernel,
+            attribs: AreaAttributes::empty(),
+        }
+    }
+
+    pub const fn start(&self) -> Page {
+        self.start
+    }
+
+    /// First page after the end of the area.
+    pub const fn end(&self) -> Page {
+        self.start.offset(self.page_count)
+    }
+
+    pub const fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub const fn owner(&self) -> AreaOwner {
+        self.owner
+    }
+
+    pub const fn attribs(&self) -> AreaAttributes {
+        self.attribs
+    }
+
+    pub fn contains(&self, addr: Address<Virtual>) -> bool {
+        (self.start.index()..self.end().index()).contains(&Page::containing_addr(addr).index())
+    }
+
+    /// Whether the area permits an access with the given page fault error code.
+    fn permits(&self, error_code: PageFaultErrorCode) -> bool {
+        (!error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            || self.attribs.contains(AreaAttributes::WRITABLE))
+            && (!error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                || self.attribs.contains(AreaAttributes::EXECUTABLE))
+            && (!error_code.contains(PageFaultErrorCode::USER_MODE)
+                || self.attribs.contains(AreaAttributes::USER_ACCESSIBLE))
+    }
+
+    fn overlaps(&self, start: Page, page_count: usize) -> bool {
+        start.index() < self.end().index() && self.start.index() < (start.index() + page_count)
+    }
+}
+
+impl core::fmt::Debug for Area {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        formatter
+            .debug_struct("Area")
+            .field("Name", &self.name)
+            .field("Start", &self.start.addr())
+            .field("End", &self.end().addr())
+            .field("Owner", &self.owner)
+            .field("Attributes", &self.attribs)
+            .finish()
+    }
+}
+
+/// Maximum number of areas that can be reserved at once.
+const AREA_CAPACITY: usize = 128;
+
+/// First page of the kernel half (the upper canonical half) of the address space, with
+///  4-level paging.
+///
+/// With 5-level paging the kernel half begins lower, but everything from here up is canonical
+///  with either depth, so areas (i.e. the physical memory map) are placed the same way.
+pub const KERNEL_HALF_START: Page = Page::from_index(0xFFFF_8000_0000_0000 / 0x1000);
+/// First page of the window from which areas are reserved, when not placed explicitly.
+pub const WINDOW_START: Page = KERNEL_HALF_START.offset(SYSTEM_SLICE_SIZE / 0x1000);
+/// Page after the end of the reservation window (the top 2GiB of the address space are excluded).
+pub const WINDOW_END: Page = Page::from_index(0xFFFF_FFFF_8000_0000 / 0x1000);
+
+/// Areas, sorted by their starting page.
+///
+/// This is fixed-size so areas can be reserved before (and on behalf of) the kernel heap.
+struct AreaMap {
+    areas: [Area; AREA_CAPACITY],
+    len: usize,
+}
+
+impl AreaMap {
+    const fn new() -> Self {
+        Self {
+            areas: [Area::empty(); AREA_CAPACITY],
+            len: 0,
+        }
+    }
+
+    fn as_slice(&self) -> &[Area] {
+        &self.areas[..self.len]
+    }
+
+    fn insert(&mut self, area: Area) -> Result<Area, VMAError> {
+        if self.len == AREA_CAPACITY {
+            return Err(VMAError::TooManyAreas);
+        } else if let Some(overlapped) = self
+            .as_slice()
+            .iter()
+            .find(|existing| existing.overlaps(area.start(), area.page_count()))
+        {
+            return Err(VMAError::Overlaps(overlapped.start()));
+        }
+
+        let index = self
+            .as_slice()
+            .iter()
+            .position(|existing| existing.start().index() > area.start().index())
+            .unwrap_or(self.len);
+
+        self.areas.copy_within(index..self.len, index + 1);
+        self.areas[index] = area;
+        self.len += 1;
+
+        Ok(area)
+    }
+
+    fn remove(&mut self, start: Page) -> Result<Area, VMAError> {
+        match self
+            .as_slice()
+            .iter()
+            .position(|area| area.start() == start)
+        {
+            Some(index) => {
+                let area = self.areas[index];
+                self.areas.copy_within((index + 1)..self.len, index);
+                self.len -= 1;
+
+                Ok(area)
+            }
+            None => Err(VMAError::NotReserved(start)),
+        }
+    }
+
+    /// First-fit search of the reservation window for a free, aligned range of pages.
+    fn find_free(&self, page_count: usize, page_alignment: usize) -> Option<Page> {
+        let mut candidate = crate::align_up(WINDOW_START.index(), page_alignment);
+
+        for area in self
+            .as_slice()
+            .iter()
+            .filter(|area| area.end().index() > WINDOW_START.index())
+        {
+            if (candidate + page_count) <= area.start().index() {
+                break;
+            }
+
+            candidate = crate::align_up(
+                core::cmp::max(candidate, area.end().index()),
+                page_alignment,
+            );
+        }
+
+        if (candidate + page_count) <= WINDOW_END.index() {
+            Some(Page::from_index(candidate))
+        } else {
+            None
+        }
+    }
+}
+
+static AREAS: RwLock<AreaMap> = RwLock::new(AreaMap::new());
+
+/// Reserves an area of `page_count` pages from the reservation window, with its
+///  starting page aligned to `page_alignment` pages.
+pub fn reserve(
+    page_count: usize,
+    page_alignment: usize,
+    name: &'static str,
+    owner: AreaOwner,
+    attribs: AreaAttributes,
+) -> Result<Area, VMAError> {
+    assert!(page_count > 0, "area reservations must be nonzero");
+    assert!(
+        page_alignment.is_power_of_two(),
+        "area alignment must be a power of two"
+    );
+
+    let mut areas = AREAS.write();
+    let start = areas
+        .find_free(page_count, page_alignment)
+        .ok_or(VMAError::NoSpace(page_count))?;
+    let area = areas.insert(Area {
+        start,
+        page_count,
+        name,
+        owner,
+        attribs,
+    })?;
+
+    debug!("Reserved virtual memory area: {:?}", area);
+    Ok(area)
+}
+
+/// Reserves an area of `page_count` pages, starting explicitly at `start`.
+pub fn reserve_at(
+    start: Page,
+    page_count: usize,
+    name: &'static str,
+    owner: AreaOwner,
+    attribs: AreaAttributes,
+) -> Result<Area, VMAError> {
+    assert!(page_count > 0, "area reservations must be nonzero");
+
+    let area = AREAS.write().insert(Area {
+        start,
+        page_count,
+        name,
+        owner,
+        attribs,
+    })?;
+
+    debug!("Reserved virtual memory area: {:?}", area);
+    Ok(area)
+}
+
+/// Releases the area starting at `start`.
+///
+/// This does not unmap any of the area's pages; that is the owner's responsibility.
+pub fn release(start: Page) -> Result<Area, VMAError> {
+    let area = AREAS.write().remove(start)?;
+
+    debug!("Released virtual memory area: {:?}", area);
+    Ok(area)
+}
+
+/// The area containing the given address, if any.
+pub fn find(addr: Address<Virtual>) -> Option<Area> {
+    find_in(&AREAS.read(), addr)
+}
+
+/// The area containing the given address, if any, or `None` if the area map is locked.
+///
+/// Exception handlers use this, as the code they interrupted may hold the lock.
+pub fn try_find(addr: Address<Virtual>) -> Option<Option<Area>> {
+    AREAS.try_read().map(|areas| find_in(&areas, addr))
+}
+
+fn find_in(areas: &AreaMap, addr: Address<Virtual>) -> Option<Area> {
+    areas
+        .as_slice()
+        .iter()
+        .find(|area| area.contains(addr))
+        .copied()
+}
+
+/// Number of attempts the page fault handler makes at taking a lock, before giving up on
+///  resolving the fault.
+const DEMAND_PAGE_LOCK_ATTEMPTS: usize = 0x10_0000;
+
+/// Retries `func` (which attempts to take a lock) until it succeeds, or the attempts run out.
+///
+/// The page fault handler runs with interrupts disabled, so it can't spin on a lock unboundedly:
+///  if the faulting CPU itself holds the lock, it'd never be released.
+fn retry_lock<T, F: FnMut() -> Option<T>>(mut func: F) -> Option<T> {
+    for _ in 0..DEMAND_PAGE_LOCK_ATTEMPTS {
+        if let Some(value) = func() {
+            return Some(value);
+        }
+
+        core::hint::spin_loop();
+    }
+
+    None
+}
+
+/// Attempts to resolve a page fault at `addr` by backing its page with a zeroed frame.
+///
+/// Returns `false` (i.e. the fault is genuine) if the page was present, the address isn't
+///  within a demand-paged area, or the access isn't permitted by the area's attributes.
+///
+/// This needs the area map's lock and the default allocator's addressor lock. It only tries to
+///  take them (for a bounded time), so a fault while the faulting CPU holds either is reported
+///  rather than deadlocking. Page faults are handled on their own stack (see
+///  `gdt::PAGE_FAULT_IST_INDEX`), so stacks may be demand paged.
+pub fn demand_page(addr: Address<Virtual>, error_code: PageFaultErrorCode) -> bool {
+    if error_code
+        .intersects(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::MALFORMED_TABLE)
+    {
+        return false;
+    }
+
+    let area = match retry_lock(|| try_find(addr)) {
+        Some(Some(area)) if area.attribs().contains(AreaAttributes::DEMAND_PAGED) => area,
+        Some(_) => return false,
+        None => {
+            error!("Area map stayed locked while demand paging: {:?}", addr);
+            return false;
+        }
+    };
+
+    let page = &mut Page::containing_addr(addr);
+    if !area.permits(error_code)
+        || (area.attribs().contains(AreaAttributes::GUARDED) && *page == area.start())
+    {
+        return false;
+    }
+
+    let (frame, zeroed) = match falloc::get().lock_zeroed() {
+        Some(frame) => (frame, true),
+        None => match crate::memory::pressure::lock_next_with_reclaim() {
+            Some(frame) => (frame, false),
+            None => {
+                error!("Out of frames to back demand-paged area: {:?}", area);
+                return false;
+            }
+        },
+    };
+
+    trace!("Demand paging {:?} in area: {:?}", page, area);
+    match retry_lock(|| unsafe { malloc::get().try_map_page(page, &frame) }) {
+        Some(Ok(())) => {
+            if !zeroed {
+                unsafe { page.clear() };
+            }
+
+            true
+        }
+        Some(Err(paging_error)) => {
+            error!("Failed to demand page {:?}: {:?}", page, paging_error);
+            unsafe { falloc::get().free_frame(frame).unwrap() };
+            false
+        }
+        None => {
+            error!("Addressor stayed locked while demand paging: {:?}", page);
+            unsafe { falloc::get().free_frame(frame).unwrap() };
+            false
+        }
+    }
+}
+
+/// Calls `func` with every reserved area, in ascending address order.
+pub fn for_each_area<F: FnMut(&Area)>(func: F) {
+    AREAS.read().as_slice().iter().for_each(func);
+}
+
+/// Logs the current virtual memory layout.
+pub fn log_layout() {
+    info!("VIRTUAL MEMORY LAYOUT");
+    info!("---------------------");
+    for_each_area(|area| {
+        info!(
+            "{:#018X}..{:#018X} {:>24} {:?} {:?}",
+            area.start().addr().as_usize(),
+            area.end().addr().as_usize(),
+            area.name(),
+            area.owner(),
+            area.attribs()
+        );
+    });
+}
@@ -0,0 +1,351 @@
+//! Per-CPU data, reached through the GS segment base.
+//!
+//! Each CPU allocates its `PerCPU` block with `init`, which writes the block's address to
+//! `IA32_GS_BASE`. The block's first field points to itself, so the current CPU's block is
+//! found with a single `gs`-relative load (which can't be split by a migration between CPUs).
+//!
+//! `IA32_KERNEL_GS_BASE` is zeroed, holding the (future) user-mode GS base. Entry points from
+//! user mode must `swapgs` before touching per-CPU data, and again before returning.
+
+use crate::{memory::paging::AddressSpace, registers::MSR, structures::apic::APIC};
+use alloc::boxed::Box;
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering},
+};
+
+/// Maximum number of CPUs which can have per-CPU data.
+pub const MAX_CPUS: usize = 256;
+
+static NEXT_CPU_ID: AtomicU32 = AtomicU32::new(0);
+/// Whether the bootstrap processor has initialized its per-CPU data. Until then, its GS base
+///  is whatever the firmware left, so isn't read.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+/// A null self-pointer, which application processors' GS base points to until they initialize
+///  their per-CPU data (see `init_null`).
+static NULL_PERCPU: usize = 0;
+
+const EMPTY_CPU: AtomicPtr<PerCPU> = AtomicPtr::new(core::ptr::null_mut());
+static CPUS: [AtomicPtr<PerCPU>; MAX_CPUS] = [EMPTY_CPU; MAX_CPUS];
+
+/// Event counters of a single CPU.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// External interrupts dispatched on the CPU.
+    pub interrupts: AtomicUsize,
+    /// Ticks of the CPU's timer (the PIT's, until the local APIC timer replaces it), which the
+    ///  kernel measures time on the CPU by.
+    pub timer_ticks: AtomicUsize,
+    /// TLB shootdowns requested of the CPU by other CPUs.
+    pub tlb_shootdowns: AtomicUsize,
+}
+
+#[repr(C)]
+pub struct PerCPU {
+    /// Address of the block itself, which must remain the first field.
+    #[allow(dead_code)]
+    this: *const PerCPU,
+    cpu_id: u32,
+    apic_id: u32,
+    /// NUMA node of the CPU, or `usize::MAX` until `numa::current_node` looks it up.
+    numa_node: AtomicUsize,
+    online: AtomicBool,
+    current_task: AtomicPtr<()>,
+    /// Address space the CPU last switched to, or null for the kernel's.
+    address_space: AtomicPtr<AddressSpace>,
+    interrupt_depth: AtomicUsize,
+    counters: Counters,
+    /// Whether another CPU has requested a TLB shootdown the CPU hasn't yet performed.
+    shootdown_requested: AtomicBool,
+    /// Bounds (as page indexes, the end exclusive) of pages modified on the CPU which other
+    ///  CPUs haven't yet been asked to invalidate (see `tlb::flush_shootdowns`).
+    deferred_shootdown_start: AtomicUsize,
+    deferred_shootdown_end: AtomicUsize,
+    /// Context generation the CPU last invalidated every process-context identifier at (see
+    ///  `tlb::invalidate_stale_contexts`).
+    context_generation: AtomicUsize,
+    /// Whether the CPU is invoking memory shrinkers (see `pressure::shrink`).
+    shrinking: AtomicBool,
+    /// Only accessed from the CPU the block belongs to (through `apic::local_apic_mut`).
+    local_apic: UnsafeCell<Option<APIC>>,
+}
+
+// Every field but the local APIC is atomic or immutable, and the local APIC is only accessed
+//  by its own CPU.
+unsafe impl Sync for PerCPU {}
+
+impl PerCPU {
+    /// Dense index of the CPU, in the order CPUs were brought up (the bootstrap processor's
+    ///  is 0).
+    pub fn cpu_id(&self) -> u32 {
+        self.cpu_id
+    }
+
+    /// (x2)APIC ID of the CPU's local APIC.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// NUMA node of the CPU, if `numa::current_node` has looked it up.
+    pub fn numa_node(&self) -> Option<usize> {
+        match self.numa_node.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            node => Some(node),
+        }
+    }
+
+    pub(crate) fn set_numa_node(&self, node: usize) {
+        self.numa_node.store(node, Ordering::Relaxed);
+    }
+
+    /// Whether the CPU is online, meaning it's able to receive interrupts from other CPUs (i.e.
+    ///  TLB shootdowns).
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// Marks the CPU as online, once its local APIC is enabled. The CPU should then invalidate
+    ///  its TLB, since it won't have been included in any shootdown before.
+    pub fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+
+    /// Task running on the CPU, or null if there is none.
+    pub fn current_task(&self) -> *mut () {
+        self.current_task.load(Ordering::Acquire)
+    }
+
+    pub fn set_current_task(&self, task: *mut ()) {
+        self.current_task.store(task, Ordering::Release);
+    }
+
+    /// Address space the CPU is running (see `AddressSpace::switch_to`), or null for the
+    ///  kernel's.
+    pub fn address_space(&self) -> *const AddressSpace {
+        self.address_space.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_address_space(&self, address_space: *const AddressSpace) {
+        self.address_space
+            .store(address_space as *mut AddressSpace, Ordering::Release);
+    }
+
+    /// Number of interrupt handlers the CPU is currently nested within (0 outside of any).
+    pub fn interrupt_depth(&self) -> usize {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth() > 0
+    }
+
+    pub(crate) fn enter_interrupt(&self) {
+        self.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn exit_interrupt(&self) {
+        self.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub(crate) fn request_shootdown(&self) {
+        self.shootdown_requested.store(true, Ordering::Release);
+    }
+
+    /// Clears the CPU's TLB shootdown request, returning whether there was one.
+    pub(crate) fn take_shootdown_request(&self) -> bool {
+        self.shootdown_requested.swap(false, Ordering::AcqRel)
+    }
+
+    /// Extends the CPU's deferred shootdown to cover the pages `start..end`.
+    pub(crate) fn defer_shootdown(&self, start: usize, end: usize) {
+        self.deferred_shootdown_start
+            .fetch_min(start, Ordering::AcqRel);
+        self.deferred_shootdown_end.fetch_max(end, Ordering::AcqRel);
+    }
+
+    /// Clears the CPU's deferred shootdown, returning its bounds if there was one.
+    pub(crate) fn take_deferred_shootdown(&self) -> Option<(usize, usize)> {
+        // Interrupts are disabled so a handler can't defer a shootdown between the swaps.
+        crate::instructions::interrupts::without_interrupts(|| {
+            let start = self
+                .deferred_shootdown_start
+                .swap(usize::MAX, Ordering::AcqRel);
+            let end = self.deferred_shootdown_end.swap(0, Ordering::AcqRel);
+
+            if start < end {
+                Some((start, end))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Records the context generation the CPU has invalidated up to, returning the previous.
+    pub(crate) fn swap_context_generation(&self, generation: usize) -> usize {
+        self.context_generation.swap(generation, Ordering::AcqRel)
+    }
+
+    /// Sets whether the CPU is invoking memory shrinkers, returning whether it already was.
+    pub(crate) fn swap_shrinking(&self, shrinking: bool) -> bool {
+        self.shrinking.swap(shrinking, Ordering::AcqRel)
+    }
+
+    pub(crate) fn local_apic(&self) -> Option<&APIC> {
+        unsafe { (*self.local_apic.get()).as_ref() }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) fn local_apic_mut(&self) -> Option<&mut APIC> {
+        unsafe { (*self.local_apic.get()).as_mut() }
+    }
+
+    pub(crate) fn set_local_apic(&self, apic: APIC) {
+        unsafe { *self.local_apic.get() = Some(apic) };
+    }
+}
+
+impl core::fmt::Debug for PerCPU {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        formatter
+            .debug_struct("PerCPU")
+            .field("CPU ID", &self.cpu_id())
+            .field("APIC ID", &self.apic_id())
+            .field("Online", &self.is_online())
+            .field("Current Task", &self.current_task())
+            .field("Interrupt Depth", &self.interrupt_depth())
+            .field("Counters", &self.counters)
+            .finish()
+    }
+}
+
+/// Allocates the current CPU's per-CPU block, and points its GS base at it.
+///
+/// This must be called once on each CPU (after the segment registers are loaded, which clears
+///  the GS base), before anything accesses per-CPU data on it.
+pub fn init() -> &'static PerCPU {
+    let cpu_id = NEXT_CPU_ID.fetch_add(1, Ordering::AcqRel);
+    assert!(
+        (cpu_id as usize) < MAX_CPUS,
+        "too many CPUs for per-CPU data"
+    );
+
+    let percpu = Box::leak(Box::new(PerCPU {
+        this: core::ptr::null(),
+        cpu_id,
+        apic_id: crate::instructions::apic_id(),
+        numa_node: AtomicUsize::new(usize::MAX),
+        online: AtomicBool::new(false),
+        current_task: AtomicPtr::new(core::ptr::null_mut()),
+        address_space: AtomicPtr::new(core::ptr::null_mut()),
+        interrupt_depth: AtomicUsize::new(0),
+        counters: Counters::default(),
+        shootdown_requested: AtomicBool::new(false),
+        deferred_shootdown_start: AtomicUsize::new(usize::MAX),
+        deferred_shootdown_end: AtomicUsize::new(0),
+        // Ensures the first switch of address space invalidates everything.
+        context_generation: AtomicUsize::new(usize::MAX),
+        shrinking: AtomicBool::new(false),
+        local_apic: UnsafeCell::new(None),
+    }));
+    let this = percpu as *mut PerCPU;
+    percpu.this = this;
+
+    unsafe {
+        MSR::IA32_GS_BASE.write(this as u64);
+        MSR::IA32_KERNEL_GS_BASE.write(0);
+    }
+
+    CPUS[cpu_id as usize].store(this, Ordering::Release);
+    INITIALIZED.store(true, Ordering::Release);
+
+    debug!("Initialized per-CPU data: {:?}", percpu);
+    percpu
+}
+
+/// Points the current CPU's GS base at a null self-pointer, for which `try_get` returns `None`.
+///
+/// Application processors call this (after loading their segment registers, which clears the
+///  GS base) before anything else, so they can't read per-CPU data through a zero GS base
+///  before calling `init`.
+pub fn init_null() {
+    unsafe { MSR::IA32_GS_BASE.write(&NULL_PERCPU as *const usize as u64) };
+}
+
+/// The current CPU's per-CPU block.
+pub fn get() -> &'static PerCPU {
+    try_get().expect("per-CPU data hasn't been initialized")
+}
+
+/// The current CPU's per-CPU block, or `None` before the current CPU has initialized it.
+///
+/// This is on every interrupt's path, so the GS base isn't read (`rdmsr` is serializing).
+///  Instead, the bootstrap processor's is only used once it's initialized, and application
+///  processors' point to a null self-pointer until they're initialized (see `init_null`).
+pub fn try_get() -> Option<&'static PerCPU> {
+    if INITIALIZED.load(Ordering::Acquire) {
+        let this: *const PerCPU;
+
+        unsafe {
+            asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags));
+            this.as_ref()
+        }
+    } else {
+        None
+    }
+}
+
+/// Per-CPU block of the CPU with the given ID, if it's been initialized.
+pub fn cpu(cpu_id: u32) -> Option<&'static PerCPU> {
+    CPUS.get(cpu_id as usize)
+        .and_then(|cpu| unsafe { cpu.load(Ordering::Acquire).as_ref() })
+}
+
+/// Per-CPU blocks of every initialized CPU, ordered by CPU ID.
+pub fn iter() -> impl Iterator<Item = &'static PerCPU> {
+    (0..NEXT_CPU_ID.load(Ordering::Acquire)).filter_map(cpu)
+}
+
+/// Number of CPUs online (see `PerCPU::is_online`).
+pub fn online_count() -> usize {
+    iter().filter(|percpu| percpu.is_online()).count()
+}
+
+/// Logs the counters of every CPU.
+pub fn log_counters() {
+    for percpu in iter() {
+        debug!(
+            "CPU {} (APIC {}): {} interrupts, {} timer ticks, {} TLB shootdowns",
+            percpu.cpu_id(),
+            percpu.apic_id(),
+            percpu.counters().interrupts.load(Ordering::Relaxed),
+            percpu.counters().timer_ticks.load(Ordering::Relaxed),
+            percpu.counters().tlb_shootdowns.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Reads a field of the current CPU's per-CPU block, i.e. `percpu!(cpu_id)`.
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        $crate::percpu::get().$field()
+    };
+}
+
+/// Increments one of the current CPU's counters, i.e. `percpu_count!(timer_ticks)`. This does
+///  nothing before per-CPU data is initialized.
+#[macro_export]
+macro_rules! percpu_count {
+    ($counter:ident) => {
+        if let Some(percpu) = $crate::percpu::try_get() {
+            percpu
+                .counters()
+                .$counter
+                .fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        }
+    };
+}
@@ -0,0 +1,34 @@
+bitflags::bitflags! {
+    pub struct CR0Flags : usize {
+        const PE = 1 << 0;
+        const MP = 1 << 1;
+        const EM = 1 << 2;
+        const TS = 1 << 3;
+        const ET = 1 << 4;
+        const NE = 1 << 5;
+        const WP = 1 << 16;
+        const AM = 1 << 18;
+        const NW = 1 << 29;
+        const CD = 1 << 30;
+        const PG = 1 << 31;
+    }
+}
+
+pub struct CR0;
+
+impl CR0 {
+    pub fn read() -> CR0Flags {
+        let value: usize;
+
+        unsafe {
+            asm!("mov {}, cr0", out(reg) value, options(nomem, nostack));
+        }
+
+        // Bits unknown to `CR0Flags` are retained, so writing the value back preserves them.
+        unsafe { CR0Flags::from_bits_unchecked(value) }
+    }
+
+    pub unsafe fn write(flags: CR0Flags) {
+        asm!("mov cr0, {}", in(reg) flags.bits(), options(nostack));
+    }
+}
@@ -12,6 +12,20 @@ impl CR3 {
         asm!("mov cr3, {}", in(reg) frame.addr().as_usize() | flags.bits(), options(nostack));
     }
 
+    /// Writes the frame to CR3, tagged with the given process-context identifier.
+    ///
+    /// If `preserve_tlb` is set, TLB entries tagged with the identifier aren't flushed.
+    ///
+    /// Safety: requires CR4.PCIDE to be set.
+    pub unsafe fn write_pcid(frame: &crate::memory::Frame, pcid: u16, preserve_tlb: bool) {
+        const NO_FLUSH: usize = 1 << 63;
+
+        debug_assert!(pcid < 0x1000, "process-context identifiers are 12 bits");
+        let value =
+            frame.addr().as_usize() | (pcid as usize) | if preserve_tlb { NO_FLUSH } else { 0 };
+        asm!("mov cr3, {}", in(reg) value, options(nostack));
+    }
+
     pub fn read() -> CR3Flags {
         let value: usize;
 
@@ -22,6 +36,18 @@ impl CR3 {
         CR3Flags::from_bits_truncate(value)
     }
 
+    /// The frame of the active PML4.
+    pub fn read_frame() -> crate::memory::Frame {
+        let value: usize;
+
+        unsafe {
+            asm!("mov {}, cr3", out(reg) value, options(nostack));
+            crate::memory::Frame::from_addr(crate::Address::<crate::addr_ty::Physical>::new(
+                value & 0x000FFFFF_FFFFF000,
+            ))
+        }
+    }
+
     pub fn refresh() {
         let value: usize;
 
@@ -1,10 +1,14 @@
+mod cr0;
 mod cr2;
 mod cr3;
+mod cr4;
 mod flags;
 mod msr;
 
 pub mod stack;
+pub use cr0::*;
 pub use cr2::*;
 pub use cr3::*;
+pub use cr4::*;
 pub use flags::*;
 pub use msr::*;
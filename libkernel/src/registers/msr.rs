@@ -3,7 +3,12 @@
 #[allow(non_camel_case_types)]
 pub enum MSR {
     IA32_APIC_BASE = 0x1B,
+    IA32_PAT = 0x277,
     IA32_X2APIC_APICID = 2050,
+    IA32_EFER = 0xC0000080,
+    IA32_GS_BASE = 0xC0000101,
+    /// Holds the GS base to be swapped in by `swapgs`.
+    IA32_KERNEL_GS_BASE = 0xC0000102,
 }
 
 impl MSR {
@@ -13,32 +18,35 @@ impl MSR {
             "CPU does not support use of model-specific registers"
         );
 
-        let low: u64;
-        let high: u64;
-
-        unsafe {
-            asm!(
-                "mov ecx, {:e}",
-                "rdmsr",
-                "mov {}, rax",
-                "mov {}, rdx",
-                in(reg) self as u32,
-                out(reg) low,
-                out(reg) high,
-                options(nomem)
-            );
-        }
-
-        (high << 32) | low
+        unsafe { Self::read_raw(self as u32) }
+    }
+
+    /// Reads the model-specific register at the given address (i.e. one of a range of
+    ///  registers, like the x2APIC's).
+    ///
+    /// SAFETY: The CPU must support model-specific registers, and the register must exist.
+    pub unsafe fn read_raw(address: u32) -> u64 {
+        let low: u32;
+        let high: u32;
+
+        asm!(
+            "rdmsr",
+            in("ecx") address,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack)
+        );
+
+        ((high as u64) << 32) | (low as u64)
     }
 
     pub unsafe fn write_bit(self, bit: usize, set: bool) {
-        assert!(bit <= 64, "bit must be within u64");
+        assert!(bit < 64, "bit must be within u64");
 
         let bit_mask = 1 << bit;
         let set_bit = (set as u64) << bit;
 
-        self.write((self.read() & bit_mask) | set_bit);
+        self.write((self.read() & !bit_mask) | set_bit);
 
         debug_assert_eq!(self.read() & bit_mask, set_bit);
     }
@@ -63,15 +71,19 @@ impl MSR {
             "CPU does not support use of model-specific registers"
         );
 
+        Self::write_raw(self as u32, value);
+    }
+
+    /// Writes the model-specific register at the given address.
+    ///
+    /// SAFETY: The CPU must support model-specific registers, and the register must exist.
+    pub unsafe fn write_raw(address: u32, value: u64) {
         asm!(
-            "mov ecx, {:e}",
-            "mov eax, {:e}",
-            "mov edx, {:e}",
             "wrmsr",
-            in(reg) self as u32,
-            in(reg) value as u32,
-            in(reg) (value >> 32) as u32,
-            options(nomem)
+            in("ecx") address,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nomem, nostack)
         );
     }
 }
@@ -1,5 +1,7 @@
-use core::marker::PhantomData;
-use spin::RwLock;
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 pub trait BitValue: Eq {
     const BIT_WIDTH: usize;
@@ -9,12 +11,19 @@ pub trait BitValue: Eq {
     fn from_usize(value: usize) -> Self;
 }
 
+/// Array of densely packed bit values, which can be read and modified concurrently.
+///
+/// Each element is modified with a compare-exchange loop on the section containing it, so
+///  concurrent modifications never block each other (they only retry when they race on the
+///  same section).
 pub struct RwBitArray<'arr, BV>
 where
     BV: BitValue,
 {
-    array: RwLock<&'arr mut [usize]>,
+    array: &'arr [AtomicUsize],
     element_count: usize,
+    /// Element at which `set_eq_next` begins its search (the element it last set).
+    next_hint: AtomicUsize,
     phantom: PhantomData<BV>,
 }
 
@@ -33,8 +42,11 @@ impl<'arr, BV: BitValue> RwBitArray<'arr, BV> {
         slice.fill(0);
 
         Self {
-            array: RwLock::new(slice),
+            // `AtomicUsize` has the same in-memory representation as `usize`, and the mutable
+            //  borrow guarantees there are no other references to the slice.
+            array: unsafe { &*(slice as *mut [usize] as *const [AtomicUsize]) },
             element_count,
+            next_hint: AtomicUsize::new(0),
             phantom: PhantomData,
         }
     }
@@ -63,7 +75,7 @@ impl<'arr, BV: BitValue> RwBitArray<'arr, BV> {
         );
 
         let (section_index, section_offset) = Self::get_index_and_offset(index);
-        let section_value = self.array.read()[section_index];
+        let section_value = self.array[section_index].load(Ordering::Acquire);
 
         BV::from_usize((section_value >> section_offset) & BV::MASK)
     }
@@ -77,12 +89,13 @@ impl<'arr, BV: BitValue> RwBitArray<'arr, BV> {
         );
 
         let (section_index, section_offset) = Self::get_index_and_offset(index);
-        let sections_read = self.array.upgradeable_read();
-        let section_value = sections_read[section_index];
-
         let section_bits_set = new_type.as_usize() << section_offset;
-        let section_bits_nonset = section_value & !(BV::MASK << section_offset);
-        sections_read.upgrade()[section_index] = section_bits_set | section_bits_nonset;
+
+        self.array[section_index]
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |section_value| {
+                Some(section_bits_set | (section_value & !(BV::MASK << section_offset)))
+            })
+            .ok();
     }
 
     pub fn set_eq(&self, index: usize, new_type: BV, eq_type: BV) -> bool {
@@ -93,41 +106,114 @@ impl<'arr, BV: BitValue> RwBitArray<'arr, BV> {
             self.len()
         );
 
-        {
-            let (section_index, section_offset) = Self::get_index_and_offset(index);
-            let sections_read = self.array.upgradeable_read();
-            let section_value = sections_read[section_index];
-            let type_actual = BV::from_usize((section_value >> section_offset) & BV::MASK);
-
-            if type_actual != eq_type {
-                return false;
-            }
-
-            let section_bits_set = new_type.as_usize() << section_offset;
-            let section_bits_nonset = section_value & !(BV::MASK << section_offset);
-            sections_read.upgrade()[section_index] = section_bits_set | section_bits_nonset;
-        }
-
-        true
+        let (section_index, section_offset) = Self::get_index_and_offset(index);
+        let section_bits_set = new_type.as_usize() << section_offset;
+        let eq_type_usize = eq_type.as_usize();
+
+        self.array[section_index]
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |section_value| {
+                if ((section_value >> section_offset) & BV::MASK) == eq_type_usize {
+                    Some(section_bits_set | (section_value & !(BV::MASK << section_offset)))
+                } else {
+                    None
+                }
+            })
+            .is_ok()
     }
 
     const ELEMENTS_PER_SECTION: usize = Self::SECTION_LEN / BV::BIT_WIDTH;
+
+    /// Sets the first element equal to `eq_type` to `new_type`, returning its index.
+    ///
+    /// This is a next-fit search: it begins at the element the previous call set, wrapping
+    ///  around to the start of the array.
     pub fn set_eq_next(&self, new_type: BV, eq_type: BV) -> Option<usize> {
-        let new_type_usize = new_type.as_usize();
+        let index = self.set_eq_next_in(
+            new_type,
+            eq_type,
+            0..self.len(),
+            self.next_hint.load(Ordering::Relaxed),
+        )?;
+        self.next_hint.store(index, Ordering::Relaxed);
+
+        Some(index)
+    }
 
-        for (index, section) in self.array.write().iter_mut().enumerate() {
-            let section_deref = *section;
+    /// Sets the first element within `range` equal to `eq_type` to `new_type`, returning its
+    ///  index.
+    ///
+    /// The search begins at the element `hint` (if it's within `range`), wrapping around to
+    ///  the start of the range, so callers can track their own next-fit position.
+    pub fn set_eq_next_in(
+        &self,
+        new_type: BV,
+        eq_type: BV,
+        range: core::ops::Range<usize>,
+        hint: usize,
+    ) -> Option<usize> {
+        assert!(
+            range.end <= self.len(),
+            "range must be within the collection ({} > {})",
+            range.end,
+            self.len()
+        );
 
-            for offset in (0..64).step_by(BV::BIT_WIDTH) {
-                let offset_bits = section_deref & (BV::MASK << offset);
-                if BV::from_usize(offset_bits >> offset) == eq_type {
-                    let section_bits_set = new_type_usize << offset;
-                    let section_bits_nonset = section_deref ^ offset_bits;
+        let new_type_usize = new_type.as_usize();
+        let eq_type_usize = eq_type.as_usize();
+        let hint = if range.contains(&hint) {
+            hint
+        } else {
+            range.start
+        };
+
+        self.set_eq_first(new_type_usize, eq_type_usize, hint..range.end)
+            .or_else(|| self.set_eq_first(new_type_usize, eq_type_usize, range.start..hint))
+    }
 
-                    *section = section_bits_set | section_bits_nonset;
-                    return Some((index * Self::ELEMENTS_PER_SECTION) + (offset / BV::BIT_WIDTH));
+    /// Sets the first element within `range` whose value is `eq_value` to `new_value`.
+    fn set_eq_first(
+        &self,
+        new_value: usize,
+        eq_value: usize,
+        range: core::ops::Range<usize>,
+    ) -> Option<usize> {
+        let mut index = range.start;
+
+        while index < range.end {
+            let section_index = index / Self::ELEMENTS_PER_SECTION;
+            let section_end =
+                core::cmp::min((section_index + 1) * Self::ELEMENTS_PER_SECTION, range.end);
+            let section = &self.array[section_index];
+            let mut section_value = section.load(Ordering::Acquire);
+
+            'section: loop {
+                for element_index in index..section_end {
+                    let offset = (element_index % Self::ELEMENTS_PER_SECTION) * BV::BIT_WIDTH;
+                    if ((section_value >> offset) & BV::MASK) != eq_value {
+                        continue;
+                    }
+
+                    let new_section_value =
+                        (new_value << offset) | (section_value & !(BV::MASK << offset));
+                    match section.compare_exchange_weak(
+                        section_value,
+                        new_section_value,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    ) {
+                        Ok(_) => return Some(element_index),
+                        // the section was modified concurrently, so search it again
+                        Err(actual_value) => {
+                            section_value = actual_value;
+                            continue 'section;
+                        }
+                    }
                 }
+
+                break;
             }
+
+            index = section_end;
         }
 
         None
@@ -163,7 +249,7 @@ impl<'arr, BV: BitValue + core::fmt::Debug> RwBitArray<'arr, BV> {
 }
 
 pub struct RwBitArrayIterator<'lock, 'arr, BV: BitValue> {
-    array: &'lock RwLock<&'arr mut [usize]>,
+    array: &'lock &'arr [AtomicUsize],
     section_index: usize,
     section_offset: usize,
     cur_len: usize,
@@ -176,7 +262,7 @@ impl<'lock, 'arr, BV: BitValue> Iterator for RwBitArrayIterator<'lock, 'arr, BV>
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.cur_len < self.max_len {
-            let section_value = self.array.read()[self.section_index];
+            let section_value = self.array[self.section_index].load(Ordering::Acquire);
             let cur_offset = self.section_offset;
 
             self.cur_len += 1;
@@ -4,6 +4,7 @@ use crate::{
         xsdt::{XSDTEntry, XSDTEntryType},
         ACPITable, SDTHeader, UnsizedACPITable,
     },
+    structures::ioapic::{InterruptPolarity, TriggerMode},
     Address,
 };
 
@@ -133,6 +134,21 @@ pub struct LocalAPIC {
     flags: LocalAPICFlags,
 }
 
+impl LocalAPIC {
+    /// ACPI processor UID of the processor the local APIC belongs to.
+    pub fn processor_id(&self) -> u8 {
+        self.processor_id
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn flags(&self) -> LocalAPICFlags {
+        self.flags
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct IOAPIC {
@@ -152,22 +168,8 @@ impl IOAPIC {
         Address::<Physical>::new(self.addr as usize)
     }
 
-    pub fn read(&self, register: u8) -> u32 {
-        unsafe {
-            let ioapic_ptr = self.register_base().as_usize() as *mut u32;
-
-            ioapic_ptr.write_volatile(register as u32);
-            ioapic_ptr.offset(4).read_volatile()
-        }
-    }
-
-    pub fn write(&self, register: u8, value: u32) {
-        unsafe {
-            let ioapic_ptr = self.register_base().as_usize() as *mut u32;
-
-            ioapic_ptr.write_volatile(register as u32);
-            ioapic_ptr.offset(4).write_volatile(value);
-        }
+    pub fn global_sys_interrupt_base(&self) -> u32 {
+        self.global_sys_interrupt_base
     }
 }
 
@@ -177,6 +179,10 @@ impl core::fmt::Debug for IOAPIC {
             .debug_struct("IO APIC Device")
             .field("ID", &self.id())
             .field("Register Base", &self.register_base())
+            .field(
+                "Global System Interrupt Base",
+                &self.global_sys_interrupt_base(),
+            )
             .finish()
     }
 }
@@ -191,6 +197,38 @@ pub struct IRQSrcOverride {
     flags: u16,
 }
 
+impl IRQSrcOverride {
+    pub fn bus_src(&self) -> u8 {
+        self.bus_src
+    }
+
+    pub fn irq_src(&self) -> u8 {
+        self.irq_src
+    }
+
+    pub fn global_sys_interrupt(&self) -> u32 {
+        self.global_sys_interrupt
+    }
+
+    /// Polarity of the interrupt, or `None` if it conforms to the specification of the bus.
+    pub fn polarity(&self) -> Option<InterruptPolarity> {
+        match self.flags & 0b11 {
+            0b01 => Some(InterruptPolarity::ActiveHigh),
+            0b11 => Some(InterruptPolarity::ActiveLow),
+            _ => None,
+        }
+    }
+
+    /// Trigger mode of the interrupt, or `None` if it conforms to the specification of the bus.
+    pub fn trigger_mode(&self) -> Option<TriggerMode> {
+        match (self.flags >> 2) & 0b11 {
+            0b01 => Some(TriggerMode::Edge),
+            0b11 => Some(TriggerMode::Level),
+            _ => None,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct NonMaskableIRQ {
@@ -1,11 +1,15 @@
 mod madt;
 mod mcfg;
 mod rdsp;
+mod slit;
+mod srat;
 
 pub mod xsdt;
 pub use madt::*;
 pub use mcfg::*;
 pub use rdsp::*;
+pub use slit::*;
+pub use srat::*;
 
 use crate::structures::GUID;
 
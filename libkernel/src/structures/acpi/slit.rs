@@ -0,0 +1,44 @@
+use crate::structures::acpi::{
+    xsdt::{XSDTEntry, XSDTEntryType},
+    SDTHeader,
+};
+
+/// System Locality Information Table, which describes the relative distance (memory latency)
+///  between each pair of proximity domains.
+pub enum SLIT {}
+impl XSDTEntryType for SLIT {
+    const SIGNATURE: &'static str = &"SLIT";
+}
+
+#[repr(C, packed)]
+struct SLITHeader {
+    sdt_header: SDTHeader,
+    locality_count: u64,
+}
+
+impl XSDTEntry<SLIT> {
+    fn slit_header(&self) -> &SLITHeader {
+        unsafe { &*(self as *const _ as *const _) }
+    }
+
+    /// Number of localities (proximity domains) described by the table.
+    pub fn locality_count(&self) -> usize {
+        self.slit_header().locality_count as usize
+    }
+
+    /// Relative distance from locality `from` to locality `to`, where a locality's distance
+    ///  to itself is normalized to 10.
+    pub fn distance(&self, from: usize, to: usize) -> u8 {
+        let locality_count = self.locality_count();
+        assert!(
+            from < locality_count && to < locality_count,
+            "locality must be within the table"
+        );
+
+        unsafe {
+            (self as *const _ as *const u8)
+                .add(core::mem::size_of::<SLITHeader>() + (from * locality_count) + to)
+                .read()
+        }
+    }
+}
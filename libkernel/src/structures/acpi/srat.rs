@@ -0,0 +1,237 @@
+use crate::{
+    addr_ty::Physical,
+    structures::acpi::{
+        xsdt::{XSDTEntry, XSDTEntryType},
+        SDTHeader, UnsizedACPITable,
+    },
+    Address,
+};
+
+/// System Resource Affinity Table, which associates processors and memory ranges with
+///  proximity domains (NUMA nodes).
+pub enum SRAT {}
+impl XSDTEntryType for SRAT {
+    const SIGNATURE: &'static str = &"SRAT";
+}
+
+#[repr(C)]
+pub struct SRATHeader {
+    sdt_header: SDTHeader,
+    reserved0: u32,
+    reserved1: [u8; 8],
+}
+
+impl XSDTEntry<SRAT> {
+    pub fn iter(&self) -> SRATIterator {
+        SRATIterator {
+            cur_header_ptr: self.first_entry_ptr(),
+            max_header_ptr: unsafe {
+                (self as *const _ as *const u8).add(self.sdt_header().table_len() as usize)
+            },
+            phantom: core::marker::PhantomData,
+        }
+    }
+}
+
+impl UnsizedACPITable<SRATHeader, u8> for XSDTEntry<SRAT> {}
+
+pub struct SRATIterator<'a> {
+    cur_header_ptr: *const u8,
+    max_header_ptr: *const u8,
+    phantom: core::marker::PhantomData<&'a u8>,
+}
+
+impl<'a> Iterator for SRATIterator<'a> {
+    type Item = AffinityEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cur_header_ptr < self.max_header_ptr {
+            unsafe {
+                let header_ptr = self.cur_header_ptr as *const AffinityEntryHeader;
+                let header = &*header_ptr;
+                assert!(header.len > 0, "invalid SRAT entry length");
+                self.cur_header_ptr = self.cur_header_ptr.add(header.len as usize);
+
+                match header.ty {
+                    0x0 => Some(AffinityEntry::LocalAPIC(&*(header_ptr as *const _))),
+                    0x1 => Some(AffinityEntry::Memory(&*(header_ptr as *const _))),
+                    0x2 => Some(AffinityEntry::X2APIC(&*(header_ptr as *const _))),
+                    _ => Some(AffinityEntry::Reserved),
+                }
+            }
+        } else {
+            None
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct AffinityEntryHeader {
+    ty: u8,
+    len: u8,
+}
+
+#[derive(Clone, Copy)]
+pub enum AffinityEntry<'a> {
+    LocalAPIC(&'a LocalAPICAffinity),
+    Memory(&'a MemoryAffinity),
+    X2APIC(&'a X2APICAffinity),
+    Reserved,
+}
+
+impl core::fmt::Debug for AffinityEntry<'_> {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AffinityEntry::LocalAPIC(affinity) => affinity.fmt(formatter),
+            AffinityEntry::Memory(affinity) => affinity.fmt(formatter),
+            AffinityEntry::X2APIC(affinity) => affinity.fmt(formatter),
+            AffinityEntry::Reserved => formatter
+                .debug_tuple("AffinityEntry")
+                .field(&"Unhandled")
+                .finish(),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    pub struct ProcessorAffinityFlags: u32 {
+        const ENABLED = 1 << 0;
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct LocalAPICAffinity {
+    header: AffinityEntryHeader,
+    proximity_domain_low: u8,
+    apic_id: u8,
+    flags: ProcessorAffinityFlags,
+    local_sapic_eid: u8,
+    proximity_domain_high: [u8; 3],
+    clock_domain: u32,
+}
+
+impl LocalAPICAffinity {
+    pub fn proximity_domain(&self) -> u32 {
+        let high = self.proximity_domain_high;
+
+        u32::from_le_bytes([self.proximity_domain_low, high[0], high[1], high[2]])
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+
+    pub fn flags(&self) -> ProcessorAffinityFlags {
+        self.flags
+    }
+
+    pub fn clock_domain(&self) -> u32 {
+        self.clock_domain
+    }
+}
+
+impl core::fmt::Debug for LocalAPICAffinity {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        formatter
+            .debug_struct("Local APIC Affinity")
+            .field("Proximity Domain", &self.proximity_domain())
+            .field("APIC ID", &self.apic_id())
+            .field("Flags", &self.flags())
+            .finish()
+    }
+}
+
+bitflags::bitflags! {
+    pub struct MemoryAffinityFlags: u32 {
+        const ENABLED = 1 << 0;
+        const HOT_PLUGGABLE = 1 << 1;
+        const NON_VOLATILE = 1 << 2;
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct MemoryAffinity {
+    header: AffinityEntryHeader,
+    proximity_domain: u32,
+    reserved0: u16,
+    base_addr: u64,
+    len: u64,
+    reserved1: u32,
+    flags: MemoryAffinityFlags,
+    reserved2: u64,
+}
+
+impl MemoryAffinity {
+    pub fn proximity_domain(&self) -> u32 {
+        self.proximity_domain
+    }
+
+    pub fn base_addr(&self) -> Address<Physical> {
+        Address::<Physical>::new(self.base_addr as usize)
+    }
+
+    /// Length of the memory range, in bytes.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn flags(&self) -> MemoryAffinityFlags {
+        self.flags
+    }
+}
+
+impl core::fmt::Debug for MemoryAffinity {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        formatter
+            .debug_struct("Memory Affinity")
+            .field("Proximity Domain", &self.proximity_domain())
+            .field("Base Address", &self.base_addr())
+            .field("Length", &self.len())
+            .field("Flags", &self.flags())
+            .finish()
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct X2APICAffinity {
+    header: AffinityEntryHeader,
+    reserved0: u16,
+    proximity_domain: u32,
+    x2apic_id: u32,
+    flags: ProcessorAffinityFlags,
+    clock_domain: u32,
+    reserved1: u32,
+}
+
+impl X2APICAffinity {
+    pub fn proximity_domain(&self) -> u32 {
+        self.proximity_domain
+    }
+
+    pub fn x2apic_id(&self) -> u32 {
+        self.x2apic_id
+    }
+
+    pub fn flags(&self) -> ProcessorAffinityFlags {
+        self.flags
+    }
+
+    pub fn clock_domain(&self) -> u32 {
+        self.clock_domain
+    }
+}
+
+impl core::fmt::Debug for X2APICAffinity {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        formatter
+            .debug_struct("x2APIC Affinity")
+            .field("Proximity Domain", &self.proximity_domain())
+            .field("x2APIC ID", &self.x2apic_id())
+            .field("Flags", &self.flags())
+            .finish()
+    }
+}
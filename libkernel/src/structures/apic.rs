@@ -1,57 +1,102 @@
 use crate::{
     addr_ty::Physical,
     cell::SyncOnceCell,
-    memory::mmio::{Mapped, MMIO},
+    memory::mmio::{
+        Mapped, ReadOnlyRegister, ReadWriteRegister, ReadableRegister, RegisterValue,
+        WritableRegister, WriteOnlyRegister, MMIO,
+    },
     registers::MSR,
     Address,
 };
-use core::{intrinsics::transmute, marker::PhantomData};
+use core::marker::PhantomData;
 
-static mut LOCAL_APIC: SyncOnceCell<APIC> = SyncOnceCell::new();
+/// The xAPIC register page, which is mapped once, as each CPU sees its own local APIC's registers
+///  at the same address.
+static XAPIC_MMIO: SyncOnceCell<MMIO<Mapped>> = SyncOnceCell::new();
 
+/// Loads the current CPU's local APIC, in x2APIC mode if the CPU supports it.
 pub fn load() {
-    if unsafe { LOCAL_APIC.get().is_some() } {
+    let percpu = crate::percpu::get();
+
+    if percpu.local_apic().is_some() {
         panic!("Local APIC has already been configured");
+    } else if crate::instructions::cpu_features().contains(crate::instructions::CPUFeatures::X2APIC)
+    {
+        debug!("Loading local APIC in x2APIC mode.");
+        percpu.set_local_apic(unsafe { APIC::new_x2apic() });
     } else {
-        debug!("Loading local APIC table.");
-        let start_index = APIC::mmio_addr().frame_index();
-        debug!("APIC MMIO mapping at frame: {}", start_index);
-
-        let mmio = crate::memory::mmio::unmapped_mmio(unsafe {
-            crate::memory::falloc::get()
-                .acquire_frames(start_index, 1, crate::memory::falloc::FrameState::MMIO)
-                .unwrap()
-        })
-        .unwrap()
-        .map();
+        if XAPIC_MMIO.get().is_none() {
+            debug!("Loading local APIC table.");
+            let start_index = APIC::mmio_addr().frame_index();
+            debug!("APIC MMIO mapping at frame: {}", start_index);
+
+            let mmio = crate::memory::mmio::unmapped_mmio(unsafe {
+                crate::memory::falloc::get()
+                    .acquire_frames(start_index, 1, crate::memory::falloc::FrameState::MMIO)
+                    .unwrap()
+            })
+            .unwrap()
+            .map();
+
+            XAPIC_MMIO.set(mmio).ok();
+        }
 
-        unsafe { LOCAL_APIC.set(APIC::new(mmio)).ok() };
+        // Each CPU only accesses its own local APIC through its handle.
+        percpu.set_local_apic(unsafe { APIC::new(XAPIC_MMIO.get().unwrap().alias()) });
     }
 }
 
+/// Loads and enables the local APIC of an application processor, in the same mode as the
+///  bootstrap processor's.
+///
+/// SAFETY: This must be called once on each application processor (after its per-CPU data is
+///  initialized), before it uses its local APIC.
+pub unsafe fn init_ap() {
+    load();
+
+    let apic = local_apic_mut().unwrap();
+    apic.reset();
+    apic.enable();
+    apic.write_spurious(crate::structures::irq::SPURIOUS_VECTOR, true);
+}
+
+/// The current CPU's local APIC, if it's been loaded.
 pub fn local_apic() -> Option<&'static APIC> {
-    unsafe { LOCAL_APIC.get() }
+    crate::percpu::try_get()?.local_apic()
 }
 
+/// The current CPU's local APIC, if it's been loaded.
 pub fn local_apic_mut() -> Option<&'static mut APIC> {
-    unsafe { LOCAL_APIC.get_mut() }
+    crate::percpu::try_get()?.local_apic_mut()
 }
 
-#[repr(u32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[allow(non_camel_case_types)]
-pub enum APICRegister {
-    ID = 0x20,
-    Version = 0x30,
-    TaskPriority = 0x80,
-    LDR = 0xD0,
-    DFR = 0xE0,
-    ICRL = 0x300,
-    ICRH = 0x310,
-    TimerInitialCount = 0x380,
-    TimerCurrentCount = 0x390,
-    TimerDivisor = 0x3E0,
-    Last = 0x38F,
+/// Registers of the local APIC, by their offset within its MMIO region.
+///
+/// In x2APIC mode, each register is instead the MSR at `X2APIC_MSR_BASE + (offset >> 4)`.
+/// `DFR` and `ICRH` don't exist in x2APIC mode, and `LDR` is read-only.
+pub enum APICRegister {}
+
+impl APICRegister {
+    pub const ID: ReadWriteRegister<u32> = ReadWriteRegister::new(0x20);
+    pub const VERSION: ReadOnlyRegister<u32> = ReadOnlyRegister::new(0x30);
+    pub const TASK_PRIORITY: ReadWriteRegister<u32> = ReadWriteRegister::new(0x80);
+    pub const EOI: WriteOnlyRegister<u32> = WriteOnlyRegister::new(0xB0);
+    pub const LDR: ReadWriteRegister<u32> = ReadWriteRegister::new(0xD0);
+    pub const DFR: ReadWriteRegister<u32> = ReadWriteRegister::new(0xE0);
+    pub const SPURIOUS: ReadWriteRegister<u32> = ReadWriteRegister::new(0xF0);
+    pub const ERROR_STATUS: ReadOnlyRegister<APICErrorStatusFlags> = ReadOnlyRegister::new(0x280);
+    pub const LVT_CMCI: ReadWriteRegister<u32> = ReadWriteRegister::new(0x2F0);
+    pub const ICRL: ReadWriteRegister<u32> = ReadWriteRegister::new(0x300);
+    pub const ICRH: ReadWriteRegister<u32> = ReadWriteRegister::new(0x310);
+    pub const LVT_TIMER: ReadWriteRegister<u32> = ReadWriteRegister::new(0x320);
+    pub const LVT_THERMAL_SENSOR: ReadWriteRegister<u32> = ReadWriteRegister::new(0x330);
+    pub const LVT_PERFORMANCE: ReadWriteRegister<u32> = ReadWriteRegister::new(0x340);
+    pub const LVT_LINT0: ReadWriteRegister<u32> = ReadWriteRegister::new(0x350);
+    pub const LVT_LINT1: ReadWriteRegister<u32> = ReadWriteRegister::new(0x360);
+    pub const LVT_ERROR: ReadWriteRegister<u32> = ReadWriteRegister::new(0x370);
+    pub const TIMER_INITIAL_COUNT: ReadWriteRegister<u32> = ReadWriteRegister::new(0x380);
+    pub const TIMER_CURRENT_COUNT: ReadOnlyRegister<u32> = ReadOnlyRegister::new(0x390);
+    pub const TIMER_DIVISOR: ReadWriteRegister<u32> = ReadWriteRegister::new(0x3E0);
 }
 
 #[repr(u32)]
@@ -86,6 +131,20 @@ pub enum APICDeliveryMode {
     NonMaskable = 0b100,
     External = 0b111,
     INIT = 0b101,
+    StartUp = 0b110,
+}
+
+/// Destination of an inter-processor interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IPIDestination {
+    /// The local APIC with the given ID.
+    APIC(u32),
+    /// The current CPU's local APIC.
+    Current,
+    /// Every local APIC, including the current CPU's.
+    All,
+    /// Every local APIC except the current CPU's.
+    AllExcludingCurrent,
 }
 
 bitflags::bitflags! {
@@ -101,8 +160,25 @@ bitflags::bitflags! {
     }
 }
 
+impl RegisterValue for APICErrorStatusFlags {
+    type Raw = u32;
+
+    fn from_raw(raw: Self::Raw) -> Self {
+        Self::from_bits_truncate(raw as u8)
+    }
+
+    fn into_raw(self) -> Self::Raw {
+        self.bits() as u32
+    }
+}
+
+/// First of the MSRs the x2APIC's registers are accessed through.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
 pub struct APIC {
-    mmio: MMIO<Mapped>,
+    /// The xAPIC register page, or `None` in x2APIC mode (where registers are accessed
+    ///  through MSRs).
+    mmio: Option<MMIO<Mapped>>,
 }
 
 impl APIC {
@@ -116,7 +192,23 @@ impl APIC {
     }
 
     pub unsafe fn new(mmio: MMIO<Mapped>) -> Self {
-        Self { mmio }
+        Self { mmio: Some(mmio) }
+    }
+
+    /// Switches the local APIC into x2APIC mode, in which its registers are accessed through
+    ///  MSRs rather than an MMIO page.
+    ///
+    /// SAFETY: The CPU must support x2APIC mode.
+    pub unsafe fn new_x2apic() -> Self {
+        // x2APIC mode can only be entered from xAPIC mode, so the APIC is enabled first.
+        MSR::IA32_APIC_BASE.write_bit(11, true);
+        MSR::IA32_APIC_BASE.write_bit(10, true);
+
+        Self { mmio: None }
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        self.mmio.is_none()
     }
 
     pub fn is_enabled(&self) -> bool {
@@ -127,81 +219,186 @@ impl APIC {
         MSR::IA32_APIC_BASE.write_bit(11, true);
     }
 
+    /// Disables the local APIC. In x2APIC mode, this also leaves x2APIC mode (the APIC must
+    ///  be re-created to be used again).
     pub unsafe fn disable(&mut self) {
-        MSR::IA32_APIC_BASE.write_bit(11, false);
+        // x2APIC mode can only be left by disabling the APIC, clearing both bits at once.
+        MSR::IA32_APIC_BASE.write(MSR::IA32_APIC_BASE.read() & !((1 << 11) | (1 << 10)));
+    }
+
+    const fn x2apic_msr(offset: usize) -> u32 {
+        X2APIC_MSR_BASE + ((offset as u32) >> 4)
+    }
+
+    pub fn read_register<R: ReadableRegister>(&self, register: R) -> R::Value
+    where
+        R::Value: RegisterValue<Raw = u32>,
+    {
+        match &self.mmio {
+            Some(mmio) => mmio.read_register(register),
+            None => R::Value::from_raw(unsafe {
+                MSR::read_raw(Self::x2apic_msr(register.offset())) as u32
+            }),
+        }
+    }
+
+    pub fn write_register<R: WritableRegister>(&mut self, register: R, value: R::Value)
+    where
+        R::Value: RegisterValue<Raw = u32>,
+    {
+        match &mut self.mmio {
+            Some(mmio) => mmio.write_register(register, value),
+            None => unsafe {
+                MSR::write_raw(Self::x2apic_msr(register.offset()), value.into_raw() as u64)
+            },
+        }
+    }
+
+    /// Reads the register, then writes the value returned by `func` back to it.
+    pub fn update_register<T: RegisterValue<Raw = u32>, F: FnOnce(T) -> T>(
+        &mut self,
+        register: ReadWriteRegister<T>,
+        func: F,
+    ) {
+        let value = self.read_register(register);
+        self.write_register(register, func(value));
+    }
+
+    /// ID of the local APIC (its full 32-bit ID in x2APIC mode, or 8 bits otherwise).
+    pub fn id(&self) -> u32 {
+        let id = self.read_register(APICRegister::ID);
+
+        if self.is_x2apic() {
+            id
+        } else {
+            id >> 24
+        }
+    }
+
+    /// Writes the interrupt command register, sending an inter-processor interrupt described
+    ///  by `command` (the low 32 bits of the register) to the APIC with the given ID.
+    pub fn write_interrupt_command(&mut self, destination: u32, command: u32) {
+        match &mut self.mmio {
+            Some(mmio) => {
+                assert!(
+                    destination <= (u8::MAX as u32),
+                    "xAPIC destinations are limited to 8 bits"
+                );
+
+                // The command is sent when the low register is written, so it's written last.
+                mmio.write_register(APICRegister::ICRH, destination << 24);
+                mmio.write_register(APICRegister::ICRL, command);
+            }
+            None => unsafe {
+                MSR::write_raw(
+                    Self::x2apic_msr(WritableRegister::offset(&APICRegister::ICRL)),
+                    ((destination as u64) << 32) | (command as u64),
+                )
+            },
+        }
+    }
+
+    /// Whether the last inter-processor interrupt hasn't yet been accepted. This is always
+    ///  false in x2APIC mode, where writes to the interrupt command register don't return
+    ///  until the interrupt has been sent.
+    pub fn is_interrupt_command_pending(&self) -> bool {
+        !self.is_x2apic() && (self.read_register(APICRegister::ICRL) & (1 << 12)) > 0
+    }
+
+    /// Sends an inter-processor interrupt, waiting until it's been accepted.
+    ///
+    /// `vector` is ignored by NMIs and INIT IPIs, and is the page number of the code the
+    ///  destination starts executing for startup IPIs.
+    ///
+    /// Interrupts are disabled while the IPI is sent, so an IPI sent by an interrupt handler
+    ///  (i.e. a TLB shootdown) can't overwrite the interrupt command register between the two
+    ///  writes of another, in xAPIC mode.
+    pub fn send_ipi(&mut self, destination: IPIDestination, mode: APICDeliveryMode, vector: u8) {
+        const LEVEL_ASSERT: u32 = 1 << 14;
+        const SHORTHAND_OFFSET: u32 = 18;
+
+        let (apic_id, shorthand) = match destination {
+            IPIDestination::APIC(apic_id) => (apic_id, 0b00),
+            IPIDestination::Current => (0, 0b01),
+            IPIDestination::All => (0, 0b10),
+            IPIDestination::AllExcludingCurrent => (0, 0b11),
+        };
+
+        crate::instructions::interrupts::without_interrupts(|| {
+            self.write_interrupt_command(
+                apic_id,
+                (vector as u32)
+                    | ((mode as u32) << 8)
+                    | LEVEL_ASSERT
+                    | (shorthand << SHORTHAND_OFFSET),
+            );
+
+            while self.is_interrupt_command_pending() {
+                core::hint::spin_loop();
+            }
+        });
     }
 
     pub fn end_of_interrupt(&mut self) {
-        unsafe { self.mmio.write(0xB0, 0).unwrap() };
+        self.write_register(APICRegister::EOI, 0);
     }
 
-    pub fn cmci(&mut self) -> &mut LVTRegister<Generic> {
-        unsafe { transmute(self.mmio.read_mut::<u32>(0x2F0).unwrap()) }
+    pub fn cmci(&mut self) -> LVTRegister<Generic> {
+        LVTRegister::new(self, APICRegister::LVT_CMCI)
     }
 
-    pub fn timer(&mut self) -> &mut LVTRegister<Timer> {
-        unsafe { transmute(self.mmio.read_mut::<u32>(0x320).unwrap()) }
+    pub fn timer(&mut self) -> LVTRegister<Timer> {
+        LVTRegister::new(self, APICRegister::LVT_TIMER)
     }
 
-    pub fn thermal_sensor(&mut self) -> &mut LVTRegister<Generic> {
-        unsafe { transmute(self.mmio.read_mut::<u32>(0x330).unwrap()) }
+    pub fn thermal_sensor(&mut self) -> LVTRegister<Generic> {
+        LVTRegister::new(self, APICRegister::LVT_THERMAL_SENSOR)
     }
 
-    pub fn performance(&mut self) -> &mut LVTRegister<Generic> {
-        unsafe { transmute(self.mmio.read_mut::<u32>(0x340).unwrap()) }
+    pub fn performance(&mut self) -> LVTRegister<Generic> {
+        LVTRegister::new(self, APICRegister::LVT_PERFORMANCE)
     }
 
-    pub fn lint0(&mut self) -> &mut LVTRegister<LINT> {
-        unsafe { transmute(self.mmio.read_mut::<u32>(0x350).unwrap()) }
+    pub fn lint0(&mut self) -> LVTRegister<LINT> {
+        LVTRegister::new(self, APICRegister::LVT_LINT0)
     }
 
-    pub fn lint1(&mut self) -> &mut LVTRegister<LINT> {
-        unsafe { transmute(self.mmio.read_mut::<u32>(0x360).unwrap()) }
+    pub fn lint1(&mut self) -> LVTRegister<LINT> {
+        LVTRegister::new(self, APICRegister::LVT_LINT1)
     }
 
-    pub fn error(&mut self) -> &mut LVTRegister<Error> {
-        unsafe { transmute(self.mmio.read_mut::<u32>(0x370).unwrap()) }
+    pub fn error(&mut self) -> LVTRegister<Error> {
+        LVTRegister::new(self, APICRegister::LVT_ERROR)
     }
 
     pub fn write_spurious(&mut self, vector: u8, enabled: bool) {
-        unsafe {
-            self.mmio
-                .write(0xF0, (vector as u32) | ((enabled as u32) << 8))
-                .unwrap()
-        };
+        self.write_register(
+            APICRegister::SPURIOUS,
+            (vector as u32) | ((enabled as u32) << 8),
+        );
     }
 
     pub fn error_status(&self) -> APICErrorStatusFlags {
-        APICErrorStatusFlags::from_bits_truncate(unsafe { *self.mmio.read(0x280).unwrap() })
+        self.read_register(APICRegister::ERROR_STATUS)
     }
 
     pub unsafe fn reset(&mut self) {
-        self[APICRegister::DFR] = 0xFFFFFFFF;
-        let mut ldr = self[APICRegister::LDR];
-        ldr &= 0xFFFFFF;
-        ldr = (ldr & !0xFF) | ((ldr & 0xFF) | 1);
-        self[APICRegister::LDR] = ldr;
+        // In x2APIC mode, the destination format is fixed, and the logical ID is read-only.
+        if !self.is_x2apic() {
+            self.write_register(APICRegister::DFR, 0xFFFFFFFF);
+            let mut ldr = self.read_register(APICRegister::LDR);
+            ldr &= 0xFFFFFF;
+            ldr = (ldr & !0xFF) | ((ldr & 0xFF) | 1);
+            self.write_register(APICRegister::LDR, ldr);
+        }
+
         self.timer().set_masked(true);
         self.performance()
             .set_delivery_mode(APICDeliveryMode::NonMaskable);
         // self.lint0().set_masked(true);
         self.lint1().set_masked(true);
-        self[APICRegister::TaskPriority] = 0;
-        self[APICRegister::TimerInitialCount] = 0;
-    }
-}
-
-impl core::ops::Index<APICRegister> for APIC {
-    type Output = u32;
-
-    fn index(&self, register: APICRegister) -> &Self::Output {
-        unsafe { self.mmio.read(register as usize).unwrap() }
-    }
-}
-
-impl core::ops::IndexMut<APICRegister> for APIC {
-    fn index_mut(&mut self, register: APICRegister) -> &mut Self::Output {
-        unsafe { self.mmio.read_mut(register as usize).unwrap() }
+        self.write_register(APICRegister::TASK_PRIORITY, 0);
+        self.write_register(APICRegister::TIMER_INITIAL_COUNT, 0);
     }
 }
 
@@ -221,79 +418,71 @@ impl LVTRegisterVariant for Error {}
 
 use bit_field::BitField;
 
-#[repr(transparent)]
-pub struct LVTRegister<T: LVTRegisterVariant> {
-    value: u32,
+/// Handle to one of the local APIC's local vector table registers.
+pub struct LVTRegister<'a, T: LVTRegisterVariant> {
+    apic: &'a mut APIC,
+    register: ReadWriteRegister<u32>,
     phantom_generic: PhantomData<T>,
 }
 
-impl<T: LVTRegisterVariant> LVTRegister<T> {
+impl<'a, T: LVTRegisterVariant> LVTRegister<'a, T> {
     const INTERRUPTED_OFFSET: u32 = 12;
     const INTERRUPTED_BIT: u32 = 1 << Self::INTERRUPTED_OFFSET;
     const MASKED_OFFSET: u32 = 16;
     const MASKED_BIT: u32 = 1 << Self::MASKED_OFFSET;
     const VECTOR_MASK: u32 = 0xFF;
 
-    fn value_ptr(&self) -> *const u32 {
-        (&self.value) as *const u32
+    fn new(apic: &'a mut APIC, register: ReadWriteRegister<u32>) -> Self {
+        Self {
+            apic,
+            register,
+            phantom_generic: PhantomData,
+        }
+    }
+
+    fn read(&self) -> u32 {
+        self.apic.read_register(self.register)
     }
 
-    fn value_mut_ptr(&mut self) -> *mut u32 {
-        (&mut self.value) as *mut u32
+    fn update<F: FnOnce(u32) -> u32>(&mut self, func: F) {
+        self.apic.update_register(self.register, func);
     }
 
     pub fn is_interrupted(&self) -> bool {
-        unsafe { (self.value_ptr().read_volatile() & Self::INTERRUPTED_BIT) > 0 }
+        (self.read() & Self::INTERRUPTED_BIT) > 0
     }
 
     pub fn is_masked(&self) -> bool {
-        unsafe { (self.value_ptr().read_volatile() & Self::MASKED_BIT) > 0 }
+        (self.read() & Self::MASKED_BIT) > 0
     }
 
     pub fn set_masked(&mut self, masked: bool) {
-        unsafe {
-            self.value_mut_ptr().write_volatile(
-                (self.value_ptr().read_volatile() & !Self::MASKED_BIT)
-                    | ((masked as u32) << Self::MASKED_OFFSET),
-            );
-        }
+        self.update(|value| (value & !Self::MASKED_BIT) | ((masked as u32) << Self::MASKED_OFFSET));
     }
 
     pub fn get_vector(&self) -> u8 {
-        unsafe { (self.value_ptr().read_volatile() & Self::VECTOR_MASK) as u8 }
+        (self.read() & Self::VECTOR_MASK) as u8
     }
 
     pub fn set_vector(&mut self, vector: u8) {
-        unsafe {
-            self.value_mut_ptr().write_volatile(
-                (self.value_ptr().read_volatile() & !Self::VECTOR_MASK) | vector as u32,
-            );
-        }
+        self.update(|value| (value & !Self::VECTOR_MASK) | vector as u32);
     }
 
     #[cfg(debug_assertions)]
     pub fn read_raw(&self) -> u32 {
-        unsafe { self.value_ptr().read_volatile() }
+        self.read()
     }
 }
 
-impl LVTRegister<Timer> {
+impl LVTRegister<'_, Timer> {
     pub fn set_mode(&mut self, mode: APICTimerMode) {
-        unsafe {
-            self.value_mut_ptr().write_volatile(
-                (self.value_ptr().read_volatile() & !(0b11 << 17)) | ((mode as u32) << 17),
-            );
-        }
+        self.update(|value| (value & !(0b11 << 17)) | ((mode as u32) << 17));
     }
 }
 
-impl LVTRegister<Generic> {
+impl LVTRegister<'_, Generic> {
     #[inline]
     pub fn set_delivery_mode(&mut self, mode: APICDeliveryMode) {
-        unsafe {
-            self.value_mut_ptr().write_volatile(
-                (self.value_ptr().read_volatile() & !(0x111 << 8)) | ((mode as u32) << 8),
-            );
-        }
+        self.update(|value| (value & !(0b111 << 8)) | ((mode as u32) << 8));
     }
 }
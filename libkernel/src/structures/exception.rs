@@ -0,0 +1,462 @@
+//! CPU exception entry, and dispatch of exceptions to registered handlers.
+//!
+//! Every exception vector enters an assembly stub (see `exception_stubs.s`), which saves all of
+//! the general-purpose registers into a `TrapFrame`. The frame is handed to the handler
+//! registered on the exception (if any), then to the kernel's default handling (i.e. demand
+//! paging). If neither resolves the exception, the CPU's state is reported and the kernel panics.
+
+use crate::{
+    registers::{CR0, CR2, CR3, CR4, MSR},
+    structures::gdt::{DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX},
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+
+global_asm!(include_str!("exception_stubs.s"));
+
+extern "C" {
+    static exception_stubs: [u64; EXCEPTION_VECTOR_COUNT];
+}
+
+/// Number of vectors reserved for CPU exceptions.
+const EXCEPTION_VECTOR_COUNT: usize = 32;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTSS = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SIMDFloatingPoint = 19,
+    Virtualization = 20,
+    SecurityException = 30,
+}
+
+impl Exception {
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        match vector {
+            0 => Some(Self::DivideError),
+            1 => Some(Self::Debug),
+            2 => Some(Self::NonMaskableInterrupt),
+            3 => Some(Self::Breakpoint),
+            4 => Some(Self::Overflow),
+            5 => Some(Self::BoundRangeExceeded),
+            6 => Some(Self::InvalidOpcode),
+            7 => Some(Self::DeviceNotAvailable),
+            8 => Some(Self::DoubleFault),
+            10 => Some(Self::InvalidTSS),
+            11 => Some(Self::SegmentNotPresent),
+            12 => Some(Self::StackSegmentFault),
+            13 => Some(Self::GeneralProtectionFault),
+            14 => Some(Self::PageFault),
+            16 => Some(Self::X87FloatingPoint),
+            17 => Some(Self::AlignmentCheck),
+            18 => Some(Self::MachineCheck),
+            19 => Some(Self::SIMDFloatingPoint),
+            20 => Some(Self::Virtualization),
+            30 => Some(Self::SecurityException),
+            _ => None,
+        }
+    }
+
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DivideError => "DIVIDE ERROR",
+            Self::Debug => "DEBUG",
+            Self::NonMaskableInterrupt => "NON-MASKABLE INTERRUPT",
+            Self::Breakpoint => "BREAKPOINT",
+            Self::Overflow => "OVERFLOW",
+            Self::BoundRangeExceeded => "BOUND RANGE EXCEEDED",
+            Self::InvalidOpcode => "INVALID OPCODE",
+            Self::DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+            Self::DoubleFault => "DOUBLE FAULT",
+            Self::InvalidTSS => "INVALID TSS",
+            Self::SegmentNotPresent => "SEGMENT NOT PRESENT",
+            Self::StackSegmentFault => "STACK-SEGMENT FAULT",
+            Self::GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            Self::PageFault => "PAGE FAULT",
+            Self::X87FloatingPoint => "x87 FLOATING POINT",
+            Self::AlignmentCheck => "ALIGNMENT CHECK",
+            Self::MachineCheck => "MACHINE CHECK",
+            Self::SIMDFloatingPoint => "SIMD FLOATING POINT",
+            Self::Virtualization => "VIRTUALIZATION",
+            Self::SecurityException => "SECURITY EXCEPTION",
+        }
+    }
+
+    /// Whether the CPU pushes an error code for the exception.
+    pub fn has_error_code(self) -> bool {
+        matches!(
+            self,
+            Self::DoubleFault
+                | Self::InvalidTSS
+                | Self::SegmentNotPresent
+                | Self::StackSegmentFault
+                | Self::GeneralProtectionFault
+                | Self::PageFault
+                | Self::AlignmentCheck
+                | Self::SecurityException
+        )
+    }
+
+    /// Whether execution can resume after the exception. Double faults and machine checks leave
+    ///  the CPU in an undefined state, so are always reported.
+    pub fn is_resumable(self) -> bool {
+        !matches!(self, Self::DoubleFault | Self::MachineCheck)
+    }
+}
+
+/// State of the CPU when an exception occurred, as saved by the entry stubs. Handlers may
+///  modify it (i.e. advance `rip` past a faulting instruction), and it's restored when
+///  execution resumes.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub vector: u64,
+    /// Pushed by the CPU for some exceptions, and by the entry stub (as 0) for the rest.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn exception(&self) -> Option<Exception> {
+        Exception::from_vector(self.vector as u8)
+    }
+}
+
+impl core::fmt::Debug for TrapFrame {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            formatter,
+            " RIP {:#018x}  CS {:#06x}  RFLAGS {:#010x}  RSP {:#018x}  SS {:#06x}",
+            self.rip, self.cs, self.rflags, self.rsp, self.ss
+        )?;
+        writeln!(
+            formatter,
+            " RAX {:#018x}  RBX {:#018x}  RCX {:#018x}  RDX {:#018x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            formatter,
+            " RSI {:#018x}  RDI {:#018x}  RBP {:#018x}  R8  {:#018x}",
+            self.rsi, self.rdi, self.rbp, self.r8
+        )?;
+        writeln!(
+            formatter,
+            " R9  {:#018x}  R10 {:#018x}  R11 {:#018x}  R12 {:#018x}",
+            self.r9, self.r10, self.r11, self.r12
+        )?;
+        write!(
+            formatter,
+            " R13 {:#018x}  R14 {:#018x}  R15 {:#018x}",
+            self.r13, self.r14, self.r15
+        )
+    }
+}
+
+/// Handles an exception, given the state of the CPU when it occurred. Returns whether the
+///  exception was resolved, in which case execution resumes with the (possibly modified) frame.
+///  Otherwise, the kernel's default handling applies.
+///
+/// Handlers run with interrupts disabled, on the CPU which raised the exception, so mustn't take
+///  locks the interrupted code may hold.
+pub type ExceptionHandler = fn(frame: &mut TrapFrame) -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionError {
+    /// The exception can't be resumed from, so can't be handled.
+    NotResumable(Exception),
+    AlreadyRegistered(Exception),
+    NotRegistered(Exception),
+}
+
+// Handlers are stored as addresses (0 being none), rather than behind a lock, since an
+//  exception can be raised while the lock is held on the same CPU.
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static HANDLERS: [AtomicUsize; EXCEPTION_VECTOR_COUNT] = [NO_HANDLER; EXCEPTION_VECTOR_COUNT];
+
+/* HANDLERS */
+
+/// Registers the handler of an exception, which is invoked before the kernel's default handling.
+pub fn register_handler(
+    exception: Exception,
+    handler: ExceptionHandler,
+) -> Result<(), ExceptionError> {
+    if !exception.is_resumable() {
+        return Err(ExceptionError::NotResumable(exception));
+    }
+
+    HANDLERS[exception.vector() as usize]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+        .map(|_| debug!("Registered exception handler for {:?}.", exception))
+        .map_err(|_| ExceptionError::AlreadyRegistered(exception))
+}
+
+pub fn unregister_handler(exception: Exception) -> Result<(), ExceptionError> {
+    match HANDLERS[exception.vector() as usize].swap(0, Ordering::AcqRel) {
+        0 => Err(ExceptionError::NotRegistered(exception)),
+        _ => Ok(()),
+    }
+}
+
+fn handler(exception: Exception) -> Option<ExceptionHandler> {
+    match HANDLERS[exception.vector() as usize].load(Ordering::Acquire) {
+        0 => None,
+        address => Some(unsafe { core::mem::transmute::<usize, ExceptionHandler>(address) }),
+    }
+}
+
+/* DISPATCH */
+
+/// Points each exception entry of the IDT at its entry stub.
+pub(crate) fn install_stubs(idt: &mut InterruptDescriptorTable) {
+    let stub = |exception: Exception| {
+        x86_64::VirtAddr::new(unsafe { exception_stubs[exception.vector() as usize] })
+    };
+
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(stub(Exception::DivideError));
+        idt.debug.set_handler_addr(stub(Exception::Debug));
+        idt.non_maskable_interrupt
+            .set_handler_addr(stub(Exception::NonMaskableInterrupt));
+        idt.breakpoint.set_handler_addr(stub(Exception::Breakpoint));
+        idt.overflow.set_handler_addr(stub(Exception::Overflow));
+        idt.bound_range_exceeded
+            .set_handler_addr(stub(Exception::BoundRangeExceeded));
+        idt.invalid_opcode
+            .set_handler_addr(stub(Exception::InvalidOpcode));
+        idt.device_not_available
+            .set_handler_addr(stub(Exception::DeviceNotAvailable));
+        idt.double_fault
+            .set_handler_addr(stub(Exception::DoubleFault))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss
+            .set_handler_addr(stub(Exception::InvalidTSS));
+        idt.segment_not_present
+            .set_handler_addr(stub(Exception::SegmentNotPresent));
+        idt.stack_segment_fault
+            .set_handler_addr(stub(Exception::StackSegmentFault));
+        idt.general_protection_fault
+            .set_handler_addr(stub(Exception::GeneralProtectionFault));
+        idt.page_fault
+            .set_handler_addr(stub(Exception::PageFault))
+            .set_stack_index(PAGE_FAULT_IST_INDEX);
+        // --- reserved 15
+        idt.x87_floating_point
+            .set_handler_addr(stub(Exception::X87FloatingPoint));
+        idt.alignment_check
+            .set_handler_addr(stub(Exception::AlignmentCheck));
+        idt.machine_check
+            .set_handler_addr(stub(Exception::MachineCheck));
+        idt.simd_floating_point
+            .set_handler_addr(stub(Exception::SIMDFloatingPoint));
+        idt.virtualization
+            .set_handler_addr(stub(Exception::Virtualization));
+        // --- reserved 21-29
+        idt.security_exception
+            .set_handler_addr(stub(Exception::SecurityException));
+        // --- triple fault (can't handle)
+    }
+}
+
+/// Called by `exception_common` with the trap frame it saved.
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut TrapFrame) {
+    let exception = frame
+        .exception()
+        .unwrap_or_else(|| panic!("CPU EXCEPTION: RESERVED VECTOR {}", frame.vector));
+
+    if exception.is_resumable() {
+        if handler(exception).map_or(false, |handler| handler(frame))
+            || default_handler(exception, frame)
+        {
+            return;
+        }
+    }
+
+    report(log::Level::Error, exception, frame);
+    panic!(
+        "CPU EXCEPTION: {}: {}",
+        exception.name(),
+        ErrorCode(exception, frame.error_code)
+    );
+}
+
+/// The kernel's own handling of exceptions no registered handler resolved.
+fn default_handler(exception: Exception, frame: &mut TrapFrame) -> bool {
+    match exception {
+        Exception::PageFault => {
+            let fault_addr = CR2::read();
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+
+            crate::memory::vma::demand_page(fault_addr, error_code)
+                || crate::memory::paging::copy_on_write_fault(fault_addr, error_code)
+        }
+        // `int3` leaves `rip` after itself, so execution simply continues.
+        Exception::Breakpoint => {
+            report(log::Level::Warn, exception, frame);
+            true
+        }
+        _ => false,
+    }
+}
+
+/* REPORTING */
+
+/// Logs the state of the CPU when an exception occurred: its registers, control registers, and
+///  decoded error code.
+pub fn report(level: log::Level, exception: Exception, frame: &TrapFrame) {
+    log!(
+        level,
+        "CPU EXCEPTION: {} (vector {}) on CPU {}",
+        exception.name(),
+        exception.vector(),
+        crate::percpu::try_get().map_or(0, |percpu| percpu.cpu_id())
+    );
+
+    if exception.has_error_code() {
+        log!(
+            level,
+            " Error code: {:#x} ({})",
+            frame.error_code,
+            ErrorCode(exception, frame.error_code)
+        );
+    }
+
+    if exception == Exception::PageFault {
+        let fault_addr = CR2::read();
+
+        match crate::memory::vma::try_find(fault_addr) {
+            Some(Some(area)) => log!(
+                level,
+                " Faulting address: {:?}\n Area: {:?}",
+                fault_addr,
+                area
+            ),
+            Some(None) => log!(level, " Faulting address: {:?}\n Area: none", fault_addr),
+            None => log!(
+                level,
+                " Faulting address: {:?}\n Area: unknown (area map is locked)",
+                fault_addr
+            ),
+        }
+    }
+
+    log!(level, "{:?}", frame);
+    log!(
+        level,
+        " CR0 {:#010x}  CR2 {:#018x}  CR3 {:#018x}  CR4 {:#010x}  EFER {:#x}",
+        CR0::read().bits(),
+        CR2::read().as_usize(),
+        CR3::read_frame().addr().as_usize() | CR3::read().bits(),
+        CR4::read().bits(),
+        MSR::IA32_EFER.read()
+    );
+}
+
+/// Decodes an exception's error code for display.
+struct ErrorCode(Exception, u64);
+
+impl core::fmt::Display for ErrorCode {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let ErrorCode(exception, error_code) = *self;
+
+        match exception {
+            Exception::PageFault => {
+                let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+
+                let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                    "instruction fetch"
+                } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                    "write"
+                } else {
+                    "read"
+                };
+                let cause = if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+                    "reserved bit set in page table entry"
+                } else if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                    "protection violation"
+                } else {
+                    "page not present"
+                };
+                let mode = if error_code.contains(PageFaultErrorCode::USER_MODE) {
+                    "user"
+                } else {
+                    "supervisor"
+                };
+
+                write!(formatter, "{} in {} mode, {}", access, mode, cause)
+            }
+
+            // These exceptions' error codes are segment selector indexes, or 0 if the exception
+            //  wasn't caused by a selector.
+            Exception::InvalidTSS
+            | Exception::SegmentNotPresent
+            | Exception::StackSegmentFault
+            | Exception::GeneralProtectionFault => {
+                if error_code == 0 {
+                    return write!(formatter, "no selector");
+                }
+
+                let table = match (error_code >> 1) & 0b11 {
+                    0b00 => "GDT",
+                    0b10 => "LDT",
+                    _ => "IDT",
+                };
+
+                write!(
+                    formatter,
+                    "{} index {}{}",
+                    table,
+                    (error_code >> 3) & 0x1FFF,
+                    if (error_code & 0x1) > 0 {
+                        ", external event"
+                    } else {
+                        ""
+                    }
+                )
+            }
+
+            _ if exception.has_error_code() => write!(formatter, "{:#x}", error_code),
+            _ => write!(formatter, "no error code"),
+        }
+    }
+}
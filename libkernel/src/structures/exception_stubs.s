@@ -0,0 +1,117 @@
+// CPU exception entry stubs.
+//
+// Each stub pushes a zero error code (if the CPU doesn't push one for its vector) and the
+// vector, then enters `exception_common`, which saves every general-purpose register below
+// them. The stack then holds a `TrapFrame` (see `exception.rs`), which is passed to
+// `exception_dispatch`. If that returns, the (possibly modified) registers are restored, and
+// execution resumes at the frame's `rip`.
+//
+// The CPU aligns the stack to 16 bytes before pushing its interrupt frame, and the trap frame
+// is a multiple of 16 bytes, so the stack is aligned for the call without adjustment.
+
+.intel_syntax noprefix
+
+.macro EXCEPTION_STUB vector
+exception_stub_\vector:
+    push 0
+    push \vector
+    jmp exception_common
+.endm
+
+.macro EXCEPTION_STUB_ERROR_CODE vector
+exception_stub_\vector:
+    push \vector
+    jmp exception_common
+.endm
+
+.section .text.exception_stubs, "ax"
+
+exception_common:
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    mov rdi, rsp
+    cld
+    call exception_dispatch
+
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+
+    // Skips the vector and error code.
+    add rsp, 16
+    iretq
+
+EXCEPTION_STUB 0
+EXCEPTION_STUB 1
+EXCEPTION_STUB 2
+EXCEPTION_STUB 3
+EXCEPTION_STUB 4
+EXCEPTION_STUB 5
+EXCEPTION_STUB 6
+EXCEPTION_STUB 7
+EXCEPTION_STUB_ERROR_CODE 8
+EXCEPTION_STUB 9
+EXCEPTION_STUB_ERROR_CODE 10
+EXCEPTION_STUB_ERROR_CODE 11
+EXCEPTION_STUB_ERROR_CODE 12
+EXCEPTION_STUB_ERROR_CODE 13
+EXCEPTION_STUB_ERROR_CODE 14
+EXCEPTION_STUB 15
+EXCEPTION_STUB 16
+EXCEPTION_STUB_ERROR_CODE 17
+EXCEPTION_STUB 18
+EXCEPTION_STUB 19
+EXCEPTION_STUB 20
+EXCEPTION_STUB_ERROR_CODE 21
+EXCEPTION_STUB 22
+EXCEPTION_STUB 23
+EXCEPTION_STUB 24
+EXCEPTION_STUB 25
+EXCEPTION_STUB 26
+EXCEPTION_STUB 27
+EXCEPTION_STUB 28
+EXCEPTION_STUB_ERROR_CODE 29
+EXCEPTION_STUB_ERROR_CODE 30
+EXCEPTION_STUB 31
+
+// Address of each vector's stub, indexed by vector.
+.section .rodata.exception_stubs, "a"
+.global exception_stubs
+.align 8
+exception_stubs:
+    .quad exception_stub_0, exception_stub_1, exception_stub_2, exception_stub_3
+    .quad exception_stub_4, exception_stub_5, exception_stub_6, exception_stub_7
+    .quad exception_stub_8, exception_stub_9, exception_stub_10, exception_stub_11
+    .quad exception_stub_12, exception_stub_13, exception_stub_14, exception_stub_15
+    .quad exception_stub_16, exception_stub_17, exception_stub_18, exception_stub_19
+    .quad exception_stub_20, exception_stub_21, exception_stub_22, exception_stub_23
+    .quad exception_stub_24, exception_stub_25, exception_stub_26, exception_stub_27
+    .quad exception_stub_28, exception_stub_29, exception_stub_30, exception_stub_31
+
+.att_syntax prefix
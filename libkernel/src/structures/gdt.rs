@@ -1,3 +1,4 @@
+use alloc::{boxed::Box, vec};
 use lazy_static::lazy_static;
 use x86_64::structures::{
     gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
@@ -5,6 +6,11 @@ use x86_64::structures::{
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+/// Page faults are handled on their own stack, so a fault on an unbacked (demand-paged) page of
+///  the current stack can be resolved.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+const PAGE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
 struct Selectors {
     code_selector: SegmentSelector,
@@ -15,11 +21,17 @@ lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
 
             let stack_start = x86_64::VirtAddr::from_ptr(&STACK);
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
+            stack_end
+        };
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            static STACK: [u8; PAGE_FAULT_STACK_SIZE] = [0; PAGE_FAULT_STACK_SIZE];
+
+            let stack_start = x86_64::VirtAddr::from_ptr(&STACK);
+            let stack_end = stack_start + PAGE_FAULT_STACK_SIZE;
             stack_end
         };
 
@@ -44,11 +56,40 @@ lazy_static! {
 }
 
 pub fn init() {
-    GDT.0.load();
+    load(&GDT.0, &GDT.1);
+}
+
+/// Builds and loads a GDT and TSS for an application processor, with its own double fault and
+///  page fault stacks. These are leaked, as they're used for as long as the processor runs.
+pub fn init_ap() {
+    let double_fault_stack = vec![0u8; DOUBLE_FAULT_STACK_SIZE].leak();
+    let page_fault_stack = vec![0u8; PAGE_FAULT_STACK_SIZE].leak();
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        x86_64::VirtAddr::from_ptr(double_fault_stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+        x86_64::VirtAddr::from_ptr(page_fault_stack.as_ptr()) + PAGE_FAULT_STACK_SIZE;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    load(
+        gdt,
+        &Selectors {
+            code_selector,
+            tss_selector,
+        },
+    );
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    gdt.load();
 
     unsafe {
         // load the code and tss segments
-        x86_64::instructions::segmentation::set_cs(GDT.1.code_selector);
-        x86_64::instructions::tables::load_tss(GDT.1.tss_selector);
+        x86_64::instructions::segmentation::set_cs(selectors.code_selector);
+        x86_64::instructions::tables::load_tss(selectors.tss_selector);
     }
 }
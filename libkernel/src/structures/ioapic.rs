@@ -0,0 +1,459 @@
+use crate::{
+    instructions::interrupts::without_interrupts,
+    memory::mmio::{Mapped, ReadWriteRegister, WriteOnlyRegister, MMIO},
+    structures::{
+        acpi::{self, xsdt, InterruptDevice, MADT},
+        apic::APICDeliveryMode,
+    },
+};
+use bit_field::BitField;
+use spin::Mutex;
+
+/// Maximum number of I/O APICs which can be loaded.
+const MAX_IOAPICS: usize = 8;
+/// Number of ISA IRQs, which are identity-mapped to global system interrupts (as edge-triggered
+///  and active-high) unless the MADT overrides them.
+pub const ISA_IRQ_COUNT: usize = 16;
+
+const NO_IOAPIC: Option<IOAPIC> = None;
+static IOAPICS: Mutex<[Option<IOAPIC>; MAX_IOAPICS]> = Mutex::new([NO_IOAPIC; MAX_IOAPICS]);
+static ISA_OVERRIDES: Mutex<[Option<ISARoute>; ISA_IRQ_COUNT]> = Mutex::new([None; ISA_IRQ_COUNT]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IOAPICError {
+    /// No I/O APIC has been loaded (see `load`).
+    NotLoaded,
+    /// No loaded I/O APIC handles the given global system interrupt.
+    NoIOAPIC(u32),
+    /// The given IRQ isn't an ISA IRQ.
+    InvalidISAIRQ(u8),
+    /// No global system interrupt is known for a PCI device with the given interrupt line (or
+    ///  none, if firmware left it unassigned); see `route_pci_interrupt`.
+    NoPCIRoute(Option<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptPolarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// Global system interrupt, polarity, and trigger mode an ISA IRQ is routed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ISARoute {
+    global_sys_interrupt: u32,
+    polarity: InterruptPolarity,
+    trigger_mode: TriggerMode,
+}
+
+/// Entry of an I/O APIC's redirection table, which describes how the interrupt on one of
+///  its input pins is delivered.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry(u64);
+
+impl RedirectionEntry {
+    /// Creates a masked, edge-triggered, active-high entry delivering `vector` to the local
+    ///  APIC with the given (physical) ID.
+    pub const fn new(vector: u8, destination_apic_id: u8) -> Self {
+        Self((1 << 16) | ((destination_apic_id as u64) << 56) | (vector as u64))
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.0.get_bits(0..8) as u8
+    }
+
+    pub fn set_vector(&mut self, vector: u8) {
+        self.0.set_bits(0..8, vector as u64);
+    }
+
+    pub fn set_delivery_mode(&mut self, mode: APICDeliveryMode) {
+        self.0.set_bits(8..11, mode as u64);
+    }
+
+    /// Whether the interrupt has been sent to a local APIC, but not yet accepted.
+    pub fn is_pending(&self) -> bool {
+        self.0.get_bit(12)
+    }
+
+    pub fn polarity(&self) -> InterruptPolarity {
+        if self.0.get_bit(13) {
+            InterruptPolarity::ActiveLow
+        } else {
+            InterruptPolarity::ActiveHigh
+        }
+    }
+
+    pub fn set_polarity(&mut self, polarity: InterruptPolarity) {
+        self.0.set_bit(13, polarity == InterruptPolarity::ActiveLow);
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        if self.0.get_bit(15) {
+            TriggerMode::Level
+        } else {
+            TriggerMode::Edge
+        }
+    }
+
+    pub fn set_trigger_mode(&mut self, trigger_mode: TriggerMode) {
+        self.0.set_bit(15, trigger_mode == TriggerMode::Level);
+    }
+
+    pub fn is_masked(&self) -> bool {
+        self.0.get_bit(16)
+    }
+
+    pub fn set_masked(&mut self, masked: bool) {
+        self.0.set_bit(16, masked);
+    }
+
+    /// Physical ID of the local APIC the interrupt is delivered to.
+    pub fn destination(&self) -> u8 {
+        self.0.get_bits(56..64) as u8
+    }
+
+    pub fn set_destination(&mut self, destination_apic_id: u8) {
+        self.0.set_bits(56..64, destination_apic_id as u64);
+    }
+}
+
+/// An I/O APIC, whose registers are accessed indirectly through a select/window register pair.
+pub struct IOAPIC {
+    id: u8,
+    global_sys_interrupt_base: u32,
+    mmio: MMIO<Mapped>,
+}
+
+impl IOAPIC {
+    const REGISTER_SELECT: WriteOnlyRegister<u32> = WriteOnlyRegister::new(0x0);
+    const REGISTER_WINDOW: ReadWriteRegister<u32> = ReadWriteRegister::new(0x10);
+
+    pub const ID: u8 = 0x0;
+    pub const VERSION: u8 = 0x1;
+    pub const ARBITRATION: u8 = 0x2;
+    /// Register of the low 32 bits of the first redirection table entry. Each entry occupies
+    ///  two consecutive registers.
+    pub const REDIRECTION_TABLE: u8 = 0x10;
+
+    /// Maps the registers of the I/O APIC described by the MADT entry.
+    pub fn from_madt(entry: &acpi::IOAPIC) -> Self {
+        let register_base = entry.register_base();
+        assert!(
+            register_base.is_aligned(0x1000),
+            "I/O APIC register base must be frame-aligned"
+        );
+
+        let mmio = crate::memory::mmio::unmapped_mmio(unsafe {
+            crate::memory::falloc::get()
+                .acquire_frames(
+                    register_base.frame_index(),
+                    1,
+                    crate::memory::falloc::FrameState::MMIO,
+                )
+                .unwrap()
+        })
+        .unwrap()
+        .map();
+
+        Self {
+            id: entry.id(),
+            global_sys_interrupt_base: entry.global_sys_interrupt_base(),
+            mmio,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn global_sys_interrupt_base(&self) -> u32 {
+        self.global_sys_interrupt_base
+    }
+
+    pub fn read(&mut self, register: u8) -> u32 {
+        self.mmio
+            .write_register(Self::REGISTER_SELECT, register as u32);
+        self.mmio.read_register(Self::REGISTER_WINDOW)
+    }
+
+    pub fn write(&mut self, register: u8, value: u32) {
+        self.mmio
+            .write_register(Self::REGISTER_SELECT, register as u32);
+        self.mmio.write_register(Self::REGISTER_WINDOW, value);
+    }
+
+    /// Number of input pins (and so redirection table entries).
+    pub fn redirection_count(&mut self) -> usize {
+        (self.read(Self::VERSION).get_bits(16..24) as usize) + 1
+    }
+
+    /// Whether this I/O APIC's input pins include the given global system interrupt.
+    pub fn handles(&mut self, global_sys_interrupt: u32) -> bool {
+        let base = self.global_sys_interrupt_base();
+
+        global_sys_interrupt >= base
+            && ((global_sys_interrupt - base) as usize) < self.redirection_count()
+    }
+
+    /// Register of the low 32 bits of the pin's redirection table entry.
+    fn redirection_register(&mut self, pin: usize) -> u8 {
+        assert!(
+            pin < self.redirection_count(),
+            "pin must be within the redirection table"
+        );
+
+        // The register select is 8 bits wide, so a (malformed) redirection table which extends
+        //  past it can't be addressed.
+        let register = (Self::REDIRECTION_TABLE as u32) + ((pin as u32) * 2);
+        assert!(
+            register < (u8::MAX as u32),
+            "redirection table entry is beyond the register select"
+        );
+
+        register as u8
+    }
+
+    pub fn read_redirection(&mut self, pin: usize) -> RedirectionEntry {
+        let register = self.redirection_register(pin);
+        let low = self.read(register) as u64;
+        let high = self.read(register + 1) as u64;
+
+        RedirectionEntry((high << 32) | low)
+    }
+
+    pub fn write_redirection(&mut self, pin: usize, entry: RedirectionEntry) {
+        let register = self.redirection_register(pin);
+        // The entry is masked while it's written, so a half-written entry is never delivered.
+        self.write(register, (entry.0 as u32) | (1 << 16));
+        self.write(register + 1, (entry.0 >> 32) as u32);
+        self.write(register, entry.0 as u32);
+    }
+
+    pub fn set_masked(&mut self, pin: usize, masked: bool) {
+        let mut entry = self.read_redirection(pin);
+        entry.set_masked(masked);
+        self.write_redirection(pin, entry);
+    }
+}
+
+impl core::fmt::Debug for IOAPIC {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        formatter
+            .debug_struct("IO APIC")
+            .field("ID", &self.id())
+            .field(
+                "Global System Interrupt Base",
+                &self.global_sys_interrupt_base(),
+            )
+            .finish()
+    }
+}
+
+/// Loads every I/O APIC and ISA IRQ override described by the MADT, masking every
+///  redirection entry. Returns the number of I/O APICs loaded.
+pub fn load() -> Result<usize, IOAPICError> {
+    let madt = xsdt::get_entry::<MADT>().map_err(|_| IOAPICError::NotLoaded)?;
+    let mut ioapics = IOAPICS.lock();
+    let mut overrides = ISA_OVERRIDES.lock();
+    let mut ioapic_count = 0;
+
+    for interrupt_device in madt.iter() {
+        match interrupt_device {
+            InterruptDevice::IOAPIC(entry) => {
+                match ioapics.iter_mut().find(|slot| slot.is_none()) {
+                    Some(slot) => {
+                        let mut ioapic = IOAPIC::from_madt(entry);
+                        (0..ioapic.redirection_count())
+                            .for_each(|pin| ioapic.set_masked(pin, true));
+
+                        debug!("Loaded {:?}", ioapic);
+                        *slot = Some(ioapic);
+                        ioapic_count += 1;
+                    }
+                    None => warn!("Too many I/O APICs; ignoring {:?}", entry),
+                }
+            }
+            // Overrides are only defined for the ISA bus (bus 0).
+            InterruptDevice::IRQSrcOverride(irq_override)
+                if irq_override.bus_src() == 0
+                    && (irq_override.irq_src() as usize) < ISA_IRQ_COUNT =>
+            {
+                debug!("ISA IRQ override: {:?}", irq_override);
+
+                overrides[irq_override.irq_src() as usize] = Some(ISARoute {
+                    global_sys_interrupt: irq_override.global_sys_interrupt(),
+                    polarity: irq_override
+                        .polarity()
+                        .unwrap_or(InterruptPolarity::ActiveHigh),
+                    trigger_mode: irq_override.trigger_mode().unwrap_or(TriggerMode::Edge),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    if ioapic_count > 0 {
+        Ok(ioapic_count)
+    } else {
+        Err(IOAPICError::NotLoaded)
+    }
+}
+
+/// Invokes `func` with the I/O APIC handling the global system interrupt, and the input pin
+///  it arrives on.
+///
+/// Interrupts are disabled while the I/O APICs are locked, so an interrupt handler which
+///  masks or unmasks an interrupt can't deadlock with the code it interrupted.
+fn with_ioapic<T, F: FnOnce(&mut IOAPIC, usize) -> T>(
+    global_sys_interrupt: u32,
+    func: F,
+) -> Result<T, IOAPICError> {
+    without_interrupts(|| {
+        let mut ioapics = IOAPICS.lock();
+
+        if ioapics.iter().all(|slot| slot.is_none()) {
+            Err(IOAPICError::NotLoaded)
+        } else {
+            match ioapics.iter_mut().flatten().find_map(|ioapic| {
+                if ioapic.handles(global_sys_interrupt) {
+                    Some(ioapic)
+                } else {
+                    None
+                }
+            }) {
+                Some(ioapic) => {
+                    let pin = (global_sys_interrupt - ioapic.global_sys_interrupt_base()) as usize;
+                    Ok(func(ioapic, pin))
+                }
+                None => Err(IOAPICError::NoIOAPIC(global_sys_interrupt)),
+            }
+        }
+    })
+}
+
+/// Global system interrupt the ISA IRQ arrives on, after any MADT override.
+pub fn isa_global_sys_interrupt(irq: u8) -> Result<u32, IOAPICError> {
+    isa_route(irq).map(|route| route.global_sys_interrupt)
+}
+
+fn isa_route(irq: u8) -> Result<ISARoute, IOAPICError> {
+    if (irq as usize) < ISA_IRQ_COUNT {
+        Ok(
+            without_interrupts(|| ISA_OVERRIDES.lock()[irq as usize]).unwrap_or(ISARoute {
+                global_sys_interrupt: irq as u32,
+                polarity: InterruptPolarity::ActiveHigh,
+                trigger_mode: TriggerMode::Edge,
+            }),
+        )
+    } else {
+        Err(IOAPICError::InvalidISAIRQ(irq))
+    }
+}
+
+/// Routes a global system interrupt to `vector` on the local APIC with the given (physical)
+///  ID, with the given polarity and trigger mode.
+///
+/// The interrupt is left masked, so a handler can be installed for `vector` before it's
+///  unmasked with `unmask`.
+pub fn route(
+    global_sys_interrupt: u32,
+    vector: u8,
+    destination_apic_id: u8,
+    polarity: InterruptPolarity,
+    trigger_mode: TriggerMode,
+) -> Result<(), IOAPICError> {
+    let mut entry = RedirectionEntry::new(vector, destination_apic_id);
+    entry.set_delivery_mode(APICDeliveryMode::Fixed);
+    entry.set_polarity(polarity);
+    entry.set_trigger_mode(trigger_mode);
+
+    with_ioapic(global_sys_interrupt, |ioapic, pin| {
+        ioapic.write_redirection(pin, entry)
+    })?;
+
+    debug!(
+        "Routed global system interrupt {} to vector {} on APIC {} ({:?}, {:?}).",
+        global_sys_interrupt, vector, destination_apic_id, polarity, trigger_mode
+    );
+    Ok(())
+}
+
+/// Routes an ISA IRQ (applying any MADT override to its global system interrupt, polarity,
+///  and trigger mode) to `vector`, returning the global system interrupt it arrives on.
+///
+/// The interrupt is left masked (see `route`).
+pub fn route_isa_irq(irq: u8, vector: u8, destination_apic_id: u8) -> Result<u32, IOAPICError> {
+    let isa_route = isa_route(irq)?;
+
+    route(
+        isa_route.global_sys_interrupt,
+        vector,
+        destination_apic_id,
+        isa_route.polarity,
+        isa_route.trigger_mode,
+    )
+    .map(|_| isa_route.global_sys_interrupt)
+}
+
+/// Routes a PCI INTx interrupt to `vector`, given the device's interrupt line (see
+///  `PCIeDeviceHeader::interrupt_line`), returning the global system interrupt it arrives on.
+///
+/// The ACPI `_PRT` (which maps INTx pins to global system interrupts) can't be evaluated
+///  without an AML interpreter, so the interrupt line firmware assigned is taken as the ISA IRQ
+///  the interrupt is routed through, applying any MADT override. That only holds on machines
+///  which route PCI interrupts through the ISA IRQs (i.e. QEMU's i440FX machine, but not its
+///  Q35 machine, whose INTx pins arrive on global system interrupts 16-23); elsewhere, MSI
+///  should be used. Lines which aren't ISA IRQs (or aren't assigned) return
+///  `IOAPICError::NoPCIRoute`.
+///
+/// Without an override, the interrupt is level-triggered and active-low, as PCI INTx lines
+///  are. The interrupt is left masked (see `route`).
+pub fn route_pci_interrupt(
+    interrupt_line: Option<u8>,
+    vector: u8,
+    destination_apic_id: u8,
+) -> Result<u32, IOAPICError> {
+    let irq = match interrupt_line {
+        Some(irq) if (irq as usize) < ISA_IRQ_COUNT => irq,
+        _ => return Err(IOAPICError::NoPCIRoute(interrupt_line)),
+    };
+    let pci_route = without_interrupts(|| ISA_OVERRIDES.lock()[irq as usize]).unwrap_or(ISARoute {
+        global_sys_interrupt: irq as u32,
+        polarity: InterruptPolarity::ActiveLow,
+        trigger_mode: TriggerMode::Level,
+    });
+
+    route(
+        pci_route.global_sys_interrupt,
+        vector,
+        destination_apic_id,
+        pci_route.polarity,
+        pci_route.trigger_mode,
+    )
+    .map(|_| pci_route.global_sys_interrupt)
+}
+
+pub fn mask(global_sys_interrupt: u32) -> Result<(), IOAPICError> {
+    with_ioapic(global_sys_interrupt, |ioapic, pin| {
+        ioapic.set_masked(pin, true)
+    })
+}
+
+pub fn unmask(global_sys_interrupt: u32) -> Result<(), IOAPICError> {
+    with_ioapic(global_sys_interrupt, |ioapic, pin| {
+        ioapic.set_masked(pin, false)
+    })
+}
+
+pub fn is_masked(global_sys_interrupt: u32) -> Result<bool, IOAPICError> {
+    with_ioapic(global_sys_interrupt, |ioapic, pin| {
+        ioapic.read_redirection(pin).is_masked()
+    })
+}
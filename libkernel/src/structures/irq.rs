@@ -0,0 +1,309 @@
+//! Allocation of external interrupt vectors, and dispatch of interrupts to registered handlers.
+//!
+//! Every external vector (32..=255) enters a common stub, which counts the interrupt, invokes
+//! each handler registered on the vector, and signals end-of-interrupt to the local APIC (unless
+//! the vector's handlers acknowledge it; see `EndOfInterrupt`). A vector may have several
+//! handlers (i.e. for a shared PCI INTx line), all of which are invoked.
+
+use crate::structures::idt::InterruptStackFrame;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::RwLock;
+
+/// First vector available to external interrupts (vectors below are CPU exceptions).
+pub const FIRST_EXTERNAL_VECTOR: u8 = 32;
+/// Vector the local APIC delivers spurious interrupts on, which mustn't be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = u8::MAX;
+/// Number of external vectors.
+const EXTERNAL_VECTOR_COUNT: usize = 256 - (FIRST_EXTERNAL_VECTOR as usize);
+/// Maximum number of handlers which can share a single vector.
+const MAX_SHARED_HANDLERS: usize = 4;
+
+/// Handles an interrupt, given the context pointer it was registered with. Returns whether the
+///  interrupt was raised by the handler's device, so handlers sharing a vector can tell whether
+///  it was theirs.
+///
+/// Handlers run with interrupts disabled, and mustn't acknowledge the interrupt themselves
+///  (unless the vector's end-of-interrupt is `EndOfInterrupt::Handler`).
+pub type InterruptHandler = fn(context: *mut ()) -> bool;
+
+/// How interrupts on a vector are acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndOfInterrupt {
+    /// The local APIC is signalled once every handler has run (the default).
+    LocalAPIC,
+    /// The vector's handlers acknowledge it themselves, i.e. for interrupts delivered by the
+    ///  8259 PIC, which the local APIC doesn't track.
+    Handler,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IRQError {
+    /// Every external vector is allocated.
+    NoFreeVector,
+    /// The vector is a CPU exception vector.
+    NotExternal(u8),
+    AlreadyAllocated(u8),
+    NotAllocated(u8),
+    /// The vector already has the maximum number of handlers.
+    TooManyHandlers(u8),
+    /// The handler (with the given context) isn't registered on the vector.
+    NotRegistered(u8),
+}
+
+#[derive(Clone, Copy)]
+struct RegisteredHandler {
+    handler: InterruptHandler,
+    // Stored as an integer, so vectors can be shared between CPUs.
+    context: usize,
+}
+
+struct Vector {
+    allocated: AtomicBool,
+    /// Whether the vector's handlers acknowledge it (see `EndOfInterrupt::Handler`).
+    handler_eoi: AtomicBool,
+    handlers: RwLock<[Option<RegisteredHandler>; MAX_SHARED_HANDLERS]>,
+    count: AtomicUsize,
+    unhandled_count: AtomicUsize,
+}
+
+const EMPTY_VECTOR: Vector = Vector {
+    allocated: AtomicBool::new(false),
+    handler_eoi: AtomicBool::new(false),
+    handlers: RwLock::new([None; MAX_SHARED_HANDLERS]),
+    count: AtomicUsize::new(0),
+    unhandled_count: AtomicUsize::new(0),
+};
+static VECTORS: [Vector; EXTERNAL_VECTOR_COUNT] = [EMPTY_VECTOR; EXTERNAL_VECTOR_COUNT];
+
+fn vector(vector: u8) -> Result<&'static Vector, IRQError> {
+    if vector >= FIRST_EXTERNAL_VECTOR {
+        Ok(&VECTORS[(vector - FIRST_EXTERNAL_VECTOR) as usize])
+    } else {
+        Err(IRQError::NotExternal(vector))
+    }
+}
+
+/* STUBS */
+
+type VectorStub = extern "x86-interrupt" fn(&mut InterruptStackFrame);
+
+extern "x86-interrupt" fn vector_stub<const VECTOR: u8>(_: &mut InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+macro_rules! vector_stubs {
+    ($($high:literal),*) => {
+        [$(
+            vector_stub::<{ ($high << 4) | 0x0 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0x1 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0x2 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0x3 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0x4 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0x5 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0x6 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0x7 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0x8 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0x9 }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0xA }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0xB }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0xC }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0xD }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0xE }> as VectorStub,
+            vector_stub::<{ ($high << 4) | 0xF }> as VectorStub,
+        )*]
+    };
+}
+
+/// Entry stub of each external vector, indexed from `FIRST_EXTERNAL_VECTOR`.
+static VECTOR_STUBS: [VectorStub; EXTERNAL_VECTOR_COUNT] =
+    vector_stubs!(0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9, 0xA, 0xB, 0xC, 0xD, 0xE, 0xF);
+
+/// Points every external vector of the IDT at its dispatch stub, and reserves the spurious
+///  vector.
+pub(crate) fn install_stubs(idt: &mut x86_64::structures::idt::InterruptDescriptorTable) {
+    for (index, stub) in VECTOR_STUBS.iter().enumerate() {
+        idt[(FIRST_EXTERNAL_VECTOR as usize) + index].set_handler_fn(*stub);
+    }
+
+    reserve_vector(SPURIOUS_VECTOR).ok();
+}
+
+fn dispatch(vector_index: u8) {
+    let vector = &VECTORS[(vector_index - FIRST_EXTERNAL_VECTOR) as usize];
+    vector.count.fetch_add(1, Ordering::Relaxed);
+
+    let percpu = crate::percpu::try_get();
+    if let Some(percpu) = percpu {
+        percpu.enter_interrupt();
+        percpu.counters().interrupts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Every handler is invoked, since more than one device may have raised a shared line.
+    let handled = vector
+        .handlers
+        .read()
+        .iter()
+        .flatten()
+        .fold(false, |handled, registered| {
+            (registered.handler)(registered.context as *mut ()) | handled
+        });
+
+    if !handled {
+        vector.unhandled_count.fetch_add(1, Ordering::Relaxed);
+        trace!("Unhandled interrupt on vector {}.", vector_index);
+    }
+
+    if vector_index != SPURIOUS_VECTOR && !vector.handler_eoi.load(Ordering::Relaxed) {
+        if let Some(apic) = crate::structures::apic::local_apic_mut() {
+            apic.end_of_interrupt();
+        }
+    }
+
+    if let Some(percpu) = percpu {
+        percpu.exit_interrupt();
+    }
+}
+
+/* ALLOCATION */
+
+/// Allocates the lowest free external vector.
+pub fn allocate_vector() -> Result<u8, IRQError> {
+    VECTORS
+        .iter()
+        .position(|vector| {
+            vector
+                .allocated
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })
+        .map(|index| (index as u8) + FIRST_EXTERNAL_VECTOR)
+        .ok_or(IRQError::NoFreeVector)
+}
+
+/// Allocates a specific vector (i.e. one fixed by hardware, like the spurious vector).
+pub fn reserve_vector(vector_index: u8) -> Result<(), IRQError> {
+    vector(vector_index)?
+        .allocated
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+        .map(|_| ())
+        .map_err(|_| IRQError::AlreadyAllocated(vector_index))
+}
+
+/// Frees an allocated vector, unregistering all of its handlers.
+pub fn free_vector(vector_index: u8) -> Result<(), IRQError> {
+    let vector = vector(vector_index)?;
+
+    crate::instructions::interrupts::without_interrupts(|| {
+        *vector.handlers.write() = [None; MAX_SHARED_HANDLERS];
+    });
+    vector.handler_eoi.store(false, Ordering::Release);
+
+    if vector.allocated.swap(false, Ordering::AcqRel) {
+        Ok(())
+    } else {
+        Err(IRQError::NotAllocated(vector_index))
+    }
+}
+
+/// Sets how interrupts on an allocated vector are acknowledged (the local APIC is signalled
+///  by default).
+pub fn set_end_of_interrupt(vector_index: u8, eoi: EndOfInterrupt) -> Result<(), IRQError> {
+    let vector = vector(vector_index)?;
+
+    if vector.allocated.load(Ordering::Acquire) {
+        vector
+            .handler_eoi
+            .store(eoi == EndOfInterrupt::Handler, Ordering::Release);
+        Ok(())
+    } else {
+        Err(IRQError::NotAllocated(vector_index))
+    }
+}
+
+/* HANDLERS */
+
+/// Registers a handler on an allocated vector, which is invoked with `context` each time an
+///  interrupt arrives on the vector.
+pub fn register_handler(
+    vector_index: u8,
+    handler: InterruptHandler,
+    context: *mut (),
+) -> Result<(), IRQError> {
+    let vector = vector(vector_index)?;
+    if !vector.allocated.load(Ordering::Acquire) {
+        return Err(IRQError::NotAllocated(vector_index));
+    }
+
+    // Interrupts are disabled while the handlers are locked, so a dispatch on this CPU can't
+    //  spin on the lock.
+    crate::instructions::interrupts::without_interrupts(|| {
+        match vector
+            .handlers
+            .write()
+            .iter_mut()
+            .find(|slot| slot.is_none())
+        {
+            Some(slot) => {
+                *slot = Some(RegisteredHandler {
+                    handler,
+                    context: context as usize,
+                });
+
+                debug!("Registered interrupt handler on vector {}.", vector_index);
+                Ok(())
+            }
+            None => Err(IRQError::TooManyHandlers(vector_index)),
+        }
+    })
+}
+
+/// Unregisters a handler (registered with the given context) from a vector.
+pub fn unregister_handler(
+    vector_index: u8,
+    handler: InterruptHandler,
+    context: *mut (),
+) -> Result<(), IRQError> {
+    let vector = vector(vector_index)?;
+
+    crate::instructions::interrupts::without_interrupts(|| {
+        match vector.handlers.write().iter_mut().find(|slot| {
+            matches!(slot, Some(registered)
+                if (registered.handler as usize) == (handler as usize)
+                    && registered.context == (context as usize))
+        }) {
+            Some(slot) => {
+                *slot = None;
+                Ok(())
+            }
+            None => Err(IRQError::NotRegistered(vector_index)),
+        }
+    })
+}
+
+/* COUNTERS */
+
+/// Number of interrupts which have arrived on the vector.
+pub fn interrupt_count(vector_index: u8) -> usize {
+    vector(vector_index).map_or(0, |vector| vector.count.load(Ordering::Relaxed))
+}
+
+/// Number of interrupts which arrived on the vector, but which no handler claimed.
+pub fn unhandled_count(vector_index: u8) -> usize {
+    vector(vector_index).map_or(0, |vector| vector.unhandled_count.load(Ordering::Relaxed))
+}
+
+/// Logs the counters of every vector which has received interrupts.
+pub fn log_counters() {
+    for (index, vector) in VECTORS.iter().enumerate() {
+        let count = vector.count.load(Ordering::Relaxed);
+
+        if count > 0 {
+            debug!(
+                "Vector {}: {} interrupts ({} unhandled)",
+                (index as u8) + FIRST_EXTERNAL_VECTOR,
+                count,
+                vector.unhandled_count.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
@@ -3,7 +3,10 @@ mod system_table;
 
 pub mod acpi;
 pub mod apic;
+pub mod exception;
 pub mod gdt;
 pub mod idt;
+pub mod ioapic;
+pub mod irq;
 pub use guid::*;
 pub use system_table::*;
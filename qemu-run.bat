@@ -3,6 +3,7 @@ qemu-system-x86_64^
     -serial stdio^
     -machine q35^
     -cpu qemu64^
+    -smp 4^
     -drive format=raw,file=fat:rw:./hdd/image/^
     -drive if=pflash,format=raw,unit=0,file=./ovmf/OVMF_CODE-pure-efi.fd,readonly=on^
     -drive if=pflash,format=raw,unit=1,file=./ovmf/OVMF_VARS-pure-efi.fd,readonly=on^
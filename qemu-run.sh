@@ -2,16 +2,24 @@
 set -e
 
 PROFILE=${1:-release}
+# kernel features (i.e. `alloc_check`, to boot with the heap checks)
+FEATURES=${2:-}
 
-echo "Compiling with profile '$PROFILE'"
+echo "Compiling with profile '$PROFILE' and features '$FEATURES'"
+
+# the kernel's dependencies don't include its features, so it's rebuilt whenever any are given
+if [ -n "$FEATURES" ]; then
+    rm -f ./hdd/image/EFI/gsai/kernel.elf
+fi
 
 # compile and link deps
-make PROFILE=$PROFILE
+make PROFILE=$PROFILE FEATURES="$FEATURES"
 
 # run the bootloader image
 qemu-system-x86_64 \
     -m 256M \
     -nographic \
     -cpu qemu64,+x2apic \
+    -smp 4 \
     -bios ./ovmf/OVMF-pure-efi.fd \
     -drive format=raw,file=fat:rw:./image/
\ No newline at end of file